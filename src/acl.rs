@@ -0,0 +1,101 @@
+use crate::{
+    config,
+    paths::{canonical_path, is_under},
+    state::Role,
+};
+
+/// Who is asking to see a page, `role` is `None` for visitors who haven't logged in.
+#[derive(Debug, Default, Clone)]
+pub struct Viewer {
+    pub role: Option<Role>,
+    /// The account they logged in with, if they used one.
+    pub user: Option<String>,
+}
+
+/// Rules restricting which pages under a path prefix people can see, read from
+/// `knowbase_ACCESS_RULES` like `hr=admin;clients/acme=alice,bob,editor`.
+#[derive(Debug, Default, Clone)]
+pub struct Acl {
+    rules: Vec<Rule>,
+}
+
+#[derive(Debug, Clone)]
+struct Rule {
+    prefix: String,
+    /// Anyone with at least this role can see the pages.
+    role: Option<Role>,
+    /// These accounts can see the pages whatever their role.
+    users: Vec<String>,
+}
+
+impl Acl {
+    pub fn from_config() -> Self {
+        let Ok(rules) = config::var("knowbase_ACCESS_RULES") else {
+            return Self::default();
+        };
+
+        let rules = rules
+            .split(';')
+            .filter(|rule| !rule.trim().is_empty())
+            .map(|rule| {
+                let (prefix, allowed) = rule.split_once('=').unwrap_or_else(|| {
+                    panic!(
+                        "knowbase_ACCESS_RULES entries should look like prefix=who, not {}",
+                        rule
+                    )
+                });
+
+                let mut rule = Rule {
+                    prefix: canonical_path(prefix.trim()),
+                    role: None,
+                    users: Vec::new(),
+                };
+                for who in allowed.split(',').map(|w| w.trim().to_lowercase()) {
+                    let role = match who.as_str() {
+                        "" => continue,
+                        "reader" => Role::Reader,
+                        "editor" => Role::Editor,
+                        "admin" => Role::Admin,
+                        _ => {
+                            rule.users.push(who);
+                            continue;
+                        }
+                    };
+                    rule.role = Some(rule.role.map_or(role, |r| r.min(role)));
+                }
+
+                rule
+            })
+            .collect();
+
+        Self { rules }
+    }
+
+    /// Whether everyone who can see the page at `from` can see the page at `path` too, because
+    /// every rule covering `path` covers `from` as well.
+    pub fn no_stricter(&self, path: &str, from: &str) -> bool {
+        self.rules
+            .iter()
+            .filter(|rule| is_under(path, &rule.prefix))
+            .all(|rule| is_under(from, &rule.prefix))
+    }
+
+    /// Whether `viewer` can see the page at `path`, every rule covering it has to let them in.
+    pub fn allows(&self, path: &str, viewer: &Viewer) -> bool {
+        // Admins can change the rules anyway, so they can see everything.
+        if viewer.role == Some(Role::Admin) {
+            return true;
+        }
+
+        self.rules
+            .iter()
+            .filter(|rule| is_under(path, &rule.prefix))
+            .all(|rule| {
+                rule.role.is_some_and(|role| viewer.role >= Some(role))
+                    || viewer
+                        .user
+                        .as_ref()
+                        .is_some_and(|user| rule.users.contains(user))
+            })
+    }
+}
@@ -0,0 +1,151 @@
+use std::{
+    fs::File,
+    io::{self, Read},
+    path::{Component, Path, PathBuf},
+};
+
+use flate2::read::GzDecoder;
+use zip::ZipArchive;
+
+/// An uploaded archive of pages, either a zip or a gzipped tarball.
+pub enum Archive {
+    Zip(ZipArchive<File>),
+    /// Tarballs can only be read from start to finish, so every pass opens the file again.
+    TarGz {
+        path: PathBuf,
+        tar: Option<Box<tar::Archive<GzDecoder<File>>>>,
+    },
+}
+
+/// A file from an archive.
+pub struct Entry {
+    /// Where the file is in the archive, `/` separated.
+    pub name: String,
+    /// What's in the file, `None` if it was too big to read.
+    pub data: Option<Vec<u8>>,
+}
+
+impl Archive {
+    /// Opens the archive at `path`, telling zips and tarballs apart by how they start.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let mut magic = [0; 2];
+        File::open(path)?.read_exact(&mut magic)?;
+
+        if magic == [0x1f, 0x8b] {
+            Ok(Archive::TarGz {
+                path: path.to_owned(),
+                tar: None,
+            })
+        } else {
+            Ok(Archive::Zip(ZipArchive::new(File::open(path)?)?))
+        }
+    }
+
+    /// The name of everything in the archive, folders included.
+    pub fn file_names(&mut self) -> io::Result<Vec<String>> {
+        match self {
+            Archive::Zip(zip) => Ok(zip.file_names().map(str::to_owned).collect()),
+            Archive::TarGz { path, .. } => {
+                let mut tar = tar::Archive::new(GzDecoder::new(File::open(&*path)?));
+                let mut names = Vec::new();
+                for entry in tar.entries()? {
+                    let entry = entry?;
+                    if let Some(mut name) = enclosed_name(&entry.path()?) {
+                        // Folders end in `/` like they do in zips.
+                        if entry.header().entry_type().is_dir() {
+                            name.push('/');
+                        }
+                        names.push(name);
+                    }
+                }
+
+                Ok(names)
+            }
+        }
+    }
+
+    /// Every regular file in the archive whose name stays inside it. Pages are only read if
+    /// they're no bigger than `max_page_bytes`, other files if they're no bigger than
+    /// `max_bytes`.
+    pub fn entries(
+        &mut self,
+        max_page_bytes: u64,
+        max_bytes: u64,
+    ) -> io::Result<Box<dyn Iterator<Item = io::Result<Entry>> + '_>> {
+        match self {
+            Archive::Zip(zip) => {
+                let entries = (0..zip.len()).filter_map(move |i| {
+                    let mut file = match zip.by_index(i) {
+                        Ok(file) => file,
+                        Err(e) => return Some(Err(e.into())),
+                    };
+                    if file.is_dir() {
+                        return None;
+                    }
+                    let name = enclosed_name(file.enclosed_name()?)?;
+
+                    let limit = entry_limit(&name, max_page_bytes, max_bytes);
+                    Some(read_entry(name, limit, &mut file))
+                });
+
+                Ok(Box::new(entries))
+            }
+            Archive::TarGz { path, tar } => {
+                let tar = tar.insert(Box::new(tar::Archive::new(GzDecoder::new(File::open(
+                    &*path,
+                )?))));
+                let entries = tar.entries()?.filter_map(move |entry| {
+                    let mut entry = match entry {
+                        Ok(entry) => entry,
+                        Err(e) => return Some(Err(e)),
+                    };
+                    if entry.header().entry_type() != tar::EntryType::Regular {
+                        return None;
+                    }
+                    let name = enclosed_name(&entry.path().ok()?)?;
+                    let limit = entry_limit(&name, max_page_bytes, max_bytes);
+                    Some(read_entry(name, limit, &mut entry))
+                });
+
+                Ok(Box::new(entries))
+            }
+        }
+    }
+}
+
+fn entry_limit(name: &str, max_page_bytes: u64, max_bytes: u64) -> u64 {
+    if name.ends_with(".md") {
+        max_page_bytes
+    } else {
+        max_bytes
+    }
+}
+
+/// Reads a file, giving up once it's past `limit` bytes. The size an archive claims for a file
+/// isn't trusted, so a small zip can't unpack into something enormous.
+fn read_entry(name: String, limit: u64, file: &mut impl Read) -> io::Result<Entry> {
+    let mut data = Vec::new();
+    file.take(limit + 1).read_to_end(&mut data)?;
+    if data.len() as u64 > limit {
+        return Ok(Entry { name, data: None });
+    }
+
+    Ok(Entry {
+        name,
+        data: Some(data),
+    })
+}
+
+/// `path` as a `/` separated name, if it doesn't climb out of the archive or start at the root.
+fn enclosed_name(path: &Path) -> Option<String> {
+    let mut segments = Vec::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(segment) => segments.push(segment.to_string_lossy()),
+            Component::CurDir => {}
+            _ => return None,
+        }
+    }
+
+    Some(segments.join("/"))
+}
@@ -0,0 +1,103 @@
+use std::{
+    fs::File,
+    io::{Cursor, Read, Write},
+};
+
+use serde::{Deserialize, Serialize};
+use zip::{result::ZipResult, write::FileOptions, ZipArchive, ZipWriter};
+
+use crate::{
+    error::{Error, Result},
+    state::{unix_time, Page},
+};
+
+/// The version of the backup format, bumped whenever what's in a backup changes.
+const BACKUP_VERSION: u32 = 1;
+const MANIFEST_FILE: &str = "knowbase-backup.json";
+
+/// Everything a backup holds besides the values themselves.
+#[derive(Serialize, Deserialize)]
+struct Manifest {
+    version: u32,
+    /// When the backup was made, in seconds since the Unix epoch.
+    created: u64,
+    /// The path of each page, in the order they're numbered in `pages/`.
+    pages: Vec<String>,
+    /// The key of each value, in the order they're numbered in `values/`.
+    keys: Vec<String>,
+}
+
+/// Everything the store holds, to be moved to another store or backend.
+#[derive(Debug, Default)]
+pub struct Backup {
+    pub pages: Vec<(String, Page)>,
+    /// Every other key the wiki keeps, like revisions, users and attachments, with its value.
+    pub values: Vec<(String, Vec<u8>)>,
+}
+
+impl Backup {
+    /// The backup as a zip of a manifest, each page as JSON and each value as it's stored.
+    pub fn to_zip(&self) -> ZipResult<Vec<u8>> {
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        let options = FileOptions::default();
+
+        let manifest = Manifest {
+            version: BACKUP_VERSION,
+            created: unix_time(),
+            pages: self.pages.iter().map(|(path, _)| path.clone()).collect(),
+            keys: self.values.iter().map(|(key, _)| key.clone()).collect(),
+        };
+        zip.start_file(MANIFEST_FILE, options)?;
+        zip.write_all(&serde_json::to_vec_pretty(&manifest).unwrap())?;
+
+        for (i, (_, page)) in self.pages.iter().enumerate() {
+            zip.start_file(format!("pages/{}.json", i), options)?;
+            zip.write_all(&serde_json::to_vec(page).unwrap())?;
+        }
+        for (i, (_, value)) in self.values.iter().enumerate() {
+            zip.start_file(format!("values/{}", i), options)?;
+            zip.write_all(value)?;
+        }
+
+        Ok(zip.finish()?.into_inner())
+    }
+
+    /// Reads a backup made by `to_zip`, by this version of the wiki or an older one.
+    pub fn from_zip(file: File) -> Result<Self> {
+        let mut zip = ZipArchive::new(file).map_err(bad_backup)?;
+        let manifest: Manifest = serde_json::from_reader(
+            zip.by_name(MANIFEST_FILE)
+                .map_err(|_| bad_backup("it isn't a knowbase backup"))?,
+        )
+        .map_err(bad_backup)?;
+        if manifest.version > BACKUP_VERSION {
+            return Err(bad_backup(format!(
+                "it's a version {} backup, this wiki only knows up to version {}",
+                manifest.version, BACKUP_VERSION
+            )));
+        }
+
+        let mut backup = Backup::default();
+        for (i, path) in manifest.pages.into_iter().enumerate() {
+            let file = zip
+                .by_name(&format!("pages/{}.json", i))
+                .map_err(bad_backup)?;
+            let page = serde_json::from_reader(file).map_err(bad_backup)?;
+            backup.pages.push((path, page));
+        }
+        for (i, key) in manifest.keys.into_iter().enumerate() {
+            let mut value = Vec::new();
+            zip.by_name(&format!("values/{}", i))
+                .map_err(bad_backup)?
+                .read_to_end(&mut value)
+                .map_err(bad_backup)?;
+            backup.values.push((key, value));
+        }
+
+        Ok(backup)
+    }
+}
+
+fn bad_backup(e: impl ToString) -> Error {
+    Error::BadUpload(e.to_string())
+}
@@ -0,0 +1,132 @@
+use std::{
+    collections::HashMap,
+    env::VarError,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::OnceLock,
+};
+
+use toml::Value;
+
+/// Where settings are read from when `knowbase_CONFIG` doesn't say, if the file is there.
+const DEFAULT_CONFIG_FILE: &str = "knowbase.toml";
+
+/// The settings from the config file, by the environment variable that would override them.
+static FILE_SETTINGS: OnceLock<HashMap<String, String>> = OnceLock::new();
+
+/// Reads the config file at `path`, or named by `knowbase_CONFIG`, or `knowbase.toml` if there is
+/// one. Has to be called before anything reads a setting, which only sees environment variables
+/// otherwise.
+pub fn load(path: Option<&Path>) {
+    let (path, required) = match (path, std::env::var("knowbase_CONFIG")) {
+        (Some(path), _) => (path.to_owned(), true),
+        (None, Ok(path)) => (PathBuf::from(path), true),
+        (None, Err(_)) => (PathBuf::from(DEFAULT_CONFIG_FILE), false),
+    };
+
+    let settings = match std::fs::read_to_string(&path) {
+        Ok(toml) => parse(&toml)
+            .unwrap_or_else(|e| panic!("{} should be valid TOML: {}", path.display(), e)),
+        Err(_) if !required => HashMap::new(),
+        Err(e) => panic!("couldn't read {}: {}", path.display(), e),
+    };
+    let _ = FILE_SETTINGS.set(settings);
+}
+
+/// Turns a config file into settings named like the environment variables that override them.
+/// `name = "Notes"` is `knowbase_NAME`, and `threshold = 5` in a `[lockout]` table is
+/// `knowbase_LOCKOUT_THRESHOLD`. Lists are joined with commas.
+fn parse(toml: &str) -> Result<HashMap<String, String>, toml::de::Error> {
+    let mut settings = HashMap::new();
+    let table: toml::value::Table = toml::from_str(toml)?;
+    flatten("knowbase", table, &mut settings);
+
+    Ok(settings)
+}
+
+fn flatten(prefix: &str, table: toml::value::Table, settings: &mut HashMap<String, String>) {
+    for (key, value) in table {
+        let name = format!("{}_{}", prefix, key.to_uppercase().replace('-', "_"));
+        if let Value::Table(table) = value {
+            flatten(&name, table, settings);
+            continue;
+        }
+
+        let value = match value {
+            Value::Array(items) => items
+                .into_iter()
+                .map(setting_text)
+                .collect::<Vec<_>>()
+                .join(","),
+            value => setting_text(value),
+        };
+        settings.insert(name, value);
+    }
+}
+
+fn setting_text(value: Value) -> String {
+    match value {
+        Value::String(s) => s,
+        value => value.to_string(),
+    }
+}
+
+/// The setting `name`, from its environment variable if that's set or the config file if not.
+/// Fails like `std::env::var` when it's in neither.
+pub fn var(name: &str) -> Result<String, VarError> {
+    std::env::var(name).or_else(|e| {
+        FILE_SETTINGS
+            .get()
+            .and_then(|settings| settings.get(name).cloned())
+            .ok_or(e)
+    })
+}
+
+/// The setting `name` parsed, or `None` if it isn't set. Panics if it's set to something that
+/// doesn't parse, rather than quietly running with a setting nobody asked for.
+pub fn parsed<T: FromStr>(name: &str) -> Option<T> {
+    let value = var(name).ok()?;
+    match value.parse() {
+        Ok(parsed) => Some(parsed),
+        Err(_) => panic!(
+            "{} should be a valid {}, not {}",
+            name,
+            std::any::type_name::<T>(),
+            value
+        ),
+    }
+}
+
+/// The setting `name` parsed, or `default` if it isn't set. Panics like [`parsed`] if it doesn't
+/// parse.
+pub fn var_or<T: FromStr>(name: &str, default: T) -> T {
+    parsed(name).unwrap_or(default)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tables_and_lists_become_setting_names() {
+        let settings = parse(
+            "name = \"Notes\"\n[lockout]\nthreshold = 5\n[public]\nprefixes = [\"docs\", \"faq\"]",
+        )
+        .unwrap();
+        assert_eq!(settings["knowbase_NAME"], "Notes");
+        assert_eq!(settings["knowbase_LOCKOUT_THRESHOLD"], "5");
+        assert_eq!(settings["knowbase_PUBLIC_PREFIXES"], "docs,faq");
+    }
+
+    #[test]
+    fn settings_that_are_not_set_keep_their_default() {
+        assert_eq!(var_or("knowbase_TEST_UNSET", 8080), 8080);
+    }
+
+    #[test]
+    #[should_panic(expected = "knowbase_TEST_PORT should be a valid u16, not eighty")]
+    fn settings_that_do_not_parse_panic() {
+        std::env::set_var("knowbase_TEST_PORT", "eighty");
+        var_or::<u16>("knowbase_TEST_PORT", 8080);
+    }
+}
@@ -0,0 +1,239 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    io::{Cursor, Write},
+};
+
+use lazy_static::lazy_static;
+use percent_encoding::percent_decode_str;
+use regex::{Captures, Regex};
+use time::{macros::format_description, OffsetDateTime};
+use zip::{result::ZipResult, write::FileOptions, CompressionMethod, ZipWriter};
+
+use crate::{
+    paths::{attachment_url, canonical_path, content_type},
+    search::escape_html,
+};
+
+lazy_static! {
+    static ref PAGE_HREF_RE: Regex = Regex::new(r##"href="/w/([^"#]*)(#[^"]*)?""##).unwrap();
+    static ref ATTACHMENT_SRC_RE: Regex = Regex::new(r#"src="/a/([^"]+)""#).unwrap();
+}
+
+const CONTAINER_XML: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<container version="1.0" xmlns="urn:oasis:names:tc:opendocument:xmlns:container">
+  <rootfiles>
+    <rootfile full-path="OEBPS/content.opf" media-type="application/oebps-package+xml"/>
+  </rootfiles>
+</container>
+"#;
+
+/// A page as a chapter of the book.
+struct Chapter {
+    path: String,
+    title: String,
+    content: String,
+}
+
+/// Pages bundled into an EPUB, so the wiki can be read offline on an e-reader.
+pub struct Epub {
+    title: String,
+    /// In the order they're read in.
+    chapters: Vec<Chapter>,
+    /// The attachments chapters embed, by path.
+    images: BTreeMap<String, Vec<u8>>,
+}
+
+impl Epub {
+    pub fn new(title: &str) -> Self {
+        Self {
+            title: title.to_owned(),
+            chapters: Vec::new(),
+            images: BTreeMap::new(),
+        }
+    }
+
+    /// Adds the rendered page at `path` as the next chapter.
+    pub fn add_page(&mut self, path: &str, title: &str, content: &str) {
+        self.chapters.push(Chapter {
+            path: path.to_owned(),
+            title: title.to_owned(),
+            content: content.to_owned(),
+        });
+    }
+
+    /// The paths of the attachments the chapters embed as images.
+    pub fn embedded_attachments(&self) -> BTreeSet<String> {
+        self.chapters
+            .iter()
+            .flat_map(|chapter| ATTACHMENT_SRC_RE.captures_iter(&chapter.content))
+            .map(|src| decoded_path(&src[1]))
+            .collect()
+    }
+
+    /// Puts an attachment in the book, so chapters embedding it show it without the wiki.
+    pub fn add_image(&mut self, path: &str, data: Vec<u8>) {
+        self.images.insert(canonical_path(path), data);
+    }
+
+    /// Writes out the book, with links between its chapters pointed at each other.
+    pub fn finish(self) -> ZipResult<Vec<u8>> {
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        // Readers tell what the file is by an uncompressed `mimetype` at the very start.
+        zip.start_file(
+            "mimetype",
+            FileOptions::default().compression_method(CompressionMethod::Stored),
+        )?;
+        zip.write_all(b"application/epub+zip")?;
+
+        let options = FileOptions::default();
+        zip.start_file("META-INF/container.xml", options)?;
+        zip.write_all(CONTAINER_XML.as_bytes())?;
+
+        let numbers: HashMap<&str, usize> = self
+            .chapters
+            .iter()
+            .enumerate()
+            .map(|(i, chapter)| (chapter.path.as_str(), i))
+            .collect();
+        for (i, chapter) in self.chapters.iter().enumerate() {
+            let body = self.chapter_content(chapter, &numbers);
+            zip.start_file(format!("OEBPS/pages/{}.xhtml", i), options)?;
+            zip.write_all(xhtml(&chapter.title, &body).as_bytes())?;
+        }
+        for (path, data) in &self.images {
+            zip.start_file(format!("OEBPS/a/{}", path), options)?;
+            zip.write_all(data)?;
+        }
+
+        zip.start_file("OEBPS/nav.xhtml", options)?;
+        zip.write_all(self.nav().as_bytes())?;
+        zip.start_file("OEBPS/content.opf", options)?;
+        zip.write_all(self.package().as_bytes())?;
+
+        Ok(zip.finish()?.into_inner())
+    }
+
+    /// The chapter's HTML, with links to other chapters and embedded attachments pointed at
+    /// where they are in the book. Anything that didn't make it in is left linking to the wiki.
+    fn chapter_content(&self, chapter: &Chapter, numbers: &HashMap<&str, usize>) -> String {
+        let content = PAGE_HREF_RE.replace_all(&chapter.content, |href: &Captures| {
+            let mut path = decoded_path(&href[1]);
+            if path.is_empty() {
+                path = "index.md".to_owned();
+            }
+            match numbers.get(path.as_str()) {
+                Some(i) => {
+                    let fragment = href.get(2).map_or("", |m| m.as_str());
+                    format!("href=\"{}.xhtml{}\"", i, fragment)
+                }
+                None => href[0].to_owned(),
+            }
+        });
+
+        ATTACHMENT_SRC_RE
+            .replace_all(&content, |src: &Captures| {
+                let path = decoded_path(&src[1]);
+                match self.images.contains_key(&path) {
+                    true => format!("src=\"..{}\"", attachment_url(&path)),
+                    false => src[0].to_owned(),
+                }
+            })
+            .into_owned()
+    }
+
+    /// The table of contents, listing every chapter in order.
+    fn nav(&self) -> String {
+        let mut items = String::new();
+        for (i, chapter) in self.chapters.iter().enumerate() {
+            items.push_str(&format!(
+                "<li><a href=\"pages/{}.xhtml\">{}</a></li>\n",
+                i,
+                escape_html(&chapter.title)
+            ));
+        }
+
+        xhtml(
+            &self.title,
+            &format!(
+                "<nav epub:type=\"toc\" id=\"toc\">\n<h1>{}</h1>\n<ol>\n{}</ol>\n</nav>",
+                escape_html(&self.title),
+                items
+            ),
+        )
+    }
+
+    /// The package document, which says what's in the book and what order it's read in.
+    fn package(&self) -> String {
+        let mut manifest = String::from(
+            "<item id=\"nav\" href=\"nav.xhtml\" media-type=\"application/xhtml+xml\" \
+             properties=\"nav\"/>\n",
+        );
+        let mut spine = String::new();
+        for (i, chapter) in self.chapters.iter().enumerate() {
+            let properties = match chapter.content.contains("<math") {
+                true => " properties=\"mathml\"",
+                false => "",
+            };
+            manifest.push_str(&format!(
+                "<item id=\"page{}\" href=\"pages/{}.xhtml\" media-type=\"application/xhtml+xml\"{}/>\n",
+                i, i, properties
+            ));
+            spine.push_str(&format!("<itemref idref=\"page{}\"/>\n", i));
+        }
+        for (i, path) in self.images.keys().enumerate() {
+            manifest.push_str(&format!(
+                "<item id=\"image{}\" href=\"{}\" media-type=\"{}\"/>\n",
+                i,
+                escape_html(attachment_url(path).trim_start_matches('/')),
+                content_type(path)
+            ));
+        }
+
+        let modified = OffsetDateTime::now_utc()
+            .format(format_description!(
+                "[year]-[month]-[day]T[hour]:[minute]:[second]Z"
+            ))
+            .unwrap_or_default();
+        let title = escape_html(&self.title);
+        format!(
+            r#"<?xml version="1.0" encoding="utf-8"?>
+<package xmlns="http://www.idpf.org/2007/opf" version="3.0" unique-identifier="id">
+<metadata xmlns:dc="http://purl.org/dc/elements/1.1/">
+<dc:identifier id="id">urn:knowbase:{}</dc:identifier>
+<dc:title>{}</dc:title>
+<dc:language>en</dc:language>
+<meta property="dcterms:modified">{}</meta>
+</metadata>
+<manifest>
+{}</manifest>
+<spine>
+{}</spine>
+</package>
+"#,
+            title, title, modified, manifest, spine
+        )
+    }
+}
+
+/// The canonical path a percent encoded link points at.
+fn decoded_path(url: &str) -> String {
+    canonical_path(&percent_decode_str(url).decode_utf8_lossy())
+}
+
+fn xhtml(title: &str, body: &str) -> String {
+    format!(
+        r#"<?xml version="1.0" encoding="utf-8"?>
+<!DOCTYPE html>
+<html xmlns="http://www.w3.org/1999/xhtml" xmlns:epub="http://www.idpf.org/2007/ops">
+<head>
+<title>{}</title>
+</head>
+<body>
+{}
+</body>
+</html>
+"#,
+        escape_html(title),
+        body
+    )
+}
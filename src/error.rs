@@ -0,0 +1,107 @@
+use std::fmt::Display;
+
+use actix_web::{http::StatusCode, ResponseError};
+
+#[derive(Debug)]
+pub enum Error {
+    Timeout,
+    Redis(redis::RedisError),
+    RedisPool(deadpool_redis::PoolError),
+    Sql(sqlx::Error),
+    /// A write was refused because it would take the wiki over a configured limit.
+    QuotaExceeded(String),
+    /// Something was bigger than the wiki accepts.
+    TooLarge(String),
+    /// An uploaded file couldn't be read, like an archive that isn't a zip or tarball.
+    BadUpload(String),
+    /// The OpenID Connect provider couldn't be reached or refused a login.
+    Oidc(String),
+    /// The bucket attachments are kept in couldn't be reached or refused a request.
+    ObjectStorage(String),
+    /// The command pages are turned into PDFs with couldn't be run or failed.
+    Pdf(String),
+    /// The search index couldn't be read or written.
+    #[cfg(feature = "tantivy")]
+    Search(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Timeout => write!(f, "Storage timed out"),
+            Error::Redis(e) => write!(f, "Storage error: {}", e),
+            Error::RedisPool(e) => write!(f, "Storage error: {}", e),
+            Error::Sql(e) => write!(f, "Storage error: {}", e),
+            Error::QuotaExceeded(reason) => write!(f, "Quota exceeded: {}", reason),
+            Error::TooLarge(reason) => write!(f, "Too large: {}", reason),
+            Error::BadUpload(e) => write!(f, "Couldn't read the upload: {}", e),
+            Error::Oidc(e) => write!(f, "Single sign-on failed: {}", e),
+            Error::ObjectStorage(e) => write!(f, "Attachment storage error: {}", e),
+            Error::Pdf(e) => write!(f, "PDF export failed: {}", e),
+            #[cfg(feature = "tantivy")]
+            Error::Search(e) => write!(f, "Search index error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<redis::RedisError> for Error {
+    fn from(e: redis::RedisError) -> Self {
+        Error::Redis(e)
+    }
+}
+
+impl From<deadpool_redis::PoolError> for Error {
+    fn from(e: deadpool_redis::PoolError) -> Self {
+        match e {
+            deadpool_redis::PoolError::Timeout(_) => Error::Timeout,
+            deadpool_redis::PoolError::Backend(e) => Error::Redis(e),
+            e => Error::RedisPool(e),
+        }
+    }
+}
+
+impl From<sqlx::Error> for Error {
+    fn from(e: sqlx::Error) -> Self {
+        Error::Sql(e)
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(e: reqwest::Error) -> Self {
+        Error::Oidc(e.to_string())
+    }
+}
+
+#[cfg(feature = "tantivy")]
+impl From<tantivy::TantivyError> for Error {
+    fn from(e: tantivy::TantivyError) -> Self {
+        Error::Search(e.to_string())
+    }
+}
+
+impl From<tokio::time::error::Elapsed> for Error {
+    fn from(_: tokio::time::error::Elapsed) -> Self {
+        Error::Timeout
+    }
+}
+
+impl ResponseError for Error {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            Error::Timeout | Error::Redis(_) | Error::RedisPool(_) | Error::Sql(_) => {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
+            Error::QuotaExceeded(_) => StatusCode::INSUFFICIENT_STORAGE,
+            Error::TooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            Error::BadUpload(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            Error::Pdf(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Error::Oidc(_) | Error::ObjectStorage(_) => StatusCode::BAD_GATEWAY,
+            #[cfg(feature = "tantivy")]
+            Error::Search(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
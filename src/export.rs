@@ -0,0 +1,200 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+
+use askama::Template;
+
+use crate::{
+    acl::Viewer,
+    nav::NavFolder,
+    paths::{canonical_path, wiki_url},
+    state::{BrandImage, State, Visibility},
+    FolderTemplate, TagTemplate, TagsTemplate, WikiTemplate, BOOTSTRAP_CSS, BOOTSTRAP_JS, FAVICON,
+    MERMAID_JS,
+};
+
+/// Writes every page anyone could be let see to `dir` as the wiki would show it, with the tag
+/// pages, attachments and assets they need, so it can be put on static hosting as it is.
+///
+/// Each page is written to `w/{path}/index.html`, so the links between pages keep working.
+/// Private pages and pages behind access rules are left out.
+pub async fn export_site(state: &State, dir: &Path) -> io::Result<()> {
+    let viewer = Viewer::default();
+    let mut pages = 0;
+    let exported = state.nav_pages().await.map_err(io::Error::other)?;
+    let nav = NavFolder::build(
+        exported
+            .iter()
+            .filter(|page| {
+                page.visibility != Visibility::Private && state.can_see(&page.path, &viewer)
+            })
+            .cloned(),
+    );
+
+    for path in state.list_pages().await.map_err(io::Error::other)? {
+        let Some(mut page) = state.get_page(&path).await.map_err(io::Error::other)? else {
+            continue;
+        };
+        if page.visibility == Visibility::Private || !state.can_see(&path, &viewer) {
+            continue;
+        }
+        page.content = state
+            .page_content(&path, &page)
+            .await
+            .map_err(io::Error::other)?;
+        page.chunks = 0;
+
+        let html = WikiTemplate {
+            name: state.name(),
+            title: &page.title(&path),
+            path: &path,
+            can_edit: false,
+            page: &page,
+            backlinks: state
+                .backlinks(&path, &viewer)
+                .await
+                .map_err(io::Error::other)?,
+            nav: &nav.to_html(&path),
+            viewed: Vec::new(),
+            starred: None,
+        }
+        .render()
+        .map_err(io::Error::other)?;
+        // Pages end up one directory deeper than they're served from, so relative links are
+        // pointed back at where the page would be.
+        let html = html.replacen(
+            "<head>",
+            &format!("<head>\n    <base href=\"{}\">", wiki_url(&path)),
+            1,
+        );
+
+        write(&page_file(dir, &format!("w/{}", path)), without_live(&html))?;
+        // The front page is at `/w` as well as its own path.
+        if path == "index.md" {
+            write(&page_file(dir, "w"), without_live(&html))?;
+        }
+        pages += 1;
+    }
+
+    write_folders(state, dir, &nav, &nav)?;
+
+    let tags = state.tags(&viewer).await.map_err(io::Error::other)?;
+    for (tag, _) in &tags {
+        let html = TagTemplate {
+            name: state.name(),
+            tag,
+            results: state
+                .tagged_pages(tag, &viewer)
+                .await
+                .map_err(io::Error::other)?,
+        }
+        .render()
+        .map_err(io::Error::other)?;
+        write(
+            &page_file(dir, &format!("tags/{}", tag)),
+            without_live(&html),
+        )?;
+    }
+    let html = TagsTemplate {
+        name: state.name(),
+        tags,
+    }
+    .render()
+    .map_err(io::Error::other)?;
+    write(&page_file(dir, "tags"), without_live(&html))?;
+
+    // Attachments kept in a bucket are linked to from there rather than copied.
+    for path in state.attachment_paths().await.map_err(io::Error::other)? {
+        if !state.can_see(&path, &viewer) {
+            continue;
+        }
+        if let Some(data) = state.attachment(&path).await.map_err(io::Error::other)? {
+            write(&dir.join("a").join(canonical_path(&path)), &data)?;
+        }
+    }
+
+    for (image, file) in [
+        (BrandImage::Favicon, "favicon.svg"),
+        (BrandImage::Logo, "logo"),
+    ] {
+        let found = state.brand_image(image).await.map_err(io::Error::other)?;
+        write(
+            &dir.join(file),
+            found.as_ref().map_or(FAVICON, |(_, data)| data),
+        )?;
+    }
+    write(&dir.join("bootstrap.css"), BOOTSTRAP_CSS)?;
+    let css = state.custom_css().await.map_err(io::Error::other)?;
+    write(&dir.join("custom.css"), css.unwrap_or_default())?;
+    write(&dir.join("bootstrap.js"), BOOTSTRAP_JS)?;
+    write(&dir.join("mermaid.js"), MERMAID_JS)?;
+    write(
+        &dir.join("index.html"),
+        "<!DOCTYPE html>\n<meta http-equiv=\"refresh\" content=\"0; url=/w/\">\n",
+    )?;
+
+    println!("Exported {} pages to {}", pages, dir.display());
+    Ok(())
+}
+
+/// Writes what's served at the URL of each folder under `folder`: a redirect to its index page,
+/// or a listing of what's in it if it doesn't have one.
+fn write_folders(
+    state: &State,
+    dir: &Path,
+    tree: &NavFolder,
+    folder: &NavFolder,
+) -> io::Result<()> {
+    for subfolder in folder.folders() {
+        let html = match subfolder.index() {
+            Some(index) => format!(
+                "<!DOCTYPE html>\n<meta http-equiv=\"refresh\" content=\"0; url={}\">\n",
+                index.url()
+            ),
+            None => FolderTemplate {
+                name: state.name(),
+                folder: subfolder,
+                nav: &tree.to_html(subfolder.path()),
+            }
+            .render()
+            .map_err(io::Error::other)?,
+        };
+        write(
+            &page_file(dir, &format!("w/{}", subfolder.path())),
+            without_live(&html),
+        )?;
+        write_folders(state, dir, tree, subfolder)?;
+    }
+
+    Ok(())
+}
+
+/// Where the page served at `url` goes, as the index of a directory named after it.
+fn page_file(dir: &Path, url: &str) -> PathBuf {
+    dir.join(url).join("index.html")
+}
+
+fn write(file: &Path, data: impl AsRef<[u8]>) -> io::Result<()> {
+    if let Some(parent) = file.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    std::fs::write(file, data)
+}
+
+/// Takes out the parts of a page marked as only working on a running wiki, like searching.
+fn without_live(html: &str) -> String {
+    let mut out = String::with_capacity(html.len());
+    let mut rest = html;
+    while let Some(start) = rest.find("<!--knowbase:live-->") {
+        out.push_str(&rest[..start]);
+        rest = match rest[start..].find("<!--/knowbase:live-->") {
+            Some(end) => &rest[start + end + "<!--/knowbase:live-->".len()..],
+            None => "",
+        };
+    }
+    out.push_str(rest);
+
+    out
+}
@@ -0,0 +1,181 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use actix_web::http::header::HeaderMap;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use tokio::{process::Command, sync::Notify};
+
+use crate::{
+    config,
+    state::{constant_time_eq, State},
+    watch::{markdown_files, sync_file},
+};
+
+/// A git repository of markdown the wiki keeps its pages in step with, read from
+/// `knowbase_GIT_URL` and friends.
+#[derive(Debug, Clone)]
+pub struct Repo {
+    url: String,
+    /// The branch to follow, the remote's default branch if it's not set.
+    branch: Option<String>,
+    /// Where the repository is checked out.
+    dir: PathBuf,
+    /// How long to wait between pulls.
+    interval: Duration,
+    /// What push webhooks have to be signed with, or send, to trigger a pull.
+    webhook_secret: Option<String>,
+    /// Wakes the sync up to pull straight away.
+    pull_now: Arc<Notify>,
+}
+
+impl Repo {
+    /// Reads the repository's settings from the config, if one is configured.
+    pub fn from_config() -> Option<Self> {
+        let url = config::var("knowbase_GIT_URL").ok()?;
+        let branch = config::var("knowbase_GIT_BRANCH").ok();
+        let dir = config::var("knowbase_GIT_DIR").unwrap_or_else(|_| "knowbase-git".to_owned());
+        let interval = config::var_or("knowbase_GIT_INTERVAL_SECS", 300);
+        let webhook_secret = config::var("knowbase_GIT_WEBHOOK_SECRET").ok();
+
+        Some(Self {
+            url,
+            branch,
+            dir: PathBuf::from(dir),
+            interval: Duration::from_secs(interval),
+            webhook_secret,
+            pull_now: Arc::new(Notify::new()),
+        })
+    }
+
+    /// Whether push webhooks are accepted at all.
+    pub fn has_webhook(&self) -> bool {
+        self.webhook_secret.is_some()
+    }
+
+    /// Whether a webhook came from the forge the repository is hosted on: GitHub signs the body
+    /// with the secret in `X-Hub-Signature-256`, GitLab sends the secret itself in `X-Gitlab-Token`.
+    pub fn verify_webhook(&self, headers: &HeaderMap, body: &[u8]) -> bool {
+        let Some(secret) = &self.webhook_secret else {
+            return false;
+        };
+        let header = |name| headers.get(name).and_then(|h| h.to_str().ok());
+
+        if let Some(signature) = header("x-hub-signature-256") {
+            let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+            mac.update(body);
+            let expected: String = mac
+                .finalize()
+                .into_bytes()
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect();
+            return constant_time_eq(&format!("sha256={}", expected), signature);
+        }
+
+        header("x-gitlab-token").is_some_and(|token| constant_time_eq(secret, token))
+    }
+
+    /// Has the sync pull as soon as it can, rather than waiting out its interval.
+    pub fn pull_now(&self) {
+        self.pull_now.notify_one();
+    }
+
+    /// Clones the repository if it isn't checked out yet, otherwise moves the checkout to the
+    /// latest commit on the remote, whatever happened to it locally. Returns the commit it's at.
+    async fn pull(&self) -> Result<String, String> {
+        if self.dir.join(".git").exists() {
+            let branch = self.branch.as_deref().unwrap_or("HEAD");
+            git(&self.dir, &["fetch", "--quiet", "origin", branch]).await?;
+            git(&self.dir, &["reset", "--quiet", "--hard", "FETCH_HEAD"]).await?;
+        } else {
+            let dir = self.dir.to_string_lossy();
+            let mut args = vec!["clone", "--quiet"];
+            if let Some(branch) = &self.branch {
+                args.extend(["--branch", branch]);
+            }
+            args.extend(["--", &self.url, &dir]);
+            git(Path::new("."), &args).await?;
+        }
+
+        Ok(git(&self.dir, &["rev-parse", "HEAD"])
+            .await?
+            .trim()
+            .to_owned())
+    }
+
+    /// The markdown files that changed between two commits, or `None` if git can't tell, like
+    /// when the old commit was lost to a force push.
+    async fn changed_files(&self, from: &str, to: &str) -> Option<Vec<PathBuf>> {
+        let range = format!("{}..{}", from, to);
+        let names = git(
+            &self.dir,
+            &["diff", "--name-only", "-z", &range, "--", "*.md"],
+        )
+        .await
+        .ok()?;
+
+        Some(
+            names
+                .split('\0')
+                .filter(|n| !n.is_empty())
+                .map(|n| self.dir.join(n))
+                .collect(),
+        )
+    }
+}
+
+/// Pulls the repository every so often until the server shuts down, importing the markdown files
+/// that changed since the last pull and removing the pages of those that were deleted.
+pub async fn sync_repo(state: State, repo: Repo) {
+    loop {
+        if let Err(e) = sync(&state, &repo).await {
+            eprintln!("Error syncing the git repository: {}", e);
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(repo.interval) => {}
+            _ = repo.pull_now.notified() => {}
+        }
+    }
+}
+
+async fn sync(state: &State, repo: &Repo) -> Result<(), String> {
+    let commit = repo.pull().await?;
+    let synced = state.synced_commit().await.map_err(|e| e.to_string())?;
+    if synced.as_deref() == Some(commit.as_str()) {
+        return Ok(());
+    }
+
+    let changed = match &synced {
+        Some(synced) => repo.changed_files(synced, &commit).await,
+        None => None,
+    };
+    for file in changed.unwrap_or_else(|| markdown_files(&repo.dir)) {
+        sync_file(state, &repo.dir, &file).await;
+    }
+
+    state
+        .set_synced_commit(&commit)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+/// Runs git in `dir`, giving what it printed or why it failed.
+async fn git(dir: &Path, args: &[&str]) -> Result<String, String> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(args)
+        .output()
+        .await
+        .map_err(|e| format!("couldn't run git: {}", e))?;
+
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr).trim().to_owned());
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
@@ -0,0 +1,420 @@
+use std::collections::{HashMap, HashSet};
+
+use lazy_static::lazy_static;
+use percent_encoding::percent_decode_str;
+use regex::{Captures, Regex};
+use serde::Deserialize;
+
+use crate::{math, paths::slug_path};
+
+lazy_static! {
+    static ref NOTION_HASH_RE: Regex =
+        Regex::new(r"(?: |%20)[0-9a-f]{32}(\.[A-Za-z0-9]+|/|$)").unwrap();
+    /// Link targets can have parentheses in them as long as they're balanced, like CommonMark's.
+    static ref MD_LINK_RE: Regex =
+        Regex::new(r"(!?\[[^\]]*\])\(((?:[^()\s]|\([^()\s]*\))+)\)").unwrap();
+    static ref WIKILINK_RE: Regex =
+        Regex::new(r"(!?)\[\[([^\]|#]*)(#[^\]|]*)?(?:\|([^\]]*))?\]\]").unwrap();
+    /// The `|300` or `|300x200` Obsidian uses to size an embedded image.
+    static ref IMAGE_SIZE_RE: Regex = Regex::new(r"^\d+(?:x\d+)?$").unwrap();
+}
+
+/// How an uploaded archive should be interpreted, selected on the upload form.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ImportMode {
+    /// Files are imported exactly as they appear in the archive.
+    #[default]
+    Raw,
+    /// Notion exports, which append a 32 character hash to every file and folder name.
+    Notion,
+    /// Obsidian vaults, which link pages and attachments with `[[wikilinks]]`.
+    Obsidian,
+}
+
+/// Rewrites paths and markdown from an exported archive into something the wiki understands.
+pub struct Importer {
+    mode: ImportMode,
+    /// Where each file is imported to keyed by its lowercased file name, Obsidian resolves links
+    /// by name alone.
+    by_name: HashMap<String, String>,
+    /// Every lowercased folder in the archive, to find the notes describing them.
+    folders: HashSet<String>,
+    /// Whether spaces in file names become `-` when they're turned into paths.
+    dashes: bool,
+}
+
+impl Importer {
+    pub fn new(mode: ImportMode, files: &[String], dashes: bool) -> Self {
+        let folders = files
+            .iter()
+            .flat_map(|f| {
+                let f = f.trim_end_matches('/').to_lowercase();
+                f.match_indices('/')
+                    .map(|(i, _)| f[..i].to_owned())
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+
+        let mut importer = Self {
+            mode,
+            by_name: HashMap::new(),
+            folders,
+            dashes,
+        };
+        importer.by_name = files
+            .iter()
+            .map(|f| (file_name(f).to_lowercase(), importer.page_path(f)))
+            .collect();
+
+        importer
+    }
+
+    /// Whether a file from the archive should be left out, like hidden files, the settings
+    /// Obsidian keeps in `.obsidian` and the resource forks macOS adds to zips.
+    pub fn skips(&self, path: &str) -> bool {
+        path.split('/')
+            .any(|s| s.starts_with('.') || s == "__MACOSX")
+    }
+
+    /// The path a file from the archive should be stored under, as a slug so it makes for a
+    /// tidy URL.
+    pub fn page_path(&self, path: &str) -> String {
+        let path = match self.mode {
+            ImportMode::Notion => strip_notion_hashes(path),
+            ImportMode::Obsidian => self.folder_note(path).unwrap_or_else(|| path.to_owned()),
+            ImportMode::Raw => path.to_owned(),
+        };
+
+        slug_path(&path, self.dashes)
+    }
+
+    /// Where a folder note is imported to, the `index.md` of the folder it describes. Folder
+    /// notes are named after their folder and kept either inside it or beside it.
+    fn folder_note(&self, path: &str) -> Option<String> {
+        let stem = path.strip_suffix(".md")?;
+        let (dir, name) = stem.rsplit_once('/').unwrap_or(("", stem));
+        let parent = dir.rsplit('/').next().unwrap_or_default();
+        if !parent.is_empty() && parent.to_lowercase() == name.to_lowercase() {
+            Some(format!("{}/index.md", dir))
+        } else if self.folders.contains(&stem.to_lowercase()) {
+            Some(format!("{}/index.md", stem))
+        } else {
+            None
+        }
+    }
+
+    /// Rewrites the links in a page so they point at where their targets were imported to.
+    /// Code is left as it is, links in it are only examples.
+    pub fn convert(&self, md: String) -> String {
+        outside_code(&md, |text| self.convert_links(text))
+    }
+
+    fn convert_links(&self, text: &str) -> String {
+        let md = self.convert_mode(text);
+
+        // Files were imported under their slugs, so links to them have to use them too.
+        MD_LINK_RE
+            .replace_all(&md, |c: &Captures| {
+                if is_external(&c[2]) {
+                    return c[0].to_owned();
+                }
+                let split = c[2].find(['#', '?']).unwrap_or(c[2].len());
+                let (target, rest) = c[2].split_at(split);
+                let target =
+                    slug_path(&percent_decode_str(target).decode_utf8_lossy(), self.dashes);
+                format!("{}({}{})", &c[1], target.replace(' ', "%20"), rest)
+            })
+            .into_owned()
+    }
+
+    fn convert_mode(&self, md: &str) -> String {
+        match self.mode {
+            ImportMode::Raw => md.to_owned(),
+            ImportMode::Notion => MD_LINK_RE
+                .replace_all(md, |c: &Captures| {
+                    if is_external(&c[2]) {
+                        c[0].to_owned()
+                    } else {
+                        format!("{}({})", &c[1], strip_notion_hashes(&c[2]))
+                    }
+                })
+                .into_owned(),
+            ImportMode::Obsidian => WIKILINK_RE
+                .replace_all(md, |c: &Captures| self.convert_wikilink(c))
+                .into_owned(),
+        }
+    }
+
+    fn convert_wikilink(&self, c: &Captures) -> String {
+        let embed = &c[1];
+        let target = c[2].trim();
+        let heading = c.get(3).map(|h| h.as_str()).unwrap_or_default();
+
+        // An embedded image's size isn't much of a description, so it's named after its file.
+        let label = c
+            .get(4)
+            .map(|l| l.as_str().trim())
+            .filter(|l| !(embed == "!" && IMAGE_SIZE_RE.is_match(l)));
+        let label = match label {
+            Some(label) => label.to_owned(),
+            None if target.is_empty() => heading.trim_start_matches('#').to_owned(),
+            None => file_name(target).trim_end_matches(".md").to_owned(),
+        };
+
+        let heading = heading_anchor(heading);
+        if target.is_empty() {
+            return format!("{}[{}]({})", embed, label, heading);
+        }
+
+        // Obsidian leaves the extension off links to notes but not to attachments.
+        let mut target = target.to_owned();
+        if !file_name(&target).contains('.') {
+            target.push_str(".md");
+        }
+
+        let path = self
+            .by_name
+            .get(&file_name(&target).to_lowercase())
+            .unwrap_or(&target);
+
+        // Embedded notes are shown in place, the same as an include.
+        if embed == "!" && path.ends_with(".md") {
+            return format!("{{{{include: /{}}}}}", path.trim_start_matches('/'));
+        }
+
+        format!(
+            "{}[{}](/{}{})",
+            embed,
+            label,
+            path.trim_start_matches('/').replace(' ', "%20"),
+            heading
+        )
+    }
+}
+
+fn strip_notion_hashes(path: &str) -> String {
+    NOTION_HASH_RE.replace_all(path, "$1").into_owned()
+}
+
+/// Whether a link goes somewhere other than a file in the archive: it has a scheme, like
+/// `tel:` or `https:`, or a host, or it's only a fragment or query on the page it's in.
+fn is_external(url: &str) -> bool {
+    url.starts_with(['#', '?'])
+        || url.starts_with("//")
+        || url
+            .split(['/', '?', '#'])
+            .next()
+            .is_some_and(|s| s.contains(':'))
+}
+
+/// `md` with `rewrite` applied to everything but fenced code blocks and inline code spans.
+fn outside_code(md: &str, rewrite: impl Fn(&str) -> String) -> String {
+    let mut out = String::with_capacity(md.len());
+    let mut fence: Option<String> = None;
+    for line in md.split_inclusive('\n') {
+        if let Some(open) = &fence {
+            if math::closing_fence(line, open) {
+                fence = None;
+            }
+            out.push_str(line);
+            continue;
+        }
+        if let Some((open, _)) = math::opening_fence(line) {
+            fence = Some(open);
+            out.push_str(line);
+            continue;
+        }
+
+        let mut rest = line;
+        while let Some(start) = rest.find('`') {
+            let ticks = backtick_run(&rest[start..]);
+            let code = &rest[start + ticks..];
+            // A span ends at the next run of as many backticks, without one they're just text.
+            let Some(end) = closing_backticks(code, ticks) else {
+                break;
+            };
+            let end = start + ticks + end + ticks;
+            out.push_str(&rewrite(&rest[..start]));
+            out.push_str(&rest[start..end]);
+            rest = &rest[end..];
+        }
+        out.push_str(&rewrite(rest));
+    }
+
+    out
+}
+
+fn backtick_run(text: &str) -> usize {
+    text.len() - text.trim_start_matches('`').len()
+}
+
+/// Where the first run of exactly `ticks` backticks in `text` starts.
+fn closing_backticks(text: &str, ticks: usize) -> Option<usize> {
+    let mut from = 0;
+    while let Some(start) = text[from..].find('`') {
+        let start = from + start;
+        let run = backtick_run(&text[start..]);
+        if run == ticks {
+            return Some(start);
+        }
+        from = start + run;
+    }
+
+    None
+}
+
+fn file_name(path: &str) -> &str {
+    path.rsplit('/').next().unwrap_or(path)
+}
+
+/// Approximates the id comrak gives a heading so `[[Page#Heading]]` lands in the right place.
+fn heading_anchor(heading: &str) -> String {
+    let heading = heading.trim_start_matches('#').trim();
+    if heading.is_empty() {
+        return String::new();
+    }
+
+    let slug: String = heading
+        .to_lowercase()
+        .chars()
+        .filter(|c| c.is_alphanumeric() || *c == ' ' || *c == '-' || *c == '_')
+        .map(|c| if c == ' ' { '-' } else { c })
+        .collect();
+
+    format!("#{}", slug)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn importer(mode: ImportMode, files: &[&str]) -> Importer {
+        let files: Vec<String> = files.iter().map(|f| f.to_string()).collect();
+        Importer::new(mode, &files, true)
+    }
+
+    #[test]
+    fn notion_hashes_are_stripped_from_file_names() {
+        let importer = importer(ImportMode::Notion, &[]);
+        assert_eq!(
+            importer.page_path("Team 0123456789abcdef0123456789abcdef/Meeting Notes 0123456789abcdef0123456789abcdef.md"),
+            "Team/Meeting-Notes.md"
+        );
+    }
+
+    #[test]
+    fn notion_hashes_are_stripped_from_links() {
+        let importer = importer(ImportMode::Notion, &[]);
+        assert_eq!(
+            importer.convert(
+                "[Notes](Team%200123456789abcdef0123456789abcdef/Meeting%20Notes%200123456789abcdef0123456789abcdef.md#agenda) and [Site](https://example.com/a%200123456789abcdef0123456789abcdef)".to_owned()
+            ),
+            "[Notes](Team/Meeting-Notes.md#agenda) and [Site](https://example.com/a%200123456789abcdef0123456789abcdef)"
+        );
+    }
+
+    #[test]
+    fn obsidian_wikilinks_point_at_where_notes_were_imported() {
+        let importer = importer(ImportMode::Obsidian, &["Notes/Meeting Notes.md", "Home.md"]);
+        assert_eq!(
+            importer.convert("[[Meeting Notes]] and [[home|Start here]]".to_owned()),
+            "[Meeting Notes](/Notes/Meeting-Notes.md) and [Start here](/Home.md)"
+        );
+    }
+
+    #[test]
+    fn obsidian_headings_become_anchors() {
+        let importer = importer(ImportMode::Obsidian, &["Home.md"]);
+        assert_eq!(
+            importer.convert("[[Home#Getting Started!]] and [[#Next Steps]]".to_owned()),
+            "[Home](/Home.md#getting-started) and [Next Steps](#next-steps)"
+        );
+    }
+
+    #[test]
+    fn obsidian_image_sizes_are_not_labels() {
+        let importer = importer(ImportMode::Obsidian, &["assets/Cat Photo.png"]);
+        assert_eq!(
+            importer.convert("![[Cat Photo.png|300x200]] ![[Cat Photo.png|A cat]]".to_owned()),
+            "![Cat Photo.png](/assets/Cat-Photo.png) ![A cat](/assets/Cat-Photo.png)"
+        );
+    }
+
+    #[test]
+    fn obsidian_embedded_notes_are_included() {
+        let importer = importer(ImportMode::Obsidian, &["Snippets/Footer.md"]);
+        assert_eq!(
+            importer.convert("![[Footer]]".to_owned()),
+            "{{include: /Snippets/Footer.md}}"
+        );
+    }
+
+    #[test]
+    fn obsidian_folder_notes_become_indexes() {
+        let importer = importer(
+            ImportMode::Obsidian,
+            &[
+                "Projects.md",
+                "Projects/Roadmap.md",
+                "Areas/Areas.md",
+                "Areas/Health.md",
+            ],
+        );
+        assert_eq!(importer.page_path("Projects.md"), "Projects/index.md");
+        assert_eq!(importer.page_path("Areas/Areas.md"), "Areas/index.md");
+        assert_eq!(
+            importer.page_path("Projects/Roadmap.md"),
+            "Projects/Roadmap.md"
+        );
+        assert_eq!(
+            importer.convert("[[Projects]] [[Areas]]".to_owned()),
+            "[Projects](/Projects/index.md) [Areas](/Areas/index.md)"
+        );
+    }
+
+    #[test]
+    fn raw_archives_are_passed_through() {
+        let importer = importer(ImportMode::Raw, &["Notes/Meeting Notes.md"]);
+        assert_eq!(
+            importer.page_path("Notes/Meeting Notes.md"),
+            "Notes/Meeting-Notes.md"
+        );
+        let md = "[[Meeting Notes]] and [Home](Home.md)";
+        assert_eq!(importer.convert(md.to_owned()), md);
+    }
+
+    #[test]
+    fn links_with_schemes_are_left_alone() {
+        let importer = importer(ImportMode::Raw, &[]);
+        let md = "[Call](tel:+15551234) [Mail](mailto:a@example.com) [Dot](data:image/png;base64,iVBO==) [Site](//example.com/A%20B) [Query](?page=2)";
+        assert_eq!(importer.convert(md.to_owned()), md);
+    }
+
+    #[test]
+    fn links_to_files_use_their_slugs() {
+        let importer = importer(ImportMode::Raw, &[]);
+        assert_eq!(
+            importer.convert("[Notes](Meeting%20Notes%20(2024).md#agenda)".to_owned()),
+            "[Notes](Meeting-Notes-2024.md#agenda)"
+        );
+    }
+
+    #[test]
+    fn links_in_code_are_left_alone() {
+        let importer = importer(ImportMode::Obsidian, &["Home.md"]);
+        let md = "Write `[Notes](Meeting Notes.md)` or ``[[Home]]``, like [[Home]].\n\n```\n[[Home]] [A](A B.md)\n```\n";
+        assert_eq!(
+            importer.convert(md.to_owned()),
+            "Write `[Notes](Meeting Notes.md)` or ``[[Home]]``, like [Home](/Home.md).\n\n```\n[[Home]] [A](A B.md)\n```\n"
+        );
+    }
+
+    #[test]
+    fn hidden_files_are_skipped() {
+        let importer = importer(ImportMode::Obsidian, &[]);
+        assert!(importer.skips(".obsidian/app.json"));
+        assert!(importer.skips("__MACOSX/Home.md"));
+        assert!(!importer.skips("Notes/Home.md"));
+    }
+}
@@ -1,24 +1,287 @@
+mod acl;
+mod archive;
+mod backup;
+mod config;
+mod epub;
+mod error;
+mod export;
+mod git;
+mod import;
+mod math;
+mod nav;
+mod oidc;
+mod paths;
+mod pdf;
+mod s3;
+mod search;
+mod sessions;
 mod state;
+mod store;
+mod totp;
+mod upload;
+mod watch;
 
-use std::io::Read;
+use std::{
+    future::ready,
+    io::{Cursor, Read, Write},
+    path::PathBuf,
+};
 
-use actix_multipart::form::{tempfile::TempFile, MultipartForm};
-use actix_session::{storage::CookieSessionStore, Session, SessionMiddleware};
+use acl::Viewer;
+use actix_multipart::{
+    form::{tempfile::TempFile, text::Text, MultipartForm, MultipartFormConfig},
+    MultipartError,
+};
+use actix_session::{config::PersistentSession, Session, SessionExt, SessionMiddleware};
 use actix_web::{
-    error::ErrorUnsupportedMediaType,
-    get, post,
-    web::{Data, Form},
-    App, HttpRequest, HttpResponse, HttpServer, Responder, Result,
+    cookie::time::Duration as CookieDuration,
+    delete,
+    dev::{Service, ServiceResponse},
+    error::{ErrorInternalServerError, ErrorPayloadTooLarge, PayloadError},
+    get,
+    http::{
+        header::{ContentDisposition, HeaderValue, AUTHORIZATION, RETRY_AFTER},
+        StatusCode,
+    },
+    post, put, route,
+    web::Bytes,
+    web::{Data, Form, FormConfig, Json, JsonConfig, PayloadConfig, Query},
+    App, Either, HttpRequest, HttpResponse, HttpServer, Responder, Result,
 };
 use askama_actix::Template;
-use serde::Deserialize;
-use state::{Page, SearchResult, State};
+use backup::Backup;
+use clap::{Parser, Subcommand};
+use error::Error;
+use futures_util::{stream, StreamExt};
+use import::ImportMode;
+use nav::{NavFolder, NavPage};
+use paths::{canonical_path, page_path_error, tree_order, wiki_url};
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use serde::{Deserialize, Serialize};
+use similar::{ChangeTag, TextDiff};
+use state::{
+    unix_time, username_error, ApiToken, Attempts, AuditAction, AuditEvent, BrandImage, Page,
+    PageLink, RecentChange, Revision, Role, SearchResult, SessionInfo, State, TrashedPage, Usage,
+    User, Visibility,
+};
+use upload::{Job, Jobs, Upload};
 
 #[derive(Template)]
 #[template(path = "index.html")]
 struct IndexTemplate<'a> {
     name: &'a str,
     error: &'a str,
+    /// Whether to offer logging in through single sign-on.
+    sso: bool,
+}
+
+/// The role of whoever a request comes from, if they're logged in through their session
+/// or sent an API token in an `Authorization: Bearer` header.
+async fn authenticate(req: &HttpRequest, session: &Session, state: &State) -> Result<Option<Role>> {
+    if session.get::<bool>("auth")?.unwrap_or(false) {
+        if session.get::<u64>("epoch")? != Some(state.session_epoch().await?)
+            || session.get::<u64>("expires")?.unwrap_or(0) < unix_time()
+        {
+            session.purge();
+            return Ok(None);
+        }
+
+        // Access code logins have no account and can do anything, guest code logins can only read.
+        // Single sign-on users without a linked account get the role they were given on login.
+        return match session_user(session)? {
+            Some(user) => Ok(state.user_role(&user).await?),
+            None => Ok(Some(session.get::<Role>("role")?.unwrap_or(Role::Admin))),
+        };
+    }
+
+    let token = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Bearer "));
+
+    match token {
+        Some(token) if state.check_token(token.trim()).await? => Ok(Some(Role::Editor)),
+        _ => Ok(None),
+    }
+}
+
+/// Who a request comes from, for checking the access rules of a page.
+async fn viewer(req: &HttpRequest, session: &Session, state: &State) -> Result<Viewer> {
+    let role = authenticate(req, session, state).await?;
+    let user = match role {
+        Some(_) => session_user(session)?,
+        None => None,
+    };
+
+    Ok(Viewer { role, user })
+}
+
+/// Whether the access rules let whoever a request comes from see the page at `path`.
+async fn can_see(req: &HttpRequest, session: &Session, state: &State, path: &str) -> Result<bool> {
+    Ok(state.can_see(path, &viewer(req, session, state).await?))
+}
+
+/// The address a request comes from. `X-Forwarded-For` is only believed when it was added by a
+/// trusted proxy, and then only back to the first address that isn't one, anything before that
+/// could have been sent by the client.
+fn client_ip(req: &HttpRequest) -> String {
+    let Some(mut ip) = req.peer_addr().map(|addr| addr.ip()) else {
+        return String::new();
+    };
+    let Some(state) = req.app_data::<Data<State>>() else {
+        return ip.to_string();
+    };
+
+    let forwarded = req
+        .headers()
+        .get_all("x-forwarded-for")
+        .filter_map(|h| h.to_str().ok())
+        .flat_map(|h| h.split(','))
+        .map(str::trim)
+        .collect::<Vec<_>>();
+    for hop in forwarded.into_iter().rev() {
+        if !state.trusts_proxy(ip) {
+            break;
+        }
+        match hop.parse() {
+            Ok(hop) => ip = hop,
+            Err(_) => break,
+        }
+    }
+
+    ip.to_string()
+}
+
+/// How whoever a request comes from is named in the audit log.
+async fn actor(req: &HttpRequest, session: &Session, state: &State) -> Result<String> {
+    if let Some(user) = session_user(session)? {
+        return Ok(user);
+    }
+    if let Some(name) = session.get::<String>("sso_name")? {
+        return Ok(format!("{} (single sign-on)", name));
+    }
+    if session.get::<bool>("auth")?.unwrap_or(false) {
+        return Ok(match session.get::<Role>("role")? {
+            Some(Role::Reader) => "guest code".to_owned(),
+            _ => "access code".to_owned(),
+        });
+    }
+
+    let token = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Bearer "));
+    match token {
+        Some(token) => match state.token_name(token.trim()).await? {
+            Some(name) => Ok(format!("API token {}", name)),
+            None => Ok("unknown API token".to_owned()),
+        },
+        None => Ok("anonymous".to_owned()),
+    }
+}
+
+/// Adds something `actor` did to the audit log.
+async fn audit(
+    req: &HttpRequest,
+    state: &State,
+    actor: String,
+    action: AuditAction,
+    detail: impl Into<String>,
+) -> Result<()> {
+    state
+        .record(&AuditEvent {
+            timestamp: unix_time(),
+            action,
+            actor,
+            ip: client_ip(req),
+            detail: detail.into(),
+        })
+        .await?;
+
+    Ok(())
+}
+
+/// Whether a request comes from someone allowed to do what needs `role`.
+async fn authorized(
+    req: &HttpRequest,
+    session: &Session,
+    state: &State,
+    role: Role,
+) -> Result<bool> {
+    Ok(authenticate(req, session, state)
+        .await?
+        .is_some_and(|r| r >= role))
+}
+
+/// Logs a session in, as `user` or with the access code if there's no user.
+async fn start_session(
+    session: &Session,
+    state: &State,
+    user: Option<String>,
+    remember: bool,
+) -> Result<()> {
+    session.clear();
+    session.renew();
+    session.insert("auth", true)?;
+    session.insert("epoch", state.session_epoch().await?)?;
+    session.insert("expires", unix_time() + state.session_ttl(remember))?;
+    session.insert("remember", remember)?;
+    if let Some(user) = user {
+        session.insert("user", user)?;
+    }
+
+    Ok(())
+}
+
+/// Marks a response whose session shouldn't outlive the browser, while the session can still be read.
+fn mark_browser_session<B>(res: &mut ServiceResponse<B>) {
+    if let Ok(Some(true)) = res.request().get_session().get::<bool>("remember") {
+        return;
+    }
+
+    res.response_mut().extensions_mut().insert(BrowserSession);
+}
+
+/// Turns the session cookie of a marked response into one the browser forgets when it closes.
+fn forget_on_close<B>(res: &mut ServiceResponse<B>) {
+    if res
+        .response()
+        .extensions()
+        .get::<BrowserSession>()
+        .is_none()
+    {
+        return;
+    }
+
+    let cookies: Vec<_> = res
+        .response()
+        .cookies()
+        .filter(|c| c.name() == SESSION_COOKIE)
+        .map(|c| c.into_owned())
+        .collect();
+    for mut cookie in cookies {
+        // Removal cookies have to keep their expiry in the past.
+        if cookie.value().is_empty() {
+            continue;
+        }
+
+        res.response_mut().del_cookie(SESSION_COOKIE);
+        cookie.set_max_age(None);
+        cookie.unset_expires();
+        let _ = res.response_mut().add_cookie(&cookie);
+    }
+}
+
+struct BrowserSession;
+
+/// The name actix-session gives its cookie.
+const SESSION_COOKIE: &str = "id";
+
+/// The account someone logged in with, if they didn't use the access code.
+fn session_user(session: &Session) -> Result<Option<String>> {
+    Ok(session.get::<String>("user")?)
 }
 
 #[get("/")]
@@ -32,6 +295,7 @@ async fn index(req: HttpRequest, state: Data<State>, session: Session) -> Result
         Ok(IndexTemplate {
             name: state.name(),
             error: "",
+            sso: state.oidc().is_some(),
         }
         .respond_to(&req))
     }
@@ -39,7 +303,11 @@ async fn index(req: HttpRequest, state: Data<State>, session: Session) -> Result
 
 #[derive(Deserialize)]
 struct LoginForm {
+    #[serde(default)]
+    username: String,
     password: String,
+    /// Checkboxes are only sent when they're ticked.
+    remember: Option<String>,
 }
 
 #[post("/login")]
@@ -49,211 +317,3245 @@ async fn login(
     state: Data<State>,
     form: Form<LoginForm>,
 ) -> Result<impl Responder> {
-    if state.is_access_code_correct(&form.password) {
-        session.insert("auth", true)?;
+    let ip = client_ip(&req);
+
+    if let Some(wait) = state.login_lockout(Attempts::Ip(&ip)).await? {
+        let mut res = IndexTemplate {
+            name: state.name(),
+            error: &format!("Too many attempts, try again in {} seconds", wait),
+            sso: state.oidc().is_some(),
+        }
+        .respond_to(&req);
+        *res.status_mut() = StatusCode::TOO_MANY_REQUESTS;
+        res.headers_mut()
+            .insert(RETRY_AFTER, HeaderValue::from(wait));
+        return Ok(res);
+    }
+
+    let user = if form.username.trim().is_empty() {
+        None
+    } else {
+        state.verify_user(&form.username, &form.password).await?
+    };
+
+    if user.is_some()
+        || (form.username.trim().is_empty() && state.is_access_code_correct(&form.password))
+    {
+        // Failures are only forgotten once the second factor is checked too, or the password
+        // could be given again to keep guessing codes.
+        if state.totp_secret(user.as_deref()).await?.is_some() {
+            // An empty name stands for the access code until the second factor is checked.
+            session.insert("totp_pending", user.unwrap_or_default())?;
+            session.insert("totp_remember", form.remember.is_some())?;
+            return Ok(HttpResponse::SeeOther()
+                .append_header(("Location", "/login/totp"))
+                .body(()));
+        }
+
+        state.clear_login_failures(Attempts::Ip(&ip)).await?;
+        let actor = user.clone().unwrap_or("access code".to_owned());
+        start_session(&session, &state, user, form.remember.is_some()).await?;
+        audit(&req, &state, actor, AuditAction::Login, "").await?;
+        Ok(HttpResponse::SeeOther()
+            .append_header(("Location", "/w"))
+            .body(()))
+    } else if form.username.trim().is_empty() && state.is_guest_code_correct(&form.password) {
+        state.clear_login_failures(Attempts::Ip(&ip)).await?;
+        start_session(&session, &state, None, form.remember.is_some()).await?;
+        session.insert("role", Role::Reader)?;
+        audit(
+            &req,
+            &state,
+            "guest code".to_owned(),
+            AuditAction::Login,
+            "",
+        )
+        .await?;
         Ok(HttpResponse::SeeOther()
             .append_header(("Location", "/w"))
             .body(()))
     } else {
+        state.record_login_failure(Attempts::Ip(&ip)).await?;
+        let actor = match form.username.trim() {
+            "" => "access code".to_owned(),
+            username => username.to_lowercase(),
+        };
+        audit(&req, &state, actor, AuditAction::FailedLogin, "").await?;
         Ok(IndexTemplate {
             name: state.name(),
-            error: "Invalid access code",
+            error: if form.username.trim().is_empty() {
+                "Invalid access code"
+            } else {
+                "Invalid username or password"
+            },
+            sso: state.oidc().is_some(),
         }
         .respond_to(&req))
     }
 }
 
+#[post("/logout")]
+async fn logout(req: HttpRequest, session: Session, state: Data<State>) -> Result<impl Responder> {
+    if session.get::<bool>("auth")?.unwrap_or(false) {
+        let actor = actor(&req, &session, &state).await?;
+        audit(&req, &state, actor, AuditAction::Logout, "").await?;
+    }
+    session.purge();
+
+    Ok(HttpResponse::SeeOther()
+        .append_header(("Location", "/"))
+        .body(()))
+}
+
 #[derive(Template)]
-#[template(path = "upload.html")]
-struct UploadTemplate<'a> {
+#[template(path = "totp.html")]
+struct TotpTemplate<'a> {
     name: &'a str,
-    message: &'a str,
+    error: &'a str,
 }
 
-#[get("/upload")]
-async fn upload_page(
+#[get("/login/totp")]
+async fn totp_page(
     req: HttpRequest,
     session: Session,
     state: Data<State>,
 ) -> Result<impl Responder> {
-    let authed = session.get::<bool>("auth")?;
-    if authed.is_none() || !authed.unwrap() {
+    if session.get::<String>("totp_pending")?.is_none() {
         return Ok(HttpResponse::SeeOther()
             .append_header(("Location", "/"))
             .body(()));
     }
 
-    Ok(UploadTemplate {
+    Ok(TotpTemplate {
         name: state.name(),
-        message: "",
+        error: "",
     }
     .respond_to(&req))
 }
 
-#[derive(MultipartForm)]
-struct UploadForm {
-    zip_file: TempFile,
+#[derive(Deserialize)]
+struct TotpForm {
+    code: String,
 }
 
-#[post("/upload")]
-async fn upload_file(
+#[post("/login/totp")]
+async fn totp_login(
     req: HttpRequest,
     session: Session,
     state: Data<State>,
-    payload: MultipartForm<UploadForm>,
+    form: Form<TotpForm>,
 ) -> Result<impl Responder> {
-    let authed = session.get::<bool>("auth")?;
-    if authed.is_none() || !authed.unwrap() {
+    let Some(pending) = session.get::<String>("totp_pending")? else {
         return Ok(HttpResponse::SeeOther()
             .append_header(("Location", "/"))
             .body(()));
-    }
+    };
+    let user = (!pending.is_empty()).then_some(pending);
 
-    let mut zip_file =
-        zip::ZipArchive::new(payload.zip_file.file.as_file()).map_err(ErrorUnsupportedMediaType)?;
+    // Codes are guessed against the account as well as the IP, so spreading guesses over many
+    // IPs doesn't help either.
+    let ip = client_ip(&req);
+    let account = Attempts::Account(user.as_deref());
 
-    let files: Vec<String> = zip_file.file_names().map(|s| s.to_owned()).collect();
+    let lockout = match state.login_lockout(Attempts::Ip(&ip)).await? {
+        Some(wait) => Some(wait),
+        None => state.login_lockout(account).await?,
+    };
+    if let Some(wait) = lockout {
+        let mut res = TotpTemplate {
+            name: state.name(),
+            error: &format!("Too many attempts, try again in {} seconds", wait),
+        }
+        .respond_to(&req);
+        *res.status_mut() = StatusCode::TOO_MANY_REQUESTS;
+        res.headers_mut()
+            .insert(RETRY_AFTER, HeaderValue::from(wait));
+        return Ok(res);
+    }
 
-    for file_name in &files {
-        let mut f = zip_file
-            .by_name(file_name)
-            .map_err(ErrorUnsupportedMediaType)?;
-        if f.is_dir() {
-            continue;
+    let step = state
+        .totp_secret(user.as_deref())
+        .await?
+        .and_then(|secret| totp::verify(&secret, &form.code, unix_time()));
+    let accepted = match step {
+        Some(step) => state.accept_totp_step(user.as_deref(), step).await?,
+        None => false,
+    };
+    if accepted {
+        state.clear_login_failures(Attempts::Ip(&ip)).await?;
+        state.clear_login_failures(account).await?;
+        let remember = session.get::<bool>("totp_remember")?.unwrap_or(false);
+        let actor = user.clone().unwrap_or("access code".to_owned());
+        start_session(&session, &state, user, remember).await?;
+        audit(&req, &state, actor, AuditAction::Login, "with two-factor").await?;
+        Ok(HttpResponse::SeeOther()
+            .append_header(("Location", "/w"))
+            .body(()))
+    } else {
+        state.record_login_failure(Attempts::Ip(&ip)).await?;
+        state.record_login_failure(account).await?;
+        let actor = user.unwrap_or("access code".to_owned());
+        audit(
+            &req,
+            &state,
+            actor,
+            AuditAction::FailedLogin,
+            "wrong two-factor code",
+        )
+        .await?;
+        Ok(TotpTemplate {
+            name: state.name(),
+            error: "Invalid code",
         }
+        .respond_to(&req))
+    }
+}
+
+#[derive(Template)]
+#[template(path = "totp_setup.html")]
+struct TotpSetupTemplate<'a> {
+    name: &'a str,
+    /// Who the secret is for, the user's name or the access code.
+    account: &'a str,
+    enabled: bool,
+    secret: &'a str,
+    qr: &'a str,
+    message: &'a str,
+}
 
-        if f.name().ends_with(".md") && f.enclosed_name().is_some() {
-            let mut md = String::new();
-            f.read_to_string(&mut md)
-                .map_err(ErrorUnsupportedMediaType)?;
+impl TotpSetupTemplate<'_> {
+    /// Renders the setup page, starting over with a new secret for the user to scan.
+    async fn respond(
+        req: &HttpRequest,
+        session: &Session,
+        state: &State,
+        message: &str,
+    ) -> Result<HttpResponse> {
+        let user = session_user(session)?;
+        let account = user.clone().unwrap_or("access code".to_owned());
+        let secret = totp::generate_secret();
+        session.insert("totp_setup", &secret)?;
+        let uri = totp::provisioning_uri(state.name(), &account, &secret);
 
-            state
-                .set_page(&f.enclosed_name().unwrap().to_string_lossy(), md)
-                .await;
+        Ok(TotpSetupTemplate {
+            name: state.name(),
+            account: &match &user {
+                Some(user) => format!("account {}", user),
+                None => "access code".to_owned(),
+            },
+            enabled: state.totp_secret(user.as_deref()).await?.is_some(),
+            secret: &totp::base32(&secret),
+            qr: &totp::qr_svg(&uri),
+            message,
         }
+        .respond_to(req))
     }
+}
 
-    Ok(UploadTemplate {
-        name: state.name(),
-        message: "Upload successful!",
+/// Whether someone can change the two-factor secret for how they logged in. It has to be
+/// through their session, and as the access code's secret is shared only admins can change it.
+async fn can_set_up_totp(req: &HttpRequest, session: &Session, state: &State) -> Result<bool> {
+    if !session.get::<bool>("auth")?.unwrap_or(false) {
+        return Ok(false);
     }
-    .respond_to(&req))
-}
 
-#[derive(Template)]
-#[template(path = "wiki.html")]
-struct WikiTemplate<'a> {
-    name: &'a str,
-    title: &'a str,
-    page: &'a Page,
+    // Single sign-on users without an account have nothing of their own to protect, the provider
+    // checks their second factor.
+    if session.get::<String>("sso_name")?.is_some() {
+        return Ok(false);
+    }
+
+    let role = match session_user(session)? {
+        Some(_) => Role::Reader,
+        None => Role::Admin,
+    };
+    authorized(req, session, state, role).await
 }
 
-#[get("/w{filepath:.*}")]
-async fn wiki(
+#[get("/totp")]
+async fn totp_setup(
     req: HttpRequest,
     session: Session,
     state: Data<State>,
-    path: actix_web::web::Path<String>,
 ) -> Result<impl Responder> {
-    let authed = session.get::<bool>("auth")?;
-    if authed.is_none() || !authed.unwrap() {
+    if !can_set_up_totp(&req, &session, &state).await? {
         return Ok(HttpResponse::SeeOther()
             .append_header(("Location", "/"))
             .body(()));
     }
 
-    let mut trimmed_path = path.trim_start_matches("/");
-    if trimmed_path.is_empty() {
-        trimmed_path = "index.md";
+    TotpSetupTemplate::respond(&req, &session, &state, "").await
+}
+
+#[post("/totp")]
+async fn totp_enable(
+    req: HttpRequest,
+    session: Session,
+    state: Data<State>,
+    form: Form<TotpForm>,
+) -> Result<impl Responder> {
+    if !can_set_up_totp(&req, &session, &state).await? {
+        return Ok(HttpResponse::SeeOther()
+            .append_header(("Location", "/"))
+            .body(()));
     }
 
-    let page = state.get_page(trimmed_path).await.unwrap_or_default();
+    let secret = session.get::<Vec<u8>>("totp_setup")?;
+    let step = secret
+        .as_ref()
+        .and_then(|secret| totp::verify(secret, &form.code, unix_time()));
+    let message = match (secret, step) {
+        (Some(secret), Some(step)) => {
+            let user = session_user(&session)?;
+            state.set_totp_secret(user.as_deref(), &secret).await?;
+            // The code just typed can't then be used to log in.
+            state.accept_totp_step(user.as_deref(), step).await?;
+            "Two-factor authentication enabled!"
+        }
+        _ => "That code didn't match, scan the new code below and try again",
+    };
+
+    TotpSetupTemplate::respond(&req, &session, &state, message).await
+}
 
-    Ok(WikiTemplate {
-        name: ,
-        title: "Wiki",
-        page: &page,
+#[post("/totp/disable")]
+async fn totp_disable(
+    req: HttpRequest,
+    session: Session,
+    state: Data<State>,
+) -> Result<impl Responder> {
+    if !can_set_up_totp(&req, &session, &state).await? {
+        return Ok(HttpResponse::SeeOther()
+            .append_header(("Location", "/"))
+            .body(()));
     }
-    .respond_to(&req))
+
+    state
+        .delete_totp_secret(session_user(&session)?.as_deref())
+        .await?;
+
+    TotpSetupTemplate::respond(&req, &session, &state, "Two-factor authentication disabled").await
 }
 
-#[derive(Template)]
-#[template(path = "search.html")]
-struct SearchTemplate<'a> {
-    name: &'a str,
-    search: &'a str,
-    results: Vec<SearchResult>,
+#[get("/login/oidc")]
+async fn oidc_login(session: Session, state: Data<State>) -> Result<impl Responder> {
+    let Some(oidc) = state.oidc() else {
+        return Ok(HttpResponse::NotFound().body(()));
+    };
+
+    let csrf = format!("{:032x}", rand::random::<u128>());
+    session.insert("oidc_state", &csrf)?;
+
+    Ok(HttpResponse::SeeOther()
+        .append_header(("Location", oidc.authorize_url(&csrf).await?))
+        .body(()))
 }
 
 #[derive(Deserialize)]
-struct SearchForm {
-    query: String,
+struct OidcCallback {
+    code: String,
+    state: String,
 }
 
-#[post("/search")]
-async fn search(
+#[get("/login/oidc/callback")]
+async fn oidc_callback(
     req: HttpRequest,
     session: Session,
     state: Data<State>,
-    form: Form<SearchForm>,
+    query: Query<OidcCallback>,
 ) -> Result<impl Responder> {
-    let authed = session.get::<bool>("auth")?;
-    if authed.is_none() || !authed.unwrap() {
+    let Some(oidc) = state.oidc() else {
+        return Ok(HttpResponse::NotFound().body(()));
+    };
+
+    let expected = session
+        .remove_as::<String>("oidc_state")
+        .and_then(|s| s.ok());
+    if expected.as_deref() != Some(query.state.as_str()) {
+        return Ok(HttpResponse::BadRequest().body("Login expired, please try again"));
+    }
+
+    // Provider identities are only ever the account an admin linked them to, a provider username
+    // matching an account's isn't enough.
+    let identity = oidc.identity(&query.code).await?;
+    let detail = format!("with single sign-on as {}", identity.subject);
+    match state.sso_user(&identity.subject).await? {
+        Some(user) => {
+            start_session(&session, &state, Some(user.clone()), false).await?;
+            audit(&req, &state, user, AuditAction::Login, detail).await?;
+        }
+        None => {
+            start_session(&session, &state, None, false).await?;
+            session.insert("role", oidc.role())?;
+            session.insert("sso_name", &identity.name)?;
+            let actor = format!("{} (single sign-on)", identity.name);
+            audit(&req, &state, actor, AuditAction::Login, detail).await?;
+        }
+    }
+
+    Ok(HttpResponse::SeeOther()
+        .append_header(("Location", "/w"))
+        .body(()))
+}
+
+#[derive(Template)]
+#[template(path = "upload.html")]
+struct UploadTemplate<'a> {
+    name: &'a str,
+    message: &'a str,
+    /// Markdown embedding a file that was just attached.
+    snippet: &'a str,
+}
+
+#[get("/upload")]
+async fn upload_page(
+    req: HttpRequest,
+    session: Session,
+    state: Data<State>,
+) -> Result<impl Responder> {
+    if !authorized(&req, &session, &state, Role::Editor).await? {
         return Ok(HttpResponse::SeeOther()
             .append_header(("Location", "/"))
             .body(()));
     }
 
-    let results = state.run_search(&form.query).await;
-
-    Ok(SearchTemplate {
+    Ok(UploadTemplate {
         name: state.name(),
-        search: &form.query,
-        results,
+        message: "",
+        snippet: "",
     }
     .respond_to(&req))
 }
 
-#[actix_web::main]
-async fn main() -> std::io::Result<()> {
-    let state = State::new();
+#[derive(MultipartForm)]
+struct UploadForm {
+    zip_file: TempFile,
+    import_mode: Option<Text<ImportMode>>,
+    /// Where to put a single page, or the folder to put it in, instead of at its file name.
+    path: Option<Text<String>>,
+    /// Whether the archive is everything the wiki should have, so pages missing from it go.
+    sync: Option<Text<String>>,
+    /// Whether spaces in file names become `-` in page paths.
+    dashes: Option<Text<String>>,
+}
 
-    let master_key = state.master_key().await;
+impl UploadForm {
+    /// The form as an upload to queue, imported as whoever sent the request.
+    async fn into_upload(
+        self,
+        req: &HttpRequest,
+        session: &Session,
+        state: &State,
+    ) -> Result<Upload> {
+        Ok(Upload {
+            file: self.zip_file,
+            mode: self.import_mode.map(|m| m.0).unwrap_or_default(),
+            path: self.path.map(|p| p.0).unwrap_or_default(),
+            sync: self.sync.is_some(),
+            dashes: self.dashes.is_some(),
+            viewer: viewer(req, session, state).await?,
+            actor: actor(req, session, state).await?,
+            ip: client_ip(req),
+        })
+    }
+}
 
-    HttpServer::new(move || {
-        App::new()
-            .wrap(
-                SessionMiddleware::builder(CookieSessionStore::default(), master_key.clone())
-                    .cookie_secure(false)
-                    .build(),
-            )
-            .app_data(Data::new(state.clone()))
-            .service(index)
-            .service(login)
-            .service(wiki)
-            .service(search)
-            .service(favicon)
-            .service(upload_page)
-            .service(upload_file)
-            .service(bootstrap_css)
-            .service(bootstrap_js)
-    })
-    .bind(("0.0.0.0", 8080))?
-    .run()
-    .await
+#[post("/upload")]
+async fn upload_file(
+    req: HttpRequest,
+    session: Session,
+    state: Data<State>,
+    jobs: Data<Jobs>,
+    payload: MultipartForm<UploadForm>,
+) -> Result<impl Responder> {
+    if !authorized(&req, &session, &state, Role::Editor).await? {
+        return Ok(HttpResponse::SeeOther()
+            .append_header(("Location", "/"))
+            .body(()));
+    }
+
+    let upload = payload
+        .into_inner()
+        .into_upload(&req, &session, &state)
+        .await?;
+    let id = jobs.submit(upload);
+    Ok(HttpResponse::SeeOther()
+        .append_header(("Location", format!("/upload/jobs/{}", id)))
+        .body(()))
 }
 
-#[get("/favicon.svg")]
-async fn favicon() -> impl Responder {
-    HttpResponse::Ok().body(&include_bytes!("../assets/favicon.ico")[..])
+#[post("/api/upload")]
+async fn api_upload(
+    req: HttpRequest,
+    session: Session,
+    state: Data<State>,
+    jobs: Data<Jobs>,
+    payload: MultipartForm<UploadForm>,
+) -> Result<impl Responder> {
+    if !authorized(&req, &session, &state, Role::Editor).await? {
+        return Ok(HttpResponse::Unauthorized().body(()));
+    }
+
+    let upload = payload
+        .into_inner()
+        .into_upload(&req, &session, &state)
+        .await?;
+    let actor = upload.actor.clone();
+    let id = jobs.submit(upload);
+    Ok(HttpResponse::Accepted()
+        .append_header(("Location", format!("/api/upload/jobs/{}", id)))
+        .json(jobs.get(id, &actor)))
 }
 
-#[get("/bootstrap.css")]
-async fn bootstrap_css() -> impl Responder {
-    HttpResponse::Ok().body(include_str!("../assets/bootstrap.css"))
+#[derive(Template)]
+#[template(path = "upload_job.html")]
+struct UploadJobTemplate<'a> {
+    name: &'a str,
+    job: &'a Job,
 }
 
-#[get("/bootstrap.js")]
-async fn bootstrap_js() -> impl Responder {
-    HttpResponse::Ok().body(include_str!("../assets/bootstrap.js"))
+#[get("/upload/jobs/{id}")]
+async fn upload_job(
+    req: HttpRequest,
+    session: Session,
+    state: Data<State>,
+    jobs: Data<Jobs>,
+    id: actix_web::web::Path<u64>,
+) -> Result<impl Responder> {
+    if !authorized(&req, &session, &state, Role::Editor).await? {
+        return Ok(HttpResponse::SeeOther()
+            .append_header(("Location", "/"))
+            .body(()));
+    }
+
+    let actor = actor(&req, &session, &state).await?;
+    let Some(job) = jobs.get(*id, &actor) else {
+        return Ok(HttpResponse::NotFound().body("Upload not found"));
+    };
+
+    Ok(UploadJobTemplate {
+        name: state.name(),
+        job: &job,
+    }
+    .respond_to(&req))
+}
+
+#[get("/api/upload/jobs/{id}")]
+async fn api_upload_job(
+    req: HttpRequest,
+    session: Session,
+    state: Data<State>,
+    jobs: Data<Jobs>,
+    id: actix_web::web::Path<u64>,
+) -> Result<impl Responder> {
+    if !authorized(&req, &session, &state, Role::Editor).await? {
+        return Ok(HttpResponse::Unauthorized().body(()));
+    }
+
+    let actor = actor(&req, &session, &state).await?;
+    match jobs.get(*id, &actor) {
+        Some(job) => Ok(HttpResponse::Ok().json(job)),
+        None => Ok(HttpResponse::NotFound().body(())),
+    }
+}
+
+/// Every page the viewer can see as markdown in a zip, laid out like an upload so exporting and
+/// uploading again gives back the same wiki.
+#[get("/export.zip")]
+async fn export_zip(
+    req: HttpRequest,
+    session: Session,
+    state: Data<State>,
+) -> Result<impl Responder> {
+    if !authorized(&req, &session, &state, Role::Reader).await? {
+        return Ok(HttpResponse::SeeOther()
+            .append_header(("Location", "/"))
+            .body(()));
+    }
+
+    let viewer = viewer(&req, &session, &state).await?;
+    let mut zip = zip::ZipWriter::new(Cursor::new(Vec::new()));
+    for path in state.list_pages().await? {
+        if !state.can_see(&path, &viewer) {
+            continue;
+        }
+        let Some(md) = state.page_markdown(&path).await? else {
+            continue;
+        };
+        zip.start_file(path, zip::write::FileOptions::default())
+            .map_err(ErrorInternalServerError)?;
+        zip.write_all(md.as_bytes())
+            .map_err(ErrorInternalServerError)?;
+    }
+    let zip = zip.finish().map_err(ErrorInternalServerError)?.into_inner();
+
+    let filename = format!("{}.zip", state.name());
+    Ok(HttpResponse::Ok()
+        .content_type("application/zip")
+        .insert_header(ContentDisposition::attachment(filename))
+        .body(zip))
+}
+
+/// Every page the viewer can see in one EPUB, in the order they sit in their folders, for
+/// reading offline on an e-reader.
+#[get("/export.epub")]
+async fn export_epub(
+    req: HttpRequest,
+    session: Session,
+    state: Data<State>,
+) -> Result<impl Responder> {
+    if !authorized(&req, &session, &state, Role::Reader).await? {
+        return Ok(HttpResponse::SeeOther()
+            .append_header(("Location", "/"))
+            .body(()));
+    }
+
+    let viewer = viewer(&req, &session, &state).await?;
+    let mut paths = state.list_pages().await?;
+    paths.retain(|path| state.can_see(path, &viewer));
+    paths.sort_by(|a, b| tree_order(a, b));
+
+    let mut book = epub::Epub::new(state.name());
+    for path in paths {
+        let Some(page) = state.get_page(&path).await? else {
+            continue;
+        };
+        let content = state.page_content(&path, &page).await?;
+        book.add_page(&path, &page.title(&path), &content);
+    }
+    // Attachments kept in a bucket stay there and are only shown with a connection.
+    for path in book.embedded_attachments() {
+        if !state.can_see(&path, &viewer) {
+            continue;
+        }
+        if let Some(data) = state.attachment(&path).await? {
+            book.add_image(&path, data);
+        }
+    }
+    let book = book.finish().map_err(ErrorInternalServerError)?;
+
+    let filename = format!("{}.epub", state.name());
+    Ok(HttpResponse::Ok()
+        .content_type("application/epub+zip")
+        .insert_header(ContentDisposition::attachment(filename))
+        .body(book))
+}
+
+/// Pulls the synced git repository straight away when it's pushed to.
+#[post("/hooks/git")]
+async fn git_hook(
+    req: HttpRequest,
+    repo: Data<Option<git::Repo>>,
+    body: Bytes,
+) -> Result<impl Responder> {
+    let Some(repo) = repo.as_ref().as_ref().filter(|r| r.has_webhook()) else {
+        return Ok(HttpResponse::NotFound().body(()));
+    };
+    if !repo.verify_webhook(req.headers(), &body) {
+        return Ok(HttpResponse::Unauthorized().body(()));
+    }
+
+    repo.pull_now();
+    Ok(HttpResponse::Accepted().body(()))
+}
+
+#[derive(MultipartForm)]
+struct AttachmentForm {
+    file: TempFile,
+    /// The folder to put the file in, the root if it's left out.
+    folder: Option<Text<String>>,
+}
+
+#[derive(Serialize)]
+struct ApiAttachment {
+    path: String,
+    url: String,
+    /// Markdown to paste into a page to embed or link to it.
+    markdown: String,
+}
+
+/// Stores the file from an attachment form, giving where it ended up or why it couldn't be kept.
+async fn save_attachment(
+    req: &HttpRequest,
+    session: &Session,
+    state: &State,
+    form: &AttachmentForm,
+) -> Result<std::result::Result<ApiAttachment, String>> {
+    let name = form.file.file_name.as_deref().unwrap_or_default();
+    let folder = form.folder.as_ref().map_or("", |f| f.as_str());
+    let path = canonical_path(&format!("{}/{}", folder, name));
+    if let Some(error) = paths::attachment_path_error(&path) {
+        return Ok(Err(error.to_owned()));
+    }
+    if !can_see(req, session, state, &path).await? {
+        return Ok(Err(format!("You can't add files under {}", path)));
+    }
+    if form.file.size > state.max_attachment_bytes() {
+        return Ok(Err(format!(
+            "Attachments are limited to {} bytes",
+            state.max_attachment_bytes()
+        )));
+    }
+
+    let data = std::fs::read(form.file.file.path()).map_err(ErrorInternalServerError)?;
+    match state.set_attachment(&path, &data).await {
+        Ok(()) => {}
+        Err(e @ Error::QuotaExceeded(_)) => return Ok(Err(e.to_string())),
+        Err(e) => return Err(e.into()),
+    }
+
+    let actor = actor(req, session, state).await?;
+    audit(req, state, actor, AuditAction::Upload, path.as_str()).await?;
+
+    Ok(Ok(ApiAttachment {
+        url: paths::attachment_url(&path),
+        markdown: paths::attachment_markdown(&path),
+        path,
+    }))
+}
+
+#[post("/upload/attachment")]
+async fn upload_attachment(
+    req: HttpRequest,
+    session: Session,
+    state: Data<State>,
+    payload: MultipartForm<AttachmentForm>,
+) -> Result<impl Responder> {
+    if !authorized(&req, &session, &state, Role::Editor).await? {
+        return Ok(HttpResponse::SeeOther()
+            .append_header(("Location", "/"))
+            .body(()));
+    }
+
+    let (message, snippet) = match save_attachment(&req, &session, &state, &payload).await? {
+        Ok(saved) => (
+            format!("Attached {}, embed it with:", saved.path),
+            saved.markdown,
+        ),
+        Err(error) => (error, String::new()),
+    };
+
+    Ok(UploadTemplate {
+        name: state.name(),
+        message: &message,
+        snippet: &snippet,
+    }
+    .respond_to(&req))
+}
+
+#[post("/api/attachments")]
+async fn api_upload_attachment(
+    req: HttpRequest,
+    session: Session,
+    state: Data<State>,
+    payload: MultipartForm<AttachmentForm>,
+) -> Result<impl Responder> {
+    if !authorized(&req, &session, &state, Role::Editor).await? {
+        return Ok(HttpResponse::Unauthorized().body(()));
+    }
+
+    match save_attachment(&req, &session, &state, &payload).await? {
+        Ok(saved) => Ok(HttpResponse::Created().json(saved)),
+        Err(error) => Ok(HttpResponse::BadRequest().body(error)),
+    }
+}
+
+#[route("/a/{filepath:.*}", method = "GET", method = "HEAD")]
+async fn attachment(
+    req: HttpRequest,
+    session: Session,
+    state: Data<State>,
+    path: actix_web::web::Path<String>,
+) -> Result<impl Responder> {
+    let viewer = viewer(&req, &session, &state).await?;
+    let path = canonical_path(&path);
+    if viewer.role.is_none() && !state.is_attachment_readable_anonymously(&path).await? {
+        return Ok(HttpResponse::NotFound().body("Attachment not found"));
+    }
+    if !state.can_see(&path, &viewer) {
+        return Ok(HttpResponse::NotFound().body("Attachment not found"));
+    }
+
+    if let Some(url) = state.attachment_url(&path) {
+        return Ok(HttpResponse::TemporaryRedirect()
+            .append_header(("Location", url))
+            .body(()));
+    }
+
+    let Some(data) = state.attachment(&path).await? else {
+        return Ok(HttpResponse::NotFound().body("Attachment not found"));
+    };
+
+    // Uploaded SVGs and HTML can carry scripts, which shouldn't run as the wiki.
+    Ok(HttpResponse::Ok()
+        .content_type(paths::content_type(&path))
+        .append_header(("Content-Security-Policy", "sandbox"))
+        .append_header(("X-Content-Type-Options", "nosniff"))
+        .body(data))
+}
+
+#[derive(Template)]
+#[template(path = "admin.html")]
+struct AdminTemplate<'a> {
+    name: &'a str,
+    message: &'a str,
+    usage: Usage,
+    trash: Vec<TrashedPage>,
+    tokens: Vec<ApiToken>,
+    users: Vec<User>,
+    /// Logged in sessions, if they're kept where they can be listed.
+    sessions: Option<Vec<SessionInfo>>,
+    /// A token that was just created, shown once so it can be copied.
+    new_token: Option<String>,
+    /// Whether a stylesheet was uploaded, which can be removed again.
+    uploaded_css: bool,
+    /// Whether single sign-on is configured, so users can be linked to it.
+    sso: bool,
+}
+
+impl<'a> AdminTemplate<'a> {
+    async fn new(state: &'a State, message: &'a str) -> error::Result<AdminTemplate<'a>> {
+        Ok(Self {
+            name: state.name(),
+            message,
+            usage: state.usage().await?,
+            trash: state.trash().await?,
+            tokens: state.tokens().await?,
+            users: state.users().await?,
+            sessions: state.sessions().await?,
+            new_token: None,
+            uploaded_css: state.has_uploaded_css().await?,
+            sso: state.oidc().is_some(),
+        })
+    }
+}
+
+#[get("/admin")]
+async fn admin_page(
+    req: HttpRequest,
+    session: Session,
+    state: Data<State>,
+) -> Result<impl Responder> {
+    if !authorized(&req, &session, &state, Role::Admin).await? {
+        return Ok(HttpResponse::SeeOther()
+            .append_header(("Location", "/"))
+            .body(()));
+    }
+
+    Ok(AdminTemplate::new(&state, "").await?.respond_to(&req))
+}
+
+#[derive(Template)]
+#[template(path = "audit.html")]
+struct AuditTemplate<'a> {
+    name: &'a str,
+    events: Vec<AuditEvent>,
+}
+
+#[derive(Template)]
+#[template(path = "broken_links.html")]
+struct BrokenLinksTemplate<'a> {
+    name: &'a str,
+    /// Each page with broken links, with the paths it links to that have no page.
+    pages: Vec<(PageLink, Vec<PageLink>)>,
+}
+
+#[get("/admin/broken-links")]
+async fn broken_links_page(
+    req: HttpRequest,
+    session: Session,
+    state: Data<State>,
+) -> Result<impl Responder> {
+    if !authorized(&req, &session, &state, Role::Admin).await? {
+        return Ok(HttpResponse::SeeOther()
+            .append_header(("Location", "/"))
+            .body(()));
+    }
+
+    let mut pages = Vec::new();
+    for (from, targets) in state.broken_links().await? {
+        let title = match state.get_page(&from).await? {
+            Some(page) => page.title(&from),
+            None => from.clone(),
+        };
+        let link = PageLink {
+            title,
+            url: wiki_url(&from),
+        };
+        let targets = targets
+            .into_iter()
+            .map(|target| PageLink {
+                url: wiki_url(&target),
+                title: target,
+            })
+            .collect();
+        pages.push((link, targets));
+    }
+
+    Ok(BrokenLinksTemplate {
+        name: state.name(),
+        pages,
+    }
+    .respond_to(&req))
+}
+
+/// The most events the audit page shows.
+const AUDIT_PAGE_EVENTS: usize = 500;
+
+#[get("/audit")]
+async fn audit_page(
+    req: HttpRequest,
+    session: Session,
+    state: Data<State>,
+) -> Result<impl Responder> {
+    if !authorized(&req, &session, &state, Role::Admin).await? {
+        return Ok(HttpResponse::SeeOther()
+            .append_header(("Location", "/"))
+            .body(()));
+    }
+
+    Ok(AuditTemplate {
+        name: state.name(),
+        events: state.audit_log(AUDIT_PAGE_EVENTS).await?,
+    }
+    .respond_to(&req))
+}
+
+/// The largest logo the admin page accepts.
+const MAX_LOGO_BYTES: usize = 256 * 1024;
+
+#[derive(MultipartForm)]
+struct LogoForm {
+    logo: TempFile,
+}
+
+#[post("/admin/logo")]
+async fn upload_logo(
+    req: HttpRequest,
+    session: Session,
+    state: Data<State>,
+    payload: MultipartForm<LogoForm>,
+) -> Result<HttpResponse> {
+    upload_brand_image(req, session, state, &payload.logo, BrandImage::Logo).await
+}
+
+#[post("/admin/favicon")]
+async fn upload_favicon(
+    req: HttpRequest,
+    session: Session,
+    state: Data<State>,
+    payload: MultipartForm<LogoForm>,
+) -> Result<HttpResponse> {
+    upload_brand_image(req, session, state, &payload.logo, BrandImage::Favicon).await
+}
+
+async fn upload_brand_image(
+    req: HttpRequest,
+    session: Session,
+    state: Data<State>,
+    file: &TempFile,
+    image: BrandImage,
+) -> Result<HttpResponse> {
+    if !authorized(&req, &session, &state, Role::Admin).await? {
+        return Ok(HttpResponse::SeeOther()
+            .append_header(("Location", "/"))
+            .body(()));
+    }
+
+    let message = if file.size > MAX_LOGO_BYTES {
+        format!("The {} must be smaller than 256KiB", image)
+    } else {
+        let mut data = Vec::new();
+        file.file.as_file().read_to_end(&mut data)?;
+
+        match image_type(&data) {
+            Some(content_type) => {
+                state.set_brand_image(image, content_type, &data).await?;
+                let actor = actor(&req, &session, &state).await?;
+                audit(&req, &state, actor, AuditAction::Upload, image.to_string()).await?;
+                format!("The {} was updated!", image)
+            }
+            None => format!("The {} must be a PNG, JPEG, GIF, WebP or ICO image", image),
+        }
+    };
+
+    Ok(AdminTemplate::new(&state, &message).await?.respond_to(&req))
+}
+
+/// The largest stylesheet the admin page accepts.
+const MAX_CSS_BYTES: usize = 256 * 1024;
+
+#[derive(MultipartForm)]
+struct CssForm {
+    css: TempFile,
+}
+
+#[post("/admin/css")]
+async fn upload_css(
+    req: HttpRequest,
+    session: Session,
+    state: Data<State>,
+    payload: MultipartForm<CssForm>,
+) -> Result<impl Responder> {
+    if !authorized(&req, &session, &state, Role::Admin).await? {
+        return Ok(HttpResponse::SeeOther()
+            .append_header(("Location", "/"))
+            .body(()));
+    }
+
+    let message = if payload.css.size > MAX_CSS_BYTES {
+        "Stylesheet must be smaller than 256KiB"
+    } else {
+        let mut css = String::new();
+        match payload.css.file.as_file().read_to_string(&mut css) {
+            Ok(_) => {
+                state.set_custom_css(Some(&css)).await?;
+                let actor = actor(&req, &session, &state).await?;
+                audit(&req, &state, actor, AuditAction::Upload, "stylesheet").await?;
+                "Stylesheet updated!"
+            }
+            Err(_) => "Stylesheet must be UTF-8 text",
+        }
+    };
+
+    Ok(AdminTemplate::new(&state, message).await?.respond_to(&req))
+}
+
+#[post("/admin/css/remove")]
+async fn remove_css(
+    req: HttpRequest,
+    session: Session,
+    state: Data<State>,
+) -> Result<impl Responder> {
+    if !authorized(&req, &session, &state, Role::Admin).await? {
+        return Ok(HttpResponse::SeeOther()
+            .append_header(("Location", "/"))
+            .body(()));
+    }
+
+    state.set_custom_css(None).await?;
+    let actor = actor(&req, &session, &state).await?;
+    audit(&req, &state, actor, AuditAction::Delete, "stylesheet").await?;
+
+    Ok(AdminTemplate::new(&state, "Stylesheet removed!")
+        .await?
+        .respond_to(&req))
+}
+
+/// Everything the wiki keeps in its store as one archive, to restore here or into another backend.
+#[get("/admin/backup")]
+async fn download_backup(
+    req: HttpRequest,
+    session: Session,
+    state: Data<State>,
+) -> Result<impl Responder> {
+    if !authorized(&req, &session, &state, Role::Admin).await? {
+        return Ok(HttpResponse::SeeOther()
+            .append_header(("Location", "/"))
+            .body(()));
+    }
+
+    let backup = state
+        .backup()
+        .await?
+        .to_zip()
+        .map_err(ErrorInternalServerError)?;
+
+    let filename = format!("{}-backup.zip", state.name());
+    Ok(HttpResponse::Ok()
+        .content_type("application/zip")
+        .insert_header(ContentDisposition::attachment(filename))
+        .body(backup))
+}
+
+#[derive(MultipartForm)]
+struct BackupForm {
+    backup: TempFile,
+}
+
+#[post("/admin/restore")]
+async fn restore_backup(
+    req: HttpRequest,
+    session: Session,
+    state: Data<State>,
+    payload: MultipartForm<BackupForm>,
+) -> Result<impl Responder> {
+    if !authorized(&req, &session, &state, Role::Admin).await? {
+        return Ok(HttpResponse::SeeOther()
+            .append_header(("Location", "/"))
+            .body(()));
+    }
+
+    let message = match Backup::from_zip(payload.backup.file.reopen()?) {
+        Ok(backup) => {
+            state.restore_backup(&backup).await?;
+            let actor = actor(&req, &session, &state).await?;
+            let detail = format!("backup of {} pages", backup.pages.len());
+            audit(&req, &state, actor, AuditAction::Restore, detail).await?;
+            format!("Restored {} pages from the backup.", backup.pages.len())
+        }
+        Err(e @ Error::BadUpload(_)) => e.to_string(),
+        Err(e) => return Err(e.into()),
+    };
+
+    Ok(AdminTemplate::new(&state, &message).await?.respond_to(&req))
+}
+
+#[post("/admin/sessions/invalidate")]
+async fn invalidate_sessions(
+    req: HttpRequest,
+    session: Session,
+    state: Data<State>,
+) -> Result<impl Responder> {
+    if !authorized(&req, &session, &state, Role::Admin).await? {
+        return Ok(HttpResponse::SeeOther()
+            .append_header(("Location", "/"))
+            .body(()));
+    }
+
+    state.invalidate_sessions().await?;
+    let actor = actor(&req, &session, &state).await?;
+    audit(&req, &state, actor, AuditAction::Revoke, "every session").await?;
+
+    Ok(HttpResponse::SeeOther()
+        .append_header(("Location", "/"))
+        .body(()))
+}
+
+#[derive(Deserialize)]
+struct RevokeSessionForm {
+    id: String,
+}
+
+#[post("/admin/sessions/revoke")]
+async fn revoke_session(
+    req: HttpRequest,
+    session: Session,
+    state: Data<State>,
+    form: Form<RevokeSessionForm>,
+) -> Result<impl Responder> {
+    if !authorized(&req, &session, &state, Role::Admin).await? {
+        return Ok(HttpResponse::SeeOther()
+            .append_header(("Location", "/"))
+            .body(()));
+    }
+
+    state.revoke_session(&form.id).await?;
+    let actor = actor(&req, &session, &state).await?;
+    let detail = format!("session {}", form.id);
+    audit(&req, &state, actor, AuditAction::Revoke, detail).await?;
+
+    Ok(HttpResponse::SeeOther()
+        .append_header(("Location", "/admin"))
+        .body(()))
+}
+
+#[derive(Deserialize)]
+struct TokenForm {
+    name: String,
+}
+
+#[post("/admin/tokens")]
+async fn create_token(
+    req: HttpRequest,
+    session: Session,
+    state: Data<State>,
+    form: Form<TokenForm>,
+) -> Result<impl Responder> {
+    if !authorized(&req, &session, &state, Role::Admin).await? {
+        return Ok(HttpResponse::SeeOther()
+            .append_header(("Location", "/"))
+            .body(()));
+    }
+
+    let token = state.create_token(form.name.trim()).await?;
+    let actor = actor(&req, &session, &state).await?;
+    let detail = format!("token {}", form.name.trim());
+    audit(&req, &state, actor, AuditAction::Create, detail).await?;
+    let mut admin = AdminTemplate::new(&state, "").await?;
+    admin.new_token = Some(token);
+
+    Ok(admin.respond_to(&req))
+}
+
+#[derive(Deserialize)]
+struct UserForm {
+    username: String,
+    password: String,
+    role: Role,
+}
+
+#[post("/admin/users")]
+async fn create_user(
+    req: HttpRequest,
+    session: Session,
+    state: Data<State>,
+    form: Form<UserForm>,
+) -> Result<impl Responder> {
+    if !authorized(&req, &session, &state, Role::Admin).await? {
+        return Ok(HttpResponse::SeeOther()
+            .append_header(("Location", "/"))
+            .body(()));
+    }
+
+    let username = form.username.trim().to_lowercase();
+    let message = match username_error(&username) {
+        Some(error) => error.to_owned(),
+        None if form.password.is_empty() => "A user needs a password".to_owned(),
+        None => {
+            state.set_user(&username, &form.password, form.role).await?;
+            let actor = actor(&req, &session, &state).await?;
+            let detail = format!("{} as {}", username, form.role);
+            audit(&req, &state, actor, AuditAction::SaveUser, detail).await?;
+            format!("Saved user {}", username)
+        }
+    };
+
+    Ok(AdminTemplate::new(&state, &message).await?.respond_to(&req))
+}
+
+#[derive(Deserialize)]
+struct LinkSsoForm {
+    username: String,
+    /// The subject to link, or nothing to unlink the user's.
+    #[serde(default)]
+    subject: String,
+}
+
+/// Links a single sign-on subject to a user, so logging in through the provider as it logs in
+/// as them.
+#[post("/admin/users/sso")]
+async fn link_sso(
+    req: HttpRequest,
+    session: Session,
+    state: Data<State>,
+    form: Form<LinkSsoForm>,
+) -> Result<impl Responder> {
+    if !authorized(&req, &session, &state, Role::Admin).await? {
+        return Ok(HttpResponse::SeeOther()
+            .append_header(("Location", "/"))
+            .body(()));
+    }
+
+    let subject = form.subject.trim();
+    let subject = (!subject.is_empty()).then_some(subject);
+    state.link_sso(&form.username, subject).await?;
+    let actor = actor(&req, &session, &state).await?;
+    let detail = match subject {
+        Some(subject) => format!("{} to {}", form.username, subject),
+        None => format!("{} from single sign-on", form.username),
+    };
+    audit(&req, &state, actor, AuditAction::Link, detail).await?;
+
+    Ok(HttpResponse::SeeOther()
+        .append_header(("Location", "/admin"))
+        .body(()))
+}
+
+#[derive(Deserialize)]
+struct DeleteUserForm {
+    username: String,
+}
+
+#[post("/admin/users/delete")]
+async fn delete_user(
+    req: HttpRequest,
+    session: Session,
+    state: Data<State>,
+    form: Form<DeleteUserForm>,
+) -> Result<impl Responder> {
+    if !authorized(&req, &session, &state, Role::Admin).await? {
+        return Ok(HttpResponse::SeeOther()
+            .append_header(("Location", "/"))
+            .body(()));
+    }
+
+    state.delete_user(&form.username).await?;
+    let actor = actor(&req, &session, &state).await?;
+    let detail = format!("user {}", form.username);
+    audit(&req, &state, actor, AuditAction::Delete, detail).await?;
+
+    Ok(HttpResponse::SeeOther()
+        .append_header(("Location", "/admin"))
+        .body(()))
+}
+
+#[derive(Deserialize)]
+struct RevokeForm {
+    hash: String,
+}
+
+#[post("/admin/tokens/revoke")]
+async fn revoke_token(
+    req: HttpRequest,
+    session: Session,
+    state: Data<State>,
+    form: Form<RevokeForm>,
+) -> Result<impl Responder> {
+    if !authorized(&req, &session, &state, Role::Admin).await? {
+        return Ok(HttpResponse::SeeOther()
+            .append_header(("Location", "/"))
+            .body(()));
+    }
+
+    state.revoke_token(&form.hash).await?;
+    let actor = actor(&req, &session, &state).await?;
+    let detail = format!("token {}", form.hash.get(..12).unwrap_or(&form.hash));
+    audit(&req, &state, actor, AuditAction::Revoke, detail).await?;
+
+    Ok(HttpResponse::SeeOther()
+        .append_header(("Location", "/admin"))
+        .body(()))
+}
+
+/// Works out the content type of an image from its magic bytes.
+fn image_type(data: &[u8]) -> Option<&'static str> {
+    match data {
+        [0x89, b'P', b'N', b'G', ..] => Some("image/png"),
+        [0xFF, 0xD8, 0xFF, ..] => Some("image/jpeg"),
+        [b'G', b'I', b'F', b'8', ..] => Some("image/gif"),
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => Some("image/webp"),
+        [0x00, 0x00, 0x01, 0x00, ..] => Some("image/x-icon"),
+        _ => None,
+    }
+}
+
+#[derive(Template)]
+#[template(path = "wiki.html")]
+struct WikiTemplate<'a> {
+    name: &'a str,
+    title: &'a str,
+    path: &'a str,
+    /// Whether to show the links for changing the page.
+    can_edit: bool,
+    page: &'a Page,
+    /// The pages linking here that the viewer can see.
+    backlinks: Vec<PageLink>,
+    /// The navigation tree of every page the viewer can read, as HTML.
+    nav: &'a str,
+    /// The other pages the session read last, most recent first.
+    viewed: Vec<NavPage>,
+    /// Whether the viewer starred the page, `None` if they can't star pages.
+    starred: Option<bool>,
+}
+
+impl WikiTemplate<'_> {
+    /// The directory an `index.md` page introduces, which gets its own search box.
+    fn section(&self) -> Option<&str> {
+        self.path.strip_suffix("/index.md")
+    }
+
+    /// Each folder the page is in, outermost first, with its URL.
+    fn breadcrumbs(&self) -> Vec<(&str, String)> {
+        let Some((dirs, _)) = self.path.rsplit_once('/') else {
+            return Vec::new();
+        };
+
+        dirs.match_indices('/')
+            .map(|(i, _)| i)
+            .chain([dirs.len()])
+            .map(|end| {
+                let start = dirs[..end].rfind('/').map_or(0, |i| i + 1);
+                (&dirs[start..end], wiki_url(&dirs[..end]))
+            })
+            .collect()
+    }
+
+    /// The page's file name, the last of its breadcrumbs.
+    fn file_name(&self) -> &str {
+        self.path.rsplit('/').next().unwrap_or(self.path)
+    }
+}
+
+/// How many pages with a similar name the page not found page suggests.
+const MISSING_PAGE_SUGGESTIONS: usize = 5;
+
+#[derive(Template)]
+#[template(path = "not_found.html")]
+struct NotFoundTemplate<'a> {
+    name: &'a str,
+    path: &'a str,
+    /// Pages found by searching for the words in the path.
+    suggestions: Vec<SearchResult>,
+    /// Whether to offer creating the page, if the viewer can and the path could be a page.
+    can_create: bool,
+}
+
+/// A page not found page for a path without a page, suggesting pages with a similar name and,
+/// for editors, offering to create it.
+async fn missing_page(state: &State, viewer: &Viewer, path: &str) -> Result<HttpResponse> {
+    let name = path
+        .rsplit('/')
+        .next()
+        .unwrap_or(path)
+        .trim_end_matches(".md");
+    let words: Vec<&str> = name
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .collect();
+    let (suggestions, _) = state
+        .run_search(&words.join(" "), "", viewer, 0, MISSING_PAGE_SUGGESTIONS)
+        .await?;
+
+    let html = NotFoundTemplate {
+        name: state.name(),
+        path,
+        suggestions,
+        can_create: viewer.role >= Some(Role::Editor) && page_path_error(path).is_none(),
+    }
+    .render()
+    .map_err(ErrorInternalServerError)?;
+    Ok(HttpResponse::NotFound()
+        .content_type("text/html; charset=utf-8")
+        .body(html))
+}
+
+#[derive(Template)]
+#[template(path = "folder.html")]
+struct FolderTemplate<'a> {
+    name: &'a str,
+    folder: &'a NavFolder,
+    /// The navigation tree of every page the viewer can read, as HTML.
+    nav: &'a str,
+}
+
+/// Whether there's a page at `path` the viewer can read.
+async fn readable_page(state: &State, viewer: &Viewer, path: &str) -> Result<bool> {
+    Ok(state
+        .get_page(path)
+        .await?
+        .is_some_and(|page| state.can_read_page(path, &page, viewer)))
+}
+
+/// How many of the pages a session read last are listed beside the page it's reading.
+const RECENTLY_VIEWED: usize = 8;
+
+/// What a `/w` URL turned out to be for the viewer asking for it.
+enum WikiLookup {
+    /// A page they can read, with its canonical path.
+    Page(String, Box<Page>),
+    /// A folder without an index page, listed from the navigation tree they can see.
+    Folder(String, NavFolder),
+    /// Nothing they can read, so they're offered to create it or shown similar pages.
+    Missing(String),
+    /// Somewhere else to go instead, or a refusal.
+    Respond(HttpResponse),
+}
+
+/// Finds what the `/w` URL for `path` shows `viewer`, running every check on the way so GET and
+/// HEAD answer alike.
+async fn lookup_wiki(state: &State, viewer: &Viewer, path: &str) -> Result<WikiLookup> {
+    let canonical = canonical_path(path);
+    if path != format!("/{}", canonical).trim_end_matches('/') {
+        return Ok(WikiLookup::Respond(
+            HttpResponse::MovedPermanently()
+                .append_header(("Location", wiki_url(&canonical)))
+                .body(()),
+        ));
+    }
+
+    let mut trimmed_path = canonical.as_str();
+    if trimmed_path.is_empty() {
+        trimmed_path = "index.md";
+    }
+
+    let Some(page) = state.get_page(trimmed_path).await? else {
+        // Redirects only go to pages the viewer could read, or they'd give away where hidden
+        // pages are.
+        if let Some(target) = state.redirect(trimmed_path).await? {
+            if readable_page(state, viewer, &target).await? {
+                return Ok(WikiLookup::Respond(
+                    HttpResponse::MovedPermanently()
+                        .append_header(("Location", wiki_url(&target)))
+                        .body(()),
+                ));
+            }
+        }
+
+        // Paths without `.md` can mean the page with it, so links don't need the extension.
+        // Otherwise folders show their index page, or a listing of what's in them if they don't
+        // have one.
+        if !trimmed_path.ends_with(".md") {
+            let index_path = format!("{}/index.md", trimmed_path);
+            let page_path = format!("{}.md", trimmed_path);
+            for found in [index_path, page_path] {
+                if readable_page(state, viewer, &found).await? {
+                    return Ok(WikiLookup::Respond(
+                        HttpResponse::Found()
+                            .append_header(("Location", wiki_url(&found)))
+                            .body(()),
+                    ));
+                }
+            }
+
+            let tree = state.nav_tree(viewer).await?;
+            if tree.find(trimmed_path).is_some() {
+                return Ok(WikiLookup::Folder(trimmed_path.to_owned(), tree));
+            }
+        }
+
+        if viewer.role.is_none() && !state.public_read() {
+            return Ok(WikiLookup::Respond(
+                HttpResponse::SeeOther()
+                    .append_header(("Location", "/"))
+                    .body(()),
+            ));
+        }
+        return Ok(WikiLookup::Missing(trimmed_path.to_owned()));
+    };
+    if viewer.role.is_none() && !state.is_readable_anonymously(trimmed_path, &page) {
+        // Private pages stay hidden when public read is on, otherwise it's time to log in.
+        if state.public_read() {
+            return Ok(WikiLookup::Respond(
+                HttpResponse::NotFound().body("Page not found"),
+            ));
+        }
+        return Ok(WikiLookup::Respond(
+            HttpResponse::SeeOther()
+                .append_header(("Location", "/"))
+                .body(()),
+        ));
+    }
+    if !state.can_see(trimmed_path, viewer) {
+        return Ok(WikiLookup::Respond(
+            HttpResponse::NotFound().body("Page not found"),
+        ));
+    }
+
+    Ok(WikiLookup::Page(trimmed_path.to_owned(), Box::new(page)))
+}
+
+/// Answers like GET would without rendering anything, or counting the page as viewed.
+#[route("/w{filepath:.*}", method = "HEAD")]
+async fn wiki_head(
+    req: HttpRequest,
+    session: Session,
+    state: Data<State>,
+    path: actix_web::web::Path<String>,
+) -> Result<HttpResponse> {
+    let viewer = viewer(&req, &session, &state).await?;
+
+    Ok(match lookup_wiki(&state, &viewer, &path).await? {
+        WikiLookup::Page(..) | WikiLookup::Folder(..) => HttpResponse::Ok()
+            .content_type("text/html; charset=utf-8")
+            .body(()),
+        WikiLookup::Missing(_) => HttpResponse::NotFound()
+            .content_type("text/html; charset=utf-8")
+            .body(()),
+        WikiLookup::Respond(res) => res,
+    })
+}
+
+#[get("/w{filepath:.*}")]
+async fn wiki(
+    req: HttpRequest,
+    session: Session,
+    state: Data<State>,
+    path: actix_web::web::Path<String>,
+) -> Result<impl Responder> {
+    let viewer = viewer(&req, &session, &state).await?;
+    let authed = viewer.role.is_some();
+    let can_edit = viewer.role >= Some(Role::Editor);
+
+    let (trimmed_path, page) = match lookup_wiki(&state, &viewer, &path).await? {
+        WikiLookup::Page(path, page) => (path, *page),
+        WikiLookup::Folder(path, tree) => {
+            return Ok(FolderTemplate {
+                name: state.name(),
+                folder: tree.find(&path).unwrap(),
+                nav: &tree.to_html(&path),
+            }
+            .respond_to(&req));
+        }
+        WikiLookup::Missing(path) => return missing_page(&state, &viewer, &path).await,
+        WikiLookup::Respond(res) => return Ok(res),
+    };
+    let trimmed_path = trimmed_path.as_str();
+
+    let title = page.title(trimmed_path);
+    let backlinks = state.backlinks(trimmed_path, &viewer).await?;
+    let nav = state.nav_tree(&viewer).await?.to_html(trimmed_path);
+    let mut viewed_paths = session.get::<Vec<String>>("viewed")?.unwrap_or_default();
+    viewed_paths.retain(|p| p != trimmed_path);
+    let viewed = state.listed_pages(&viewed_paths, &viewer).await?;
+    viewed_paths.insert(0, trimmed_path.to_owned());
+    viewed_paths.truncate(RECENTLY_VIEWED);
+    session.insert("viewed", viewed_paths)?;
+    let starred = match authed {
+        true => Some(
+            state
+                .starred(&viewer)
+                .await?
+                .iter()
+                .any(|p| p == trimmed_path),
+        ),
+        false => None,
+    };
+    if page.chunks == 0 {
+        return Ok(WikiTemplate {
+            name: state.name(),
+            title: &title,
+            path: trimmed_path,
+            can_edit,
+            page: &page,
+            backlinks,
+            nav: &nav,
+            viewed,
+            starred,
+        }
+        .respond_to(&req));
+    }
+
+    // Large pages are streamed a chunk at a time between the rendered header and footer.
+    let shell = WikiTemplate {
+        name: state.name(),
+        title: &title,
+        path: trimmed_path,
+        can_edit,
+        page: &Page {
+            content: CONTENT_MARKER.to_owned(),
+            ..page.clone()
+        },
+        backlinks,
+        nav: &nav,
+        viewed,
+        starred,
+    }
+    .render()
+    .map_err(ErrorInternalServerError)?;
+    let (head, tail) = shell.split_once(CONTENT_MARKER).unwrap_or((&shell, ""));
+    let (head, tail) = (Bytes::from(head.to_owned()), Bytes::from(tail.to_owned()));
+
+    let path = trimmed_path.to_owned();
+    let chunks = stream::iter(0..page.chunks).then(move |i| {
+        let state = state.clone();
+        let path = path.clone();
+        async move { state.page_chunk(&path, i).await.map(Bytes::from) }
+    });
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .streaming(
+            stream::once(ready(Ok(head)))
+                .chain(chunks)
+                .chain(stream::once(ready(Ok(tail)))),
+        ))
+}
+
+/// The markdown a page was written in, for external editors, diffs and scripts.
+#[get("/raw/{path:.*}")]
+async fn raw_page(
+    req: HttpRequest,
+    session: Session,
+    state: Data<State>,
+    path: actix_web::web::Path<String>,
+) -> Result<impl Responder> {
+    let viewer = viewer(&req, &session, &state).await?;
+    let mut path = canonical_path(&path);
+    if path.is_empty() {
+        path = "index.md".to_owned();
+    }
+
+    let Some(page) = state.get_page(&path).await? else {
+        return Ok(HttpResponse::NotFound().body("Page not found"));
+    };
+    if viewer.role.is_none() && !state.is_readable_anonymously(&path, &page) {
+        if state.public_read() {
+            return Ok(HttpResponse::NotFound().body("Page not found"));
+        }
+        return Ok(HttpResponse::SeeOther()
+            .append_header(("Location", "/"))
+            .body(()));
+    }
+    if !state.can_see(&path, &viewer) {
+        return Ok(HttpResponse::NotFound().body("Page not found"));
+    }
+
+    match state.page_markdown(&path).await? {
+        Some(md) => Ok(HttpResponse::Ok()
+            .content_type("text/markdown; charset=utf-8")
+            .body(md)),
+        None => Ok(HttpResponse::NotFound().body("Page not found")),
+    }
+}
+
+#[derive(Template)]
+#[template(path = "pdf.html")]
+struct PdfTemplate<'a> {
+    title: &'a str,
+    content: &'a str,
+}
+
+/// A page by itself as a PDF, without any of the wiki around it, for sharing with people who
+/// can't read the wiki.
+#[get("/pdf/{path:.*}")]
+async fn page_pdf(
+    req: HttpRequest,
+    session: Session,
+    state: Data<State>,
+    path: actix_web::web::Path<String>,
+) -> Result<impl Responder> {
+    let viewer = viewer(&req, &session, &state).await?;
+    let mut path = canonical_path(&path);
+    if path.is_empty() {
+        path = "index.md".to_owned();
+    }
+
+    let Some(page) = state.get_page(&path).await? else {
+        return Ok(HttpResponse::NotFound().body("Page not found"));
+    };
+    if viewer.role.is_none() && !state.is_readable_anonymously(&path, &page) {
+        if state.public_read() {
+            return Ok(HttpResponse::NotFound().body("Page not found"));
+        }
+        return Ok(HttpResponse::SeeOther()
+            .append_header(("Location", "/"))
+            .body(()));
+    }
+    if !state.can_see(&path, &viewer) {
+        return Ok(HttpResponse::NotFound().body("Page not found"));
+    }
+
+    let title = page.title(&path);
+    let content = state.page_content(&path, &page).await?;
+    let html = PdfTemplate {
+        title: &title,
+        content: &content,
+    }
+    .render()
+    .map_err(ErrorInternalServerError)?;
+    let pdf = pdf::html_to_pdf(&state, &html).await?;
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/pdf")
+        .insert_header(ContentDisposition::attachment(format!("{}.pdf", title)))
+        .body(pdf))
+}
+
+#[derive(Template)]
+#[template(path = "print.html")]
+struct PrintTemplate<'a> {
+    name: &'a str,
+    title: &'a str,
+    /// Where the page itself is, to get back to it.
+    url: &'a str,
+    content: &'a str,
+    diagrams: bool,
+}
+
+/// A page by itself, without the navigation and search around it, to print or save as a PDF
+/// from the browser.
+#[get("/print/{path:.*}")]
+async fn page_print(
+    req: HttpRequest,
+    session: Session,
+    state: Data<State>,
+    path: actix_web::web::Path<String>,
+) -> Result<impl Responder> {
+    let viewer = viewer(&req, &session, &state).await?;
+    let mut path = canonical_path(&path);
+    if path.is_empty() {
+        path = "index.md".to_owned();
+    }
+
+    let Some(page) = state.get_page(&path).await? else {
+        return Ok(HttpResponse::NotFound().body("Page not found"));
+    };
+    if viewer.role.is_none() && !state.is_readable_anonymously(&path, &page) {
+        if state.public_read() {
+            return Ok(HttpResponse::NotFound().body("Page not found"));
+        }
+        return Ok(HttpResponse::SeeOther()
+            .append_header(("Location", "/"))
+            .body(()));
+    }
+    if !state.can_see(&path, &viewer) {
+        return Ok(HttpResponse::NotFound().body("Page not found"));
+    }
+
+    let content = state.page_content(&path, &page).await?;
+    let html = PrintTemplate {
+        name: state.name(),
+        title: &page.title(&path),
+        url: &wiki_url(&path),
+        content: &content,
+        diagrams: page.diagrams,
+    }
+    .render()
+    .map_err(ErrorInternalServerError)?;
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(html))
+}
+
+/// Stands in for the content of a streamed page so the template can be split around it.
+const CONTENT_MARKER: &str = "<!--knowbase:content-->";
+
+#[derive(Template)]
+#[template(path = "edit.html")]
+struct EditTemplate<'a> {
+    name: &'a str,
+    path: &'a str,
+    markdown: &'a str,
+    error: &'a str,
+}
+
+#[get("/edit/{filepath:.*}")]
+async fn edit_page(
+    req: HttpRequest,
+    session: Session,
+    state: Data<State>,
+    path: actix_web::web::Path<String>,
+) -> Result<impl Responder> {
+    if !authorized(&req, &session, &state, Role::Editor).await? {
+        return Ok(HttpResponse::SeeOther()
+            .append_header(("Location", "/"))
+            .body(()));
+    }
+
+    let path = canonical_path(&path);
+    if !can_see(&req, &session, &state, &path).await? {
+        return Ok(HttpResponse::NotFound().body("Page not found"));
+    }
+    let markdown = state.page_markdown(&path).await?;
+    let error = if markdown.is_none() && state.get_page(&path).await?.is_some() {
+        "The markdown for this page wasn't kept, saving will replace it"
+    } else {
+        ""
+    };
+
+    Ok(EditTemplate {
+        name: state.name(),
+        path: &path,
+        markdown: &markdown.unwrap_or_default(),
+        error,
+    }
+    .respond_to(&req))
+}
+
+#[derive(Deserialize)]
+struct EditForm {
+    markdown: String,
+}
+
+#[post("/edit/{filepath:.*}")]
+async fn save_page(
+    req: HttpRequest,
+    session: Session,
+    state: Data<State>,
+    path: actix_web::web::Path<String>,
+    form: Form<EditForm>,
+) -> Result<impl Responder> {
+    if !authorized(&req, &session, &state, Role::Editor).await? {
+        return Ok(HttpResponse::SeeOther()
+            .append_header(("Location", "/"))
+            .body(()));
+    }
+
+    let path = canonical_path(&path);
+    if !can_see(&req, &session, &state, &path).await? {
+        return Ok(HttpResponse::NotFound().body("Page not found"));
+    }
+    let user = session_user(&session)?;
+    match state
+        .set_page_as(&path, form.markdown.clone(), user.as_deref())
+        .await
+    {
+        Ok(_) => {
+            let actor = actor(&req, &session, &state).await?;
+            audit(&req, &state, actor, AuditAction::Write, path.as_str()).await?;
+            Ok(HttpResponse::SeeOther()
+                .append_header(("Location", wiki_url(&path)))
+                .body(()))
+        }
+        Err(e @ (Error::QuotaExceeded(_) | Error::TooLarge(_))) => Ok(EditTemplate {
+            name: state.name(),
+            path: &path,
+            markdown: &form.markdown,
+            error: &e.to_string(),
+        }
+        .respond_to(&req)),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[derive(Template)]
+#[template(path = "new.html")]
+struct NewTemplate<'a> {
+    name: &'a str,
+    path: &'a str,
+    markdown: &'a str,
+    error: &'a str,
+}
+
+#[derive(Deserialize)]
+struct NewQuery {
+    path: Option<String>,
+}
+
+#[get("/new")]
+async fn new_page(
+    req: HttpRequest,
+    session: Session,
+    state: Data<State>,
+    query: Query<NewQuery>,
+) -> Result<impl Responder> {
+    if !authorized(&req, &session, &state, Role::Editor).await? {
+        return Ok(HttpResponse::SeeOther()
+            .append_header(("Location", "/"))
+            .body(()));
+    }
+
+    Ok(NewTemplate {
+        name: state.name(),
+        path: query.path.as_deref().unwrap_or_default(),
+        markdown: "",
+        error: "",
+    }
+    .respond_to(&req))
+}
+
+#[derive(Deserialize)]
+struct NewForm {
+    path: String,
+    markdown: String,
+}
+
+#[post("/new")]
+async fn create_page(
+    req: HttpRequest,
+    session: Session,
+    state: Data<State>,
+    form: Form<NewForm>,
+) -> Result<impl Responder> {
+    if !authorized(&req, &session, &state, Role::Editor).await? {
+        return Ok(HttpResponse::SeeOther()
+            .append_header(("Location", "/"))
+            .body(()));
+    }
+
+    let path = canonical_path(&form.path);
+    if !can_see(&req, &session, &state, &path).await? {
+        return Ok(HttpResponse::NotFound().body("Page not found"));
+    }
+    let error = match page_path_error(&path) {
+        Some(error) => error.to_owned(),
+        None if state.get_page(&path).await?.is_some() => {
+            format!("A page already exists at {}", path)
+        }
+        None => match state
+            .set_page_as(
+                &path,
+                form.markdown.clone(),
+                session_user(&session)?.as_deref(),
+            )
+            .await
+        {
+            Ok(_) => {
+                let actor = actor(&req, &session, &state).await?;
+                audit(&req, &state, actor, AuditAction::Write, path.as_str()).await?;
+                return Ok(HttpResponse::SeeOther()
+                    .append_header(("Location", wiki_url(&path)))
+                    .body(()));
+            }
+            Err(e @ (Error::QuotaExceeded(_) | Error::TooLarge(_))) => e.to_string(),
+            Err(e) => return Err(e.into()),
+        },
+    };
+
+    Ok(NewTemplate {
+        name: state.name(),
+        path: &form.path,
+        markdown: &form.markdown,
+        error: &error,
+    }
+    .respond_to(&req))
+}
+
+#[derive(Template)]
+#[template(path = "move.html")]
+struct MoveTemplate<'a> {
+    name: &'a str,
+    path: &'a str,
+    to: &'a str,
+    error: &'a str,
+}
+
+#[get("/move/{filepath:.*}")]
+async fn move_page_form(
+    req: HttpRequest,
+    session: Session,
+    state: Data<State>,
+    path: actix_web::web::Path<String>,
+) -> Result<impl Responder> {
+    if !authorized(&req, &session, &state, Role::Editor).await? {
+        return Ok(HttpResponse::SeeOther()
+            .append_header(("Location", "/"))
+            .body(()));
+    }
+
+    let path = canonical_path(&path);
+    if !can_see(&req, &session, &state, &path).await? {
+        return Ok(HttpResponse::NotFound().body("Page not found"));
+    }
+    Ok(MoveTemplate {
+        name: state.name(),
+        path: &path,
+        to: &path,
+        error: "",
+    }
+    .respond_to(&req))
+}
+
+#[derive(Deserialize)]
+struct MoveForm {
+    to: String,
+}
+
+#[post("/move/{filepath:.*}")]
+async fn move_page(
+    req: HttpRequest,
+    session: Session,
+    state: Data<State>,
+    path: actix_web::web::Path<String>,
+    form: Form<MoveForm>,
+) -> Result<impl Responder> {
+    if !authorized(&req, &session, &state, Role::Editor).await? {
+        return Ok(HttpResponse::SeeOther()
+            .append_header(("Location", "/"))
+            .body(()));
+    }
+
+    let path = canonical_path(&path);
+    let to = canonical_path(&form.to);
+    let viewer = viewer(&req, &session, &state).await?;
+    if !state.can_see(&path, &viewer) || !state.can_see(&to, &viewer) {
+        return Ok(HttpResponse::NotFound().body("Page not found"));
+    }
+    let error = match page_path_error(&to) {
+        Some(error) => error.to_owned(),
+        None if state.get_page(&path).await?.is_none() => format!("There is no page at {}", path),
+        None if state.get_page(&to).await?.is_some() => {
+            format!("A page already exists at {}", to)
+        }
+        None => match state.move_page(&path, &to).await {
+            Ok(()) => {
+                let actor = actor(&req, &session, &state).await?;
+                let detail = format!("{} to {}", path, to);
+                audit(&req, &state, actor, AuditAction::Move, detail).await?;
+                return Ok(HttpResponse::SeeOther()
+                    .append_header(("Location", wiki_url(&to)))
+                    .body(()));
+            }
+            Err(e @ Error::QuotaExceeded(_)) => e.to_string(),
+            Err(e) => return Err(e.into()),
+        },
+    };
+
+    Ok(MoveTemplate {
+        name: state.name(),
+        path: &path,
+        to: &form.to,
+        error: &error,
+    }
+    .respond_to(&req))
+}
+
+#[derive(Template)]
+#[template(path = "history.html")]
+struct HistoryTemplate<'a> {
+    name: &'a str,
+    path: &'a str,
+    revisions: Vec<Revision>,
+    /// The revision being viewed and its rendered content.
+    viewing: Option<(u64, Page)>,
+}
+
+impl HistoryTemplate<'_> {
+    /// The id of the revision before the one at `i`, revisions are listed newest first.
+    fn previous(&self, i: &usize) -> Option<u64> {
+        self.revisions.get(*i + 1).map(|r| r.id)
+    }
+}
+
+#[derive(Deserialize)]
+struct HistoryQuery {
+    revision: Option<u64>,
+}
+
+#[get("/history/{filepath:.*}")]
+async fn history(
+    req: HttpRequest,
+    session: Session,
+    state: Data<State>,
+    path: actix_web::web::Path<String>,
+    query: Query<HistoryQuery>,
+) -> Result<impl Responder> {
+    if !authorized(&req, &session, &state, Role::Reader).await? {
+        return Ok(HttpResponse::SeeOther()
+            .append_header(("Location", "/"))
+            .body(()));
+    }
+
+    let path = canonical_path(&path);
+    if !can_see(&req, &session, &state, &path).await? {
+        return Ok(HttpResponse::NotFound().body("Page not found"));
+    }
+    let revisions = state.revisions(&path).await?;
+
+    let mut viewing = None;
+    if let Some(id) = query.revision {
+        let Some(md) = state.revision_markdown(&path, id).await? else {
+            return Ok(HttpResponse::NotFound().body("Revision not found"));
+        };
+        viewing = Some((id, state.render_markdown(&path, &md).await?));
+    }
+
+    Ok(HistoryTemplate {
+        name: state.name(),
+        path: &path,
+        revisions,
+        viewing,
+    }
+    .respond_to(&req))
+}
+
+/// One line of a unified diff, `kind` is `+`, `-` or a space for context.
+struct DiffLine {
+    kind: char,
+    text: String,
+}
+
+#[derive(Template)]
+#[template(path = "diff.html")]
+struct DiffTemplate<'a> {
+    name: &'a str,
+    path: &'a str,
+    from: u64,
+    to: u64,
+    /// Runs of changed lines with a little context around each.
+    hunks: Vec<Vec<DiffLine>>,
+}
+
+#[derive(Deserialize)]
+struct DiffQuery {
+    from: u64,
+    to: u64,
+}
+
+#[get("/diff/{filepath:.*}")]
+async fn diff(
+    req: HttpRequest,
+    session: Session,
+    state: Data<State>,
+    path: actix_web::web::Path<String>,
+    query: Query<DiffQuery>,
+) -> Result<impl Responder> {
+    if !authorized(&req, &session, &state, Role::Reader).await? {
+        return Ok(HttpResponse::SeeOther()
+            .append_header(("Location", "/"))
+            .body(()));
+    }
+
+    let path = canonical_path(&path);
+    if !can_see(&req, &session, &state, &path).await? {
+        return Ok(HttpResponse::NotFound().body("Page not found"));
+    }
+    let (Some(old), Some(new)) = (
+        state.revision_markdown(&path, query.from).await?,
+        state.revision_markdown(&path, query.to).await?,
+    ) else {
+        return Ok(HttpResponse::NotFound().body("Revision not found"));
+    };
+
+    let diff = TextDiff::from_lines(&old, &new);
+    let hunks = diff
+        .grouped_ops(3)
+        .iter()
+        .map(|group| {
+            group
+                .iter()
+                .flat_map(|op| diff.iter_changes(op))
+                .map(|change| DiffLine {
+                    kind: match change.tag() {
+                        ChangeTag::Delete => '-',
+                        ChangeTag::Insert => '+',
+                        ChangeTag::Equal => ' ',
+                    },
+                    text: change.value().trim_end_matches('\n').to_owned(),
+                })
+                .collect()
+        })
+        .collect();
+
+    Ok(DiffTemplate {
+        name: state.name(),
+        path: &path,
+        from: query.from,
+        to: query.to,
+        hunks,
+    }
+    .respond_to(&req))
+}
+
+#[derive(Deserialize)]
+struct RestoreForm {
+    path: String,
+    revision: u64,
+}
+
+#[post("/restore")]
+async fn restore(
+    req: HttpRequest,
+    session: Session,
+    state: Data<State>,
+    form: Form<RestoreForm>,
+) -> Result<impl Responder> {
+    if !authorized(&req, &session, &state, Role::Editor).await? {
+        return Ok(HttpResponse::SeeOther()
+            .append_header(("Location", "/"))
+            .body(()));
+    }
+
+    let path = canonical_path(&form.path);
+    if !can_see(&req, &session, &state, &path).await? {
+        return Ok(HttpResponse::NotFound().body("Page not found"));
+    }
+
+    let Some(md) = state.revision_markdown(&path, form.revision).await? else {
+        return Ok(HttpResponse::NotFound().body("Revision not found"));
+    };
+    state
+        .set_page_as(&path, md, session_user(&session)?.as_deref())
+        .await?;
+    let actor = actor(&req, &session, &state).await?;
+    let detail = format!("{} to revision #{}", path, form.revision);
+    audit(&req, &state, actor, AuditAction::Restore, detail).await?;
+
+    Ok(HttpResponse::SeeOther()
+        .append_header(("Location", wiki_url(&path)))
+        .body(()))
+}
+
+#[derive(Deserialize)]
+struct TrashForm {
+    path: String,
+}
+
+#[post("/admin/trash/restore")]
+async fn restore_trashed(
+    req: HttpRequest,
+    session: Session,
+    state: Data<State>,
+    form: Form<TrashForm>,
+) -> Result<impl Responder> {
+    if !authorized(&req, &session, &state, Role::Admin).await? {
+        return Ok(HttpResponse::SeeOther()
+            .append_header(("Location", "/"))
+            .body(()));
+    }
+
+    if !state.restore_trashed(&form.path).await? {
+        return Ok(HttpResponse::NotFound().body("Page not in trash"));
+    }
+    let actor = actor(&req, &session, &state).await?;
+    let detail = format!("{} from the trash", canonical_path(&form.path));
+    audit(&req, &state, actor, AuditAction::Restore, detail).await?;
+
+    Ok(HttpResponse::SeeOther()
+        .append_header(("Location", wiki_url(&canonical_path(&form.path))))
+        .body(()))
+}
+
+#[derive(Deserialize)]
+struct SearchQuery {
+    q: String,
+    #[serde(default)]
+    path: String,
+    offset: Option<usize>,
+    limit: Option<usize>,
+}
+
+/// The most results the search API returns, it's meant for suggestions rather than browsing.
+const MAX_SEARCH_API_LIMIT: usize = 50;
+
+#[get("/api/search")]
+async fn search_api(
+    req: HttpRequest,
+    session: Session,
+    state: Data<State>,
+    query: Query<SearchQuery>,
+) -> Result<impl Responder> {
+    let viewer = viewer(&req, &session, &state).await?;
+    if viewer.role.is_none() && !state.public_read() {
+        return Ok(HttpResponse::Unauthorized().body(()));
+    }
+
+    let offset = query.offset.unwrap_or(0);
+    let limit = query.limit.unwrap_or(10).min(MAX_SEARCH_API_LIMIT);
+    let (results, _) = state
+        .run_search(&query.q, &query.path, &viewer, offset, limit)
+        .await?;
+
+    Ok(HttpResponse::Ok().json(results))
+}
+
+#[derive(Template)]
+#[template(path = "search.html")]
+struct SearchTemplate<'a> {
+    name: &'a str,
+    search: &'a str,
+    /// The directory the search was limited to, empty if it covered everything.
+    scope: &'a str,
+    results: Vec<SearchResult>,
+    offset: usize,
+    total: usize,
+}
+
+impl SearchTemplate<'_> {
+    /// The offset of the previous page of results, if this isn't the first.
+    fn previous(&self) -> Option<usize> {
+        (self.offset > 0).then(|| self.offset.saturating_sub(SEARCH_PAGE_SIZE))
+    }
+
+    /// The offset of the next page of results, if there are more.
+    fn next(&self) -> Option<usize> {
+        let next = self.offset + SEARCH_PAGE_SIZE;
+        (next < self.total).then_some(next)
+    }
+
+    fn query_param(&self) -> String {
+        let query = utf8_percent_encode(self.search, NON_ALPHANUMERIC);
+        if self.scope.is_empty() {
+            return query.to_string();
+        }
+
+        format!(
+            "{}&path={}",
+            query,
+            utf8_percent_encode(self.scope, NON_ALPHANUMERIC)
+        )
+    }
+}
+
+/// How many results each page of search results shows.
+const SEARCH_PAGE_SIZE: usize = 20;
+
+#[derive(Deserialize)]
+struct SearchForm {
+    query: String,
+    /// Only search pages under this directory.
+    #[serde(default)]
+    path: String,
+    offset: Option<usize>,
+}
+
+#[get("/search")]
+async fn search_page(
+    req: HttpRequest,
+    session: Session,
+    state: Data<State>,
+    form: Query<SearchForm>,
+) -> Result<impl Responder> {
+    let viewer = viewer(&req, &session, &state).await?;
+    if viewer.role.is_none() && !state.public_read() {
+        return Ok(HttpResponse::SeeOther()
+            .append_header(("Location", "/"))
+            .body(()));
+    }
+
+    let offset = form.offset.unwrap_or(0);
+    let scope = canonical_path(&form.path);
+    let (results, total) = state
+        .run_search(&form.query, &scope, &viewer, offset, SEARCH_PAGE_SIZE)
+        .await?;
+
+    Ok(SearchTemplate {
+        name: state.name(),
+        search: &form.query,
+        scope: &scope,
+        results,
+        offset,
+        total,
+    }
+    .respond_to(&req))
+}
+
+#[derive(Template)]
+#[template(path = "tags.html")]
+struct TagsTemplate<'a> {
+    name: &'a str,
+    tags: Vec<(String, usize)>,
+}
+
+#[get("/tags")]
+async fn tags_page(
+    req: HttpRequest,
+    session: Session,
+    state: Data<State>,
+) -> Result<impl Responder> {
+    let viewer = viewer(&req, &session, &state).await?;
+    if viewer.role.is_none() && !state.public_read() {
+        return Ok(HttpResponse::SeeOther()
+            .append_header(("Location", "/"))
+            .body(()));
+    }
+
+    Ok(TagsTemplate {
+        name: state.name(),
+        tags: state.tags(&viewer).await?,
+    }
+    .respond_to(&req))
+}
+
+#[derive(Template)]
+#[template(path = "tag.html")]
+struct TagTemplate<'a> {
+    name: &'a str,
+    tag: &'a str,
+    results: Vec<SearchResult>,
+}
+
+#[get("/tags/{tag}")]
+async fn tag_page(
+    req: HttpRequest,
+    session: Session,
+    state: Data<State>,
+    tag: actix_web::web::Path<String>,
+) -> Result<impl Responder> {
+    let viewer = viewer(&req, &session, &state).await?;
+    if viewer.role.is_none() && !state.public_read() {
+        return Ok(HttpResponse::SeeOther()
+            .append_header(("Location", "/"))
+            .body(()));
+    }
+
+    let tag = tag.trim().to_lowercase();
+    Ok(TagTemplate {
+        name: state.name(),
+        tag: &tag,
+        results: state.tagged_pages(&tag, &viewer).await?,
+    }
+    .respond_to(&req))
+}
+
+/// How many pages the recent changes page lists.
+const RECENT_CHANGES: usize = 50;
+
+#[derive(Template)]
+#[template(path = "recent.html")]
+struct RecentTemplate<'a> {
+    name: &'a str,
+    changes: Vec<RecentChange>,
+    /// When the viewer last looked at recent changes, so what's new since can be marked.
+    last_seen: u64,
+}
+
+/// The pages created or updated most recently.
+#[get("/recent")]
+async fn recent_page(
+    req: HttpRequest,
+    session: Session,
+    state: Data<State>,
+) -> Result<impl Responder> {
+    let viewer = viewer(&req, &session, &state).await?;
+    if viewer.role.is_none() && !state.public_read() {
+        return Ok(HttpResponse::SeeOther()
+            .append_header(("Location", "/"))
+            .body(()));
+    }
+
+    let last_seen = session.get::<u64>("recent_seen")?.unwrap_or(u64::MAX);
+    session.insert("recent_seen", unix_time())?;
+    Ok(RecentTemplate {
+        name: state.name(),
+        changes: state.recent_pages(&viewer, RECENT_CHANGES).await?,
+        last_seen,
+    }
+    .respond_to(&req))
+}
+
+#[derive(Deserialize)]
+struct StarForm {
+    starred: bool,
+}
+
+#[post("/star/{path:.*}")]
+async fn star_page(
+    req: HttpRequest,
+    session: Session,
+    state: Data<State>,
+    path: actix_web::web::Path<String>,
+    form: Form<StarForm>,
+) -> Result<impl Responder> {
+    let viewer = viewer(&req, &session, &state).await?;
+    if viewer.role.is_none() {
+        return Ok(HttpResponse::SeeOther()
+            .append_header(("Location", "/"))
+            .body(()));
+    }
+
+    let path = canonical_path(&path);
+    if state.can_see(&path, &viewer) {
+        state.set_starred(&viewer, &path, form.starred).await?;
+    }
+
+    Ok(HttpResponse::SeeOther()
+        .append_header(("Location", wiki_url(&path)))
+        .body(()))
+}
+
+#[derive(Template)]
+#[template(path = "starred.html")]
+struct StarredTemplate<'a> {
+    name: &'a str,
+    pages: Vec<NavPage>,
+}
+
+#[get("/starred")]
+async fn starred_page(
+    req: HttpRequest,
+    session: Session,
+    state: Data<State>,
+) -> Result<impl Responder> {
+    let viewer = viewer(&req, &session, &state).await?;
+    if viewer.role.is_none() {
+        return Ok(HttpResponse::SeeOther()
+            .append_header(("Location", "/"))
+            .body(()));
+    }
+
+    let starred = state.starred(&viewer).await?;
+    Ok(StarredTemplate {
+        name: state.name(),
+        pages: state.listed_pages(&starred, &viewer).await?,
+    }
+    .respond_to(&req))
+}
+
+/// Sends the viewer to a page they can read, picked at random.
+#[get("/random")]
+async fn random_page(
+    req: HttpRequest,
+    session: Session,
+    state: Data<State>,
+) -> Result<impl Responder> {
+    let viewer = viewer(&req, &session, &state).await?;
+    if viewer.role.is_none() && !state.public_read() {
+        return Ok(HttpResponse::SeeOther()
+            .append_header(("Location", "/"))
+            .body(()));
+    }
+
+    let location = match state.random_page(&viewer).await? {
+        Some(path) => wiki_url(&path),
+        None => "/".to_owned(),
+    };
+    Ok(HttpResponse::Found()
+        .append_header(("Location", location))
+        .append_header(("Cache-Control", "no-store"))
+        .body(()))
+}
+
+#[derive(Template)]
+#[template(path = "graph.html")]
+struct GraphTemplate<'a> {
+    name: &'a str,
+}
+
+#[get("/graph")]
+async fn graph_page(
+    req: HttpRequest,
+    session: Session,
+    state: Data<State>,
+) -> Result<impl Responder> {
+    let viewer = viewer(&req, &session, &state).await?;
+    if viewer.role.is_none() && !state.public_read() {
+        return Ok(HttpResponse::SeeOther()
+            .append_header(("Location", "/"))
+            .body(()));
+    }
+
+    Ok(GraphTemplate { name: state.name() }.respond_to(&req))
+}
+
+/// Every page the viewer can read as nodes, and the links between them as edges.
+#[get("/graph.json")]
+async fn graph_json(
+    req: HttpRequest,
+    session: Session,
+    state: Data<State>,
+) -> Result<impl Responder> {
+    let viewer = viewer(&req, &session, &state).await?;
+    if viewer.role.is_none() && !state.public_read() {
+        return Ok(HttpResponse::Unauthorized().body(()));
+    }
+
+    Ok(HttpResponse::Ok().json(state.link_graph(&viewer).await?))
+}
+
+/// The title and path of every page the viewer can read, for the quick switcher.
+#[get("/api/titles")]
+async fn titles(req: HttpRequest, session: Session, state: Data<State>) -> Result<impl Responder> {
+    let viewer = viewer(&req, &session, &state).await?;
+    if viewer.role.is_none() && !state.public_read() {
+        return Ok(HttpResponse::Unauthorized().body(()));
+    }
+
+    Ok(HttpResponse::Ok().json(state.page_titles(&viewer).await?))
+}
+
+#[post("/api/render")]
+async fn render(
+    req: HttpRequest,
+    session: Session,
+    state: Data<State>,
+    md: String,
+) -> Result<impl Responder> {
+    if !authorized(&req, &session, &state, Role::Editor).await? {
+        return Ok(HttpResponse::Unauthorized().body(()));
+    }
+
+    let page = state.render_markdown("", &md).await?;
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(page.content))
+}
+
+#[derive(Deserialize)]
+struct ListQuery {
+    offset: Option<usize>,
+    limit: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct PageList {
+    pages: Vec<String>,
+    total: usize,
+    offset: usize,
+    limit: usize,
+}
+
+/// The most pages a single list request returns.
+const MAX_LIST_LIMIT: usize = 1000;
+
+#[get("/api/pages")]
+async fn list_pages(
+    req: HttpRequest,
+    session: Session,
+    state: Data<State>,
+    query: Query<ListQuery>,
+) -> Result<impl Responder> {
+    if !authorized(&req, &session, &state, Role::Reader).await? {
+        return Ok(HttpResponse::Unauthorized().body(()));
+    }
+
+    let offset = query.offset.unwrap_or(0);
+    let limit = query.limit.unwrap_or(100).min(MAX_LIST_LIMIT);
+    let viewer = viewer(&req, &session, &state).await?;
+    let pages: Vec<_> = state
+        .list_pages()
+        .await?
+        .into_iter()
+        .filter(|path| state.can_see(path, &viewer))
+        .collect();
+
+    Ok(HttpResponse::Ok().json(PageList {
+        total: pages.len(),
+        pages: pages.into_iter().skip(offset).take(limit).collect(),
+        offset,
+        limit,
+    }))
+}
+
+#[derive(Serialize)]
+struct ApiPage {
+    path: String,
+    /// Missing for pages whose markdown wasn't kept.
+    markdown: Option<String>,
+    html: String,
+    visibility: Visibility,
+    tags: Vec<String>,
+}
+
+impl ApiPage {
+    async fn load(state: &State, path: &str) -> error::Result<Option<Self>> {
+        let Some(page) = state.get_page(path).await? else {
+            return Ok(None);
+        };
+
+        Ok(Some(Self {
+            path: path.to_owned(),
+            markdown: state.page_markdown(path).await?,
+            html: state.page_content(path, &page).await?,
+            visibility: page.visibility,
+            tags: page.tags,
+        }))
+    }
+}
+
+#[get("/api/pages/{filepath:.*}")]
+async fn get_page(
+    req: HttpRequest,
+    session: Session,
+    state: Data<State>,
+    path: actix_web::web::Path<String>,
+) -> Result<impl Responder> {
+    if !authorized(&req, &session, &state, Role::Reader).await? {
+        return Ok(HttpResponse::Unauthorized().body(()));
+    }
+
+    let path = canonical_path(&path);
+    if !can_see(&req, &session, &state, &path).await? {
+        return Ok(HttpResponse::NotFound().body(()));
+    }
+
+    match ApiPage::load(&state, &path).await? {
+        Some(page) => Ok(HttpResponse::Ok().json(page)),
+        None => Ok(HttpResponse::NotFound().body(())),
+    }
+}
+
+#[derive(Deserialize)]
+struct PutPage {
+    markdown: String,
+}
+
+impl PutPage {
+    /// The markdown from a JSON body, or a body that's just the markdown itself.
+    fn markdown(body: Either<Json<PutPage>, String>) -> String {
+        match body {
+            Either::Left(page) => page.into_inner().markdown,
+            Either::Right(markdown) => markdown,
+        }
+    }
+}
+
+#[put("/api/pages/{filepath:.*}")]
+async fn put_page(
+    req: HttpRequest,
+    session: Session,
+    state: Data<State>,
+    path: actix_web::web::Path<String>,
+    body: Either<Json<PutPage>, String>,
+) -> Result<impl Responder> {
+    if !authorized(&req, &session, &state, Role::Editor).await? {
+        return Ok(HttpResponse::Unauthorized().body(()));
+    }
+
+    let path = canonical_path(&path);
+    if !can_see(&req, &session, &state, &path).await? {
+        return Ok(HttpResponse::NotFound().body(()));
+    }
+    if let Some(error) = page_path_error(&path) {
+        return Ok(HttpResponse::BadRequest().body(error));
+    }
+
+    let created = state.get_page(&path).await?.is_none();
+    state
+        .set_page_as(
+            &path,
+            PutPage::markdown(body),
+            session_user(&session)?.as_deref(),
+        )
+        .await?;
+    let actor = actor(&req, &session, &state).await?;
+    audit(&req, &state, actor, AuditAction::Write, path.as_str()).await?;
+
+    let page = ApiPage::load(&state, &path).await?;
+    if created {
+        Ok(HttpResponse::Created().json(page))
+    } else {
+        Ok(HttpResponse::Ok().json(page))
+    }
+}
+
+#[delete("/api/pages/{filepath:.*}")]
+async fn delete_page(
+    req: HttpRequest,
+    session: Session,
+    state: Data<State>,
+    path: actix_web::web::Path<String>,
+) -> Result<impl Responder> {
+    if !authorized(&req, &session, &state, Role::Editor).await? {
+        return Ok(HttpResponse::Unauthorized().body(()));
+    }
+
+    if !can_see(&req, &session, &state, &canonical_path(&path)).await? {
+        return Ok(HttpResponse::NotFound().body(()));
+    }
+
+    if !state.trash_page(&path).await? {
+        return Ok(HttpResponse::NotFound().body(()));
+    }
+    let actor = actor(&req, &session, &state).await?;
+    audit(
+        &req,
+        &state,
+        actor,
+        AuditAction::Delete,
+        canonical_path(&path),
+    )
+    .await?;
+
+    Ok(HttpResponse::NoContent().body(()))
+}
+
+/// Some really jank, mildly private wiki software. Settings not given here come from
+/// `knowbase_*` environment variables or the config file.
+#[derive(Parser)]
+#[command(version)]
+struct Cli {
+    /// Address to listen on, instead of `knowbase_ADDRESS`
+    #[arg(long)]
+    address: Option<String>,
+    /// Port to listen on, instead of `knowbase_PORT`
+    #[arg(short, long)]
+    port: Option<u16>,
+    /// Number of HTTP worker threads, instead of `knowbase_WORKERS`
+    #[arg(short, long, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    workers: Option<usize>,
+    /// Config file to read settings from, instead of `knowbase_CONFIG`
+    #[arg(short, long)]
+    config: Option<PathBuf>,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Writes the wiki out as a static site instead of serving it
+    Export {
+        /// Directory to write the site to
+        dir: PathBuf,
+    },
+}
+
+#[actix_web::main]
+async fn main() -> std::io::Result<()> {
+    let cli = Cli::parse();
+    config::load(cli.config.as_deref());
+    let state = State::new().await;
+
+    if let Some(Command::Export { dir }) = &cli.command {
+        return export::export_site(&state, dir).await;
+    }
+
+    let master_key = state.master_key().await.map_err(std::io::Error::other)?;
+    state
+        .bootstrap_admin()
+        .await
+        .map_err(std::io::Error::other)?;
+    state
+        .canonicalize_pages()
+        .await
+        .map_err(std::io::Error::other)?;
+    state
+        .rerender_pages()
+        .await
+        .map_err(std::io::Error::other)?;
+    state
+        .build_search_index()
+        .await
+        .map_err(std::io::Error::other)?;
+    let workers = cli.workers.unwrap_or(state.workers());
+    let address = cli.address.unwrap_or_else(|| {
+        config::var("knowbase_ADDRESS").unwrap_or_else(|_| "0.0.0.0".to_owned())
+    });
+    let port = cli
+        .port
+        .unwrap_or_else(|| config::var_or("knowbase_PORT", 8080));
+
+    if let Some(dir) = state.pages_dir() {
+        actix_web::rt::spawn(watch::sync_dir(state.clone(), dir.clone()));
+    }
+    let repo = git::Repo::from_config();
+    if let Some(repo) = &repo {
+        actix_web::rt::spawn(git::sync_repo(state.clone(), repo.clone()));
+    }
+    let (jobs, queue) = Jobs::new();
+    actix_web::rt::spawn(upload::import_uploads(state.clone(), jobs.clone(), queue));
+
+    let remember_ttl = state.session_ttl(true);
+    let max_upload_bytes = state.max_upload_bytes();
+    // Markdown grows when it's escaped into JSON or a form, so bodies get some room to spare and
+    // pages that are too long are caught with a clearer error when they're written.
+    let max_body_bytes = state.max_page_bytes() * 3;
+    HttpServer::new(move || {
+        App::new()
+            // Has to run inside the session middleware to read the session before it's saved.
+            .wrap_fn(|req, srv| {
+                let res = srv.call(req);
+                async move {
+                    let mut res = res.await?;
+                    mark_browser_session(&mut res);
+                    Ok(res)
+                }
+            })
+            .wrap(
+                SessionMiddleware::builder(state.session_backend(), master_key.clone())
+                    .cookie_secure(false)
+                    .session_lifecycle(
+                        PersistentSession::default()
+                            .session_ttl(CookieDuration::seconds(remember_ttl as i64)),
+                    )
+                    .build(),
+            )
+            .wrap_fn(|req, srv| {
+                let res = srv.call(req);
+                async move {
+                    let mut res = res.await?;
+                    forget_on_close(&mut res);
+                    Ok(res)
+                }
+            })
+            .app_data(Data::new(state.clone()))
+            .app_data(Data::from(jobs.clone()))
+            .app_data(Data::new(repo.clone()))
+            .app_data(
+                MultipartFormConfig::default()
+                    .total_limit(max_upload_bytes)
+                    .error_handler(move |e, _| match e {
+                        MultipartError::Payload(PayloadError::Overflow) => ErrorPayloadTooLarge(
+                            format!("Uploads are limited to {} bytes", max_upload_bytes),
+                        ),
+                        e => e.into(),
+                    }),
+            )
+            .app_data(JsonConfig::default().limit(max_body_bytes))
+            .app_data(FormConfig::default().limit(max_body_bytes))
+            .app_data(PayloadConfig::new(max_body_bytes))
+            .service(index)
+            .service(login)
+            .service(logout)
+            .service(totp_page)
+            .service(totp_login)
+            .service(totp_setup)
+            .service(totp_enable)
+            .service(totp_disable)
+            .service(oidc_login)
+            .service(oidc_callback)
+            .service(wiki)
+            .service(wiki_head)
+            .service(search_page)
+            .service(search_api)
+            .service(tags_page)
+            .service(tag_page)
+            .service(recent_page)
+            .service(graph_page)
+            .service(random_page)
+            .service(star_page)
+            .service(starred_page)
+            .service(graph_json)
+            .service(titles)
+            .service(new_page)
+            .service(create_page)
+            .service(edit_page)
+            .service(save_page)
+            .service(move_page_form)
+            .service(move_page)
+            .service(history)
+            .service(restore)
+            .service(restore_trashed)
+            .service(list_pages)
+            .service(get_page)
+            .service(put_page)
+            .service(delete_page)
+            .service(diff)
+            .service(render)
+            .service(healthz)
+            .service(livez)
+            .service(favicon)
+            .service(logo)
+            .service(upload_page)
+            .service(upload_file)
+            .service(upload_attachment)
+            .service(api_upload_attachment)
+            .service(api_upload)
+            .service(upload_job)
+            .service(api_upload_job)
+            .service(git_hook)
+            .service(export_zip)
+            .service(export_epub)
+            .service(page_pdf)
+            .service(page_print)
+            .service(raw_page)
+            .service(attachment)
+            .service(admin_page)
+            .service(audit_page)
+            .service(broken_links_page)
+            .service(upload_logo)
+            .service(upload_favicon)
+            .service(upload_css)
+            .service(remove_css)
+            .service(download_backup)
+            .service(restore_backup)
+            .service(invalidate_sessions)
+            .service(revoke_session)
+            .service(create_token)
+            .service(create_user)
+            .service(delete_user)
+            .service(link_sso)
+            .service(revoke_token)
+            .service(bootstrap_css)
+            .service(custom_css)
+            .service(bootstrap_js)
+            .service(mermaid_js)
+            .service(set_theme)
+            .service(theme_js)
+    })
+    .workers(workers)
+    .bind((address, port))?
+    .run()
+    .await
+}
+
+/// Whether the wiki can serve pages, which it can't while its storage is unreachable.
+#[route("/healthz", method = "GET", method = "HEAD")]
+async fn healthz(state: Data<State>) -> Result<impl Responder> {
+    state.ping().await?;
+    Ok(HttpResponse::Ok().body("ok"))
+}
+
+/// Whether the server is running at all. Storage isn't checked, restarting the wiki won't bring
+/// it back.
+#[route("/livez", method = "GET", method = "HEAD")]
+async fn livez() -> impl Responder {
+    HttpResponse::Ok().body("ok")
+}
+
+#[get("/favicon.svg")]
+async fn favicon(state: Data<State>) -> Result<impl Responder> {
+    brand_image(&state, BrandImage::Favicon).await
+}
+
+#[get("/logo")]
+async fn logo(state: Data<State>) -> Result<impl Responder> {
+    brand_image(&state, BrandImage::Logo).await
+}
+
+async fn brand_image(state: &State, image: BrandImage) -> Result<HttpResponse> {
+    Ok(match state.brand_image(image).await? {
+        Some((content_type, data)) => HttpResponse::Ok().content_type(content_type).body(data),
+        None => HttpResponse::Ok()
+            .content_type("image/x-icon")
+            .body(FAVICON),
+    })
+}
+
+const BOOTSTRAP_CSS: &str = include_str!("../assets/bootstrap.css");
+const BOOTSTRAP_JS: &str = include_str!("../assets/bootstrap.js");
+const MERMAID_JS: &str = include_str!("../assets/mermaid.js");
+const FAVICON: &[u8] = include_bytes!("../assets/favicon.ico");
+
+#[derive(Deserialize)]
+struct ThemeForm {
+    theme: String,
+}
+
+/// Remembers whether the session wants the light or dark theme.
+#[post("/theme")]
+async fn set_theme(session: Session, form: Form<ThemeForm>) -> Result<impl Responder> {
+    if form.theme != "light" && form.theme != "dark" {
+        return Ok(HttpResponse::BadRequest().body("The theme should be light or dark"));
+    }
+
+    session.insert("theme", &form.theme)?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Switches pages to the theme the session picked, if it picked one, before they're drawn.
+/// Without one they follow the browser's `prefers-color-scheme`.
+#[get("/theme.js")]
+async fn theme_js(session: Session) -> Result<impl Responder> {
+    let script = match session.get::<String>("theme")?.as_deref() {
+        Some(theme @ ("light" | "dark")) => {
+            format!(
+                "document.documentElement.dataset.bsTheme = \"{}\";\n",
+                theme
+            )
+        }
+        _ => String::new(),
+    };
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/javascript")
+        .insert_header(("Cache-Control", "no-store"))
+        .body(script))
+}
+
+/// The deployment's own styles, loaded after bootstrap's so they can override them.
+#[get("/custom.css")]
+async fn custom_css(state: Data<State>) -> Result<impl Responder> {
+    Ok(HttpResponse::Ok()
+        .content_type("text/css; charset=utf-8")
+        .insert_header(("Cache-Control", "no-cache"))
+        .body(state.custom_css().await?.unwrap_or_default()))
+}
+
+#[get("/bootstrap.css")]
+async fn bootstrap_css() -> impl Responder {
+    HttpResponse::Ok().body(BOOTSTRAP_CSS)
+}
+
+#[get("/bootstrap.js")]
+async fn bootstrap_js() -> impl Responder {
+    HttpResponse::Ok().body(BOOTSTRAP_JS)
+}
+
+#[get("/mermaid.js")]
+async fn mermaid_js() -> impl Responder {
+    HttpResponse::Ok()
+        .content_type("text/javascript")
+        .body(MERMAID_JS)
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{dev::ServiceResponse, http::Method, test};
+
+    use super::*;
+
+    /// Sends `req` to a wiki that serves pages from `state`.
+    async fn send(state: &State, req: test::TestRequest) -> ServiceResponse {
+        let key = state.master_key().await.unwrap();
+        let app = test::init_service(
+            App::new()
+                .wrap(SessionMiddleware::new(state.session_backend(), key))
+                .app_data(Data::new(state.clone()))
+                .service(login)
+                .service(wiki)
+                .service(wiki_head),
+        )
+        .await;
+        test::call_service(&app, req.to_request()).await
+    }
+
+    #[actix_web::test]
+    async fn forwarded_headers_do_not_get_around_the_lockout() {
+        let state = State::in_memory();
+        let peer = "203.0.113.9:4000".parse().unwrap();
+
+        let mut status = StatusCode::OK;
+        for i in 0..6 {
+            let req = test::TestRequest::post()
+                .uri("/login")
+                .peer_addr(peer)
+                .insert_header(("X-Forwarded-For", format!("198.51.100.{}", i)))
+                .set_form([("username", ""), ("password", "wrong")]);
+            status = send(&state, req).await.status();
+        }
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[actix_web::test]
+    async fn private_pages_are_hidden_from_anonymous_readers() {
+        let state = State::in_memory();
+        let md = "---\nvisibility: private\n---\n# Payroll";
+        state.set_page("payroll.md", md.to_owned()).await.unwrap();
+
+        let res = send(&state, test::TestRequest::get().uri("/w/payroll.md")).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        let token = state.create_token("tests").await.unwrap();
+        let req = test::TestRequest::get()
+            .uri("/w/payroll.md")
+            .insert_header((AUTHORIZATION, format!("Bearer {}", token)));
+        let res = send(&state, req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn moved_private_pages_do_not_say_where_they_went() {
+        let state = State::in_memory();
+        let md = "---\nvisibility: private\n---\n# Payroll";
+        state.set_page("payroll.md", md.to_owned()).await.unwrap();
+        state.move_page("payroll.md", "hr/pay.md").await.unwrap();
+
+        for uri in ["/w/payroll.md", "/w/hr/pay"] {
+            let res = send(&state, test::TestRequest::get().uri(uri)).await;
+            assert_eq!(res.status(), StatusCode::NOT_FOUND);
+            assert!(res.headers().get("Location").is_none());
+        }
+
+        let token = state.create_token("tests").await.unwrap();
+        let req = test::TestRequest::get()
+            .uri("/w/payroll.md")
+            .insert_header((AUTHORIZATION, format!("Bearer {}", token)));
+        let res = send(&state, req).await;
+        assert_eq!(res.status(), StatusCode::MOVED_PERMANENTLY);
+        assert_eq!(res.headers().get("Location").unwrap(), "/w/hr/pay.md");
+    }
+
+    #[actix_web::test]
+    async fn head_requests_do_not_count_as_views() {
+        let state = State::in_memory();
+        state.set_page("foo.md", "# Foo".to_owned()).await.unwrap();
+
+        let res = send(
+            &state,
+            test::TestRequest::default()
+                .method(Method::HEAD)
+                .uri("/w/foo.md"),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(res.headers().get("set-cookie").is_none());
+
+        let res = send(
+            &state,
+            test::TestRequest::default()
+                .method(Method::HEAD)
+                .uri("/w/bar.md"),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_web::test]
+    async fn mixed_case_links_reach_their_page() {
+        let state = State::in_memory();
+        state
+            .set_page("guides/setup.md", "# Setup".to_owned())
+            .await
+            .unwrap();
+        state
+            .set_page(
+                "index.md",
+                "Start with [Setup](/Guides/Setup.MD).".to_owned(),
+            )
+            .await
+            .unwrap();
+
+        let home = state.get_page("index.md").await.unwrap().unwrap();
+        let href = regex::Regex::new(r#"href="([^"]*)">Setup<"#)
+            .unwrap()
+            .captures(&home.content)
+            .unwrap()[1]
+            .to_owned();
+
+        let mut uri = href;
+        let mut res = send(&state, test::TestRequest::get().uri(&uri)).await;
+        for _ in 0..3 {
+            let Some(location) = res.headers().get("Location") else {
+                break;
+            };
+            uri = location.to_str().unwrap().to_owned();
+            res = send(&state, test::TestRequest::get().uri(&uri)).await;
+        }
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(uri, "/w/guides/setup.md");
+    }
+
+    #[actix_web::test]
+    async fn page_urls_redirect_to_their_canonical_path() {
+        let state = State::in_memory();
+        state.set_page("foo.md", "# Foo".to_owned()).await.unwrap();
+
+        let res = send(&state, test::TestRequest::get().uri("/w/Foo.md/")).await;
+        assert_eq!(res.status(), StatusCode::MOVED_PERMANENTLY);
+        assert_eq!(res.headers().get("Location").unwrap(), "/w/foo.md");
+    }
 }
@@ -0,0 +1,243 @@
+use latex2mathml::{latex_to_mathml, DisplayStyle};
+
+use crate::search::escape_html;
+
+/// A TeX formula taken out of a page so markdown can't mangle its `_`, `*` and `\`.
+#[derive(Debug)]
+pub struct Formula {
+    tex: String,
+    /// Whether it was written as `$$...$$` or a `math` fence rather than `$...$`.
+    block: bool,
+}
+
+impl Formula {
+    /// MathML for the formula, or the TeX itself if it couldn't be converted.
+    fn to_html(&self) -> String {
+        let display = if self.block {
+            DisplayStyle::Block
+        } else {
+            DisplayStyle::Inline
+        };
+
+        // The converter copies text through as is, so angle brackets can't be let in as HTML.
+        let tex = self.tex.trim().replace('<', "\\lt ").replace('>', "\\gt ");
+        match latex_to_mathml(&tex, display) {
+            Ok(mathml) if !mathml.contains("[PARSE ERROR") => mathml
+                .replace("<mo><</mo>", "<mo>&lt;</mo>")
+                .replace("<mo>></mo>", "<mo>&gt;</mo>"),
+            _ => format!(
+                "<code class=\"math-error\">{}</code>",
+                escape_html(self.tex.trim())
+            ),
+        }
+    }
+}
+
+fn marker(i: usize) -> String {
+    format!("KNOWBASEMATH{}X", i)
+}
+
+/// Swaps every `$...$`, `$$...$$` and ```` ```math ```` block outside of code for a marker
+/// markdown leaves alone, so the formulas can be put back with `restore` once it's rendered.
+pub fn extract(md: &str) -> (String, Vec<Formula>) {
+    let mut out = String::with_capacity(md.len());
+    let mut formulas = Vec::new();
+    // The fence a code block was opened with and whether it holds math.
+    let mut fence: Option<(String, bool)> = None;
+    // A `$$` formula that hasn't been closed yet.
+    let mut block: Option<String> = None;
+
+    for line in md.split_inclusive('\n') {
+        if let Some((open, math)) = &fence {
+            let closes = closing_fence(line, open);
+            match (math, formulas.last_mut()) {
+                (true, Some(Formula { tex, .. })) if !closes => tex.push_str(line),
+                (false, _) => out.push_str(line),
+                _ => {}
+            }
+            if closes {
+                if *math {
+                    out.push('\n');
+                }
+                fence = None;
+            }
+            continue;
+        }
+
+        let mut line = line;
+        if let Some(mut tex) = block.take() {
+            match line.find("$$") {
+                Some(end) => {
+                    tex.push_str(&line[..end]);
+                    out.push_str(&marker(formulas.len()));
+                    formulas.push(Formula { tex, block: true });
+                    line = &line[end + 2..];
+                }
+                None => {
+                    tex.push_str(line);
+                    block = Some(tex);
+                    continue;
+                }
+            }
+        } else if let Some((open, info)) = opening_fence(line) {
+            let math = info == "math";
+            if math {
+                out.push_str(&marker(formulas.len()));
+                formulas.push(Formula {
+                    tex: String::new(),
+                    block: true,
+                });
+            } else {
+                out.push_str(line);
+            }
+            fence = Some((open, math));
+            continue;
+        }
+
+        if let Some(start) = extract_inline(line, &mut formulas, &mut out) {
+            block = Some(line[start + 2..].to_owned());
+        }
+    }
+
+    // Something that never closed wasn't math after all.
+    if let Some(tex) = block {
+        out.push_str("$$");
+        out.push_str(&tex);
+    }
+
+    (out, formulas)
+}
+
+/// Copies `line` to `out`, swapping its formulas for markers. Returns where a `$$` formula that
+/// carries on past the end of the line starts.
+fn extract_inline(line: &str, formulas: &mut Vec<Formula>, out: &mut String) -> Option<usize> {
+    let bytes = line.as_bytes();
+    let mut copied = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\\' => i += 2,
+            b'`' => {
+                let run = bytes[i..].iter().take_while(|&&b| b == b'`').count();
+                i = closing_backticks(bytes, i + run, run).unwrap_or(i + run);
+            }
+            b'$' if bytes.get(i + 1) == Some(&b'$') => {
+                out.push_str(&line[copied..i]);
+                let Some(len) = line[i + 2..].find("$$") else {
+                    return Some(i);
+                };
+
+                out.push_str(&marker(formulas.len()));
+                formulas.push(Formula {
+                    tex: line[i + 2..i + 2 + len].to_owned(),
+                    block: true,
+                });
+                i += len + 4;
+                copied = i;
+            }
+            b'$' => match closing_dollar(bytes, i) {
+                Some(end) => {
+                    out.push_str(&line[copied..i]);
+                    out.push_str(&marker(formulas.len()));
+                    formulas.push(Formula {
+                        tex: line[i + 1..end].to_owned(),
+                        block: false,
+                    });
+                    i = end + 1;
+                    copied = i;
+                }
+                None => i += 1,
+            },
+            _ => i += 1,
+        }
+    }
+
+    out.push_str(&line[copied..]);
+    None
+}
+
+/// Where the `$` closing the formula opened at `open` is. Like Pandoc, the formula can't start or
+/// end with a space and the closing `$` can't be followed by a digit, so prices stay prices.
+/// Formulas don't carry on into code spans either.
+fn closing_dollar(bytes: &[u8], open: usize) -> Option<usize> {
+    if bytes.get(open + 1).is_none_or(u8::is_ascii_whitespace) {
+        return None;
+    }
+
+    let mut i = open + 1;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\\' => i += 2,
+            b'`' => return None,
+            b'$' => {
+                if !bytes[i - 1].is_ascii_whitespace()
+                    && !bytes.get(i + 1).is_some_and(u8::is_ascii_digit)
+                {
+                    return Some(i);
+                }
+                i += 1;
+            }
+            _ => i += 1,
+        }
+    }
+
+    None
+}
+
+/// Where a code span opened by `run` backticks ends, just past its closing backticks.
+fn closing_backticks(bytes: &[u8], from: usize, run: usize) -> Option<usize> {
+    let mut i = from;
+    while i < bytes.len() {
+        if bytes[i] == b'`' {
+            let len = bytes[i..].iter().take_while(|&&b| b == b'`').count();
+            if len == run {
+                return Some(i + len);
+            }
+            i += len;
+        } else {
+            i += 1;
+        }
+    }
+
+    None
+}
+
+/// The fence and info string of a line opening a fenced code block.
+pub fn opening_fence(line: &str) -> Option<(String, &str)> {
+    let trimmed = line.trim_start_matches(' ');
+    if line.len() - trimmed.len() > 3 {
+        return None;
+    }
+
+    let c = trimmed.chars().next().filter(|&c| c == '`' || c == '~')?;
+    let len = trimmed.chars().take_while(|&ch| ch == c).count();
+    let info = trimmed[len..].trim();
+    if len < 3 || (c == '`' && info.contains('`')) {
+        return None;
+    }
+
+    Some((trimmed[..len].to_owned(), info))
+}
+
+/// Whether `line` closes a fenced code block opened with `open`.
+pub fn closing_fence(line: &str, open: &str) -> bool {
+    let trimmed = line.trim_start_matches(' ');
+    let c = open.as_bytes()[0] as char;
+    let len = trimmed.chars().take_while(|&ch| ch == c).count();
+
+    line.len() - trimmed.len() <= 3 && len >= open.len() && trimmed[len..].trim().is_empty()
+}
+
+/// Puts the formulas `extract` took out of a page back into its HTML as MathML.
+pub fn restore(html: &str, formulas: &[Formula]) -> String {
+    let mut html = html.to_owned();
+    for (i, formula) in formulas.iter().enumerate() {
+        let marker = marker(i);
+        let math = formula.to_html();
+        // Block formulas on their own line shouldn't be wrapped in a paragraph.
+        html = html.replace(&format!("<p>{}</p>", marker), &math);
+        html = html.replace(&marker, &math);
+    }
+
+    html
+}
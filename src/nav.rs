@@ -0,0 +1,259 @@
+use std::{
+    collections::{BTreeSet, HashSet},
+    sync::{Arc, Mutex},
+};
+
+use serde::Serialize;
+
+use crate::{
+    paths::{is_under, wiki_url},
+    search::escape_html,
+    state::Visibility,
+};
+
+/// A page as the navigation tree lists it.
+#[derive(Debug, Clone)]
+pub struct NavPage {
+    pub path: String,
+    pub title: String,
+    /// Kept so the tree can leave out pages a viewer can't read without loading them again.
+    pub visibility: Visibility,
+    pub public: bool,
+    /// The other pages it links to, for the link graph.
+    pub links: Vec<String>,
+}
+
+impl NavPage {
+    pub fn url(&self) -> String {
+        wiki_url(&self.path)
+    }
+}
+
+/// A folder of pages, as the navigation tree shows it.
+#[derive(Debug, Default)]
+pub struct NavFolder {
+    /// Where the folder is, empty for the root.
+    path: String,
+    name: String,
+    /// The folder's `index.md`, which its heading links to.
+    index: Option<NavPage>,
+    pages: Vec<NavPage>,
+    folders: Vec<NavFolder>,
+}
+
+impl NavFolder {
+    /// The tree of folders `pages` are in, which have to be sorted by `tree_order`.
+    pub fn build(pages: impl IntoIterator<Item = NavPage>) -> Self {
+        let mut root = NavFolder::default();
+        for page in pages {
+            let (dirs, name) = page.path.rsplit_once('/').unwrap_or(("", &page.path));
+            let is_index = name == "index.md";
+            let dirs: Vec<String> = dirs
+                .split('/')
+                .filter(|dir| !dir.is_empty())
+                .map(str::to_owned)
+                .collect();
+
+            let mut folder = &mut root;
+            for dir in dirs {
+                // Sorted pages come a folder at a time, so a folder is either the last one or new.
+                if folder.folders.last().is_none_or(|f| f.name != dir) {
+                    let path = match folder.path.is_empty() {
+                        true => dir.clone(),
+                        false => format!("{}/{}", folder.path, dir),
+                    };
+                    folder.folders.push(NavFolder {
+                        path,
+                        name: dir,
+                        ..Default::default()
+                    });
+                }
+                folder = folder.folders.last_mut().unwrap();
+            }
+
+            match is_index {
+                true => folder.index = Some(page),
+                false => folder.pages.push(page),
+            }
+        }
+
+        root
+    }
+
+    /// The tree as nested lists, with every folder closed but those leading to the page at
+    /// `current`. Empty if there are no pages at all.
+    pub fn to_html(&self, current: &str) -> String {
+        if self.index.is_none() && self.pages.is_empty() && self.folders.is_empty() {
+            return String::new();
+        }
+
+        let mut html = String::from("<ul class=\"nav-tree\">\n");
+        if let Some(index) = &self.index {
+            html.push_str(&format!("<li>{}</li>\n", page_link(index, current)));
+        }
+        self.push_items(&mut html, current);
+        html.push_str("</ul>\n");
+
+        html
+    }
+
+    /// The folder at `path`, if there are any pages in it.
+    pub fn find(&self, path: &str) -> Option<&NavFolder> {
+        let mut folder = self;
+        for dir in path.split('/').filter(|dir| !dir.is_empty()) {
+            folder = folder.folders.iter().find(|f| f.name == dir)?;
+        }
+
+        Some(folder)
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn url(&self) -> String {
+        wiki_url(&self.path)
+    }
+
+    pub fn index(&self) -> Option<&NavPage> {
+        self.index.as_ref()
+    }
+
+    /// The pages directly in the folder, besides its `index.md`.
+    pub fn pages(&self) -> &[NavPage] {
+        &self.pages
+    }
+
+    /// The folders directly inside this one.
+    pub fn folders(&self) -> &[NavFolder] {
+        &self.folders
+    }
+
+    fn push_items(&self, html: &mut String, current: &str) {
+        for page in &self.pages {
+            html.push_str(&format!("<li>{}</li>\n", page_link(page, current)));
+        }
+        for folder in &self.folders {
+            let open = match is_under(current, &folder.path) {
+                true => " open",
+                false => "",
+            };
+            let heading = match &folder.index {
+                Some(index) => page_link(index, current),
+                None => escape_html(&folder.name.replace('-', " ")),
+            };
+            html.push_str(&format!(
+                "<li><details{}><summary>{}</summary>\n<ul>\n",
+                open, heading
+            ));
+            folder.push_items(html, current);
+            html.push_str("</ul>\n</details></li>\n");
+        }
+    }
+}
+
+fn page_link(page: &NavPage, current: &str) -> String {
+    let aria = match page.path == current {
+        true => " aria-current=\"page\"",
+        false => "",
+    };
+
+    format!(
+        "<a href=\"{}\"{}>{}</a>",
+        escape_html(&wiki_url(&page.path)),
+        aria,
+        escape_html(&page.title)
+    )
+}
+
+/// Pages and the links between them, as `/graph` draws them.
+#[derive(Debug, Serialize)]
+pub struct LinkGraph {
+    pub nodes: Vec<PageTitle>,
+    pub edges: Vec<GraphEdge>,
+}
+
+/// A page by its title, for finding it without loading the whole navigation tree.
+#[derive(Debug, Serialize)]
+pub struct PageTitle {
+    pub path: String,
+    pub title: String,
+    pub url: String,
+}
+
+impl From<&NavPage> for PageTitle {
+    fn from(page: &NavPage) -> Self {
+        PageTitle {
+            path: page.path.clone(),
+            title: page.title.clone(),
+            url: page.url(),
+        }
+    }
+}
+
+/// A link from the page at `source` to the page at `target`.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub struct GraphEdge {
+    pub source: String,
+    pub target: String,
+}
+
+impl LinkGraph {
+    /// The graph of `pages`, leaving out links to pages that aren't among them.
+    pub fn build<'a>(pages: impl IntoIterator<Item = &'a NavPage>) -> Self {
+        let pages: Vec<&NavPage> = pages.into_iter().collect();
+        let paths: HashSet<&str> = pages.iter().map(|page| page.path.as_str()).collect();
+
+        let mut edges = BTreeSet::new();
+        for page in &pages {
+            for link in &page.links {
+                if link != &page.path && paths.contains(link.as_str()) {
+                    edges.insert(GraphEdge {
+                        source: page.path.clone(),
+                        target: link.clone(),
+                    });
+                }
+            }
+        }
+
+        LinkGraph {
+            nodes: pages.into_iter().map(PageTitle::from).collect(),
+            edges: edges.into_iter().collect(),
+        }
+    }
+}
+
+/// The pages the navigation tree is built from, kept until one of them changes.
+#[derive(Debug, Default)]
+pub struct NavCache {
+    /// How many times the pages have been forgotten, so a list that was being built while a page
+    /// changed isn't kept.
+    pages: Mutex<(u64, Option<Arc<Vec<NavPage>>>)>,
+}
+
+impl NavCache {
+    /// The kept pages, or the generation to give `set` once they've been listed again.
+    pub fn get(&self) -> Result<Arc<Vec<NavPage>>, u64> {
+        let pages = self.pages.lock().unwrap();
+        pages.1.clone().ok_or(pages.0)
+    }
+
+    /// Keeps `pages`, unless a page changed since `generation` was handed out by `get`.
+    pub fn set(&self, generation: u64, pages: Arc<Vec<NavPage>>) {
+        let mut kept = self.pages.lock().unwrap();
+        if kept.0 == generation {
+            kept.1 = Some(pages);
+        }
+    }
+
+    /// Forgets the pages, so the tree is built again the next time it's needed.
+    pub fn invalidate(&self) {
+        let mut kept = self.pages.lock().unwrap();
+        kept.0 += 1;
+        kept.1 = None;
+    }
+}
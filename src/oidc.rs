@@ -0,0 +1,149 @@
+use reqwest::{Client, Url};
+use serde::Deserialize;
+
+use crate::{
+    config,
+    error::{Error, Result},
+    state::Role,
+};
+
+/// Logs people in through an OpenID Connect provider using the authorization code flow.
+#[derive(Debug, Clone)]
+pub struct Oidc {
+    client: Client,
+    issuer: String,
+    client_id: String,
+    client_secret: String,
+    redirect_url: String,
+    /// The role given to people who log in this way without a matching account.
+    role: Role,
+}
+
+#[derive(Deserialize)]
+struct Discovery {
+    authorization_endpoint: String,
+    token_endpoint: String,
+    userinfo_endpoint: String,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+}
+
+/// Someone the provider logged in.
+#[derive(Debug, Clone)]
+pub struct Identity {
+    /// The issuer and the provider's subject, like `https://sso.example.com#248289761001`, which
+    /// stays the same for them whatever they change their username to.
+    pub subject: String,
+    /// Their username, email or subject, whichever the provider gave, for showing who they are.
+    pub name: String,
+}
+
+#[derive(Deserialize)]
+struct UserInfo {
+    sub: String,
+    preferred_username: Option<String>,
+    email: Option<String>,
+}
+
+impl Oidc {
+    /// Reads the provider's settings from the config, if an issuer is configured.
+    pub fn from_config() -> Option<Self> {
+        let issuer = config::var("knowbase_OIDC_ISSUER").ok()?;
+        let client_id =
+            config::var("knowbase_OIDC_CLIENT_ID").expect("knowbase_OIDC_CLIENT_ID should be set");
+        let client_secret = config::var("knowbase_OIDC_CLIENT_SECRET")
+            .expect("knowbase_OIDC_CLIENT_SECRET should be set");
+        let redirect_url = config::var("knowbase_OIDC_REDIRECT_URL")
+            .expect("knowbase_OIDC_REDIRECT_URL should be set");
+        let role = match config::var("knowbase_OIDC_ROLE").as_deref() {
+            Ok("reader") | Err(_) => Role::Reader,
+            Ok("editor") => Role::Editor,
+            Ok("admin") => Role::Admin,
+            Ok(other) => panic!(
+                "knowbase_OIDC_ROLE should be reader, editor or admin, not {}",
+                other
+            ),
+        };
+
+        Some(Self {
+            client: Client::new(),
+            issuer: issuer.trim_end_matches('/').to_owned(),
+            client_id,
+            client_secret,
+            redirect_url,
+            role,
+        })
+    }
+
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    /// Where to send someone to log in, `state` is handed back to the callback unchanged.
+    pub async fn authorize_url(&self, state: &str) -> Result<String> {
+        let discovery = self.discover().await?;
+        let url = Url::parse_with_params(
+            &discovery.authorization_endpoint,
+            [
+                ("response_type", "code"),
+                ("client_id", &self.client_id),
+                ("redirect_uri", &self.redirect_url),
+                ("scope", "openid profile email"),
+                ("state", state),
+            ],
+        )
+        .map_err(|e| Error::Oidc(e.to_string()))?;
+
+        Ok(url.into())
+    }
+
+    /// Exchanges the code the provider sent to the callback for who it belongs to.
+    pub async fn identity(&self, code: &str) -> Result<Identity> {
+        let discovery = self.discover().await?;
+        let token: TokenResponse = self
+            .client
+            .post(&discovery.token_endpoint)
+            .form(&[
+                ("grant_type", "authorization_code"),
+                ("code", code),
+                ("redirect_uri", &self.redirect_url),
+                ("client_id", &self.client_id),
+                ("client_secret", &self.client_secret),
+            ])
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        // The user info comes straight from the provider over TLS, so the ID token isn't needed.
+        let info: UserInfo = self
+            .client
+            .get(&discovery.userinfo_endpoint)
+            .bearer_auth(token.access_token)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        Ok(Identity {
+            subject: format!("{}#{}", self.issuer, info.sub),
+            name: info.preferred_username.or(info.email).unwrap_or(info.sub),
+        })
+    }
+
+    async fn discover(&self) -> Result<Discovery> {
+        Ok(self
+            .client
+            .get(format!("{}/.well-known/openid-configuration", self.issuer))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?)
+    }
+}
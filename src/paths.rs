@@ -0,0 +1,276 @@
+use std::cmp::Ordering;
+
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, CONTROLS};
+use unicode_normalization::UnicodeNormalization;
+
+/// Characters that can't appear unescaped in the path of a URL.
+const PATH_ENCODE_SET: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'`')
+    .add(b'{')
+    .add(b'}');
+
+/// The form every page path is stored and served under: lowercase, `/` separated,
+/// with no leading, trailing, empty or `.` segments and `..` resolved.
+pub fn canonical_path(path: &str) -> String {
+    let path = path.replace('\\', "/").to_lowercase();
+    let mut segments = Vec::new();
+    for segment in path.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop();
+            }
+            segment => segments.push(segment),
+        }
+    }
+
+    segments.join("/")
+}
+
+/// `path` with what makes for awkward URLs taken out of each segment. Unicode is composed (NFC)
+/// so a name typed on one system matches the same name from another, punctuation other than `-`,
+/// `_` and `.` is dropped, and with `dashes` spaces become `-`. Like `Meeting Notes (2024).md`
+/// becoming `Meeting-Notes-2024.md`.
+pub fn slug_path(path: &str, dashes: bool) -> String {
+    path.split('/')
+        .map(|segment| slug_segment(segment, dashes))
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn slug_segment(segment: &str, dashes: bool) -> String {
+    let segment: String = segment.nfc().collect();
+    let (stem, extension) = match segment.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => (stem, Some(extension)),
+        _ => (segment.as_str(), None),
+    };
+
+    let mut slug = String::with_capacity(stem.len());
+    for c in stem.chars() {
+        let c = match c {
+            c if c.is_whitespace() && dashes => '-',
+            c if c.is_whitespace() => ' ',
+            c if c.is_alphanumeric() || matches!(c, '-' | '_' | '.') => c,
+            _ => continue,
+        };
+        // The gaps left by dropped punctuation shouldn't leave runs of separators.
+        if matches!(c, ' ' | '-') && slug.ends_with(c) {
+            continue;
+        }
+        slug.push(c);
+    }
+    let slug = slug.trim_matches([' ', '-']);
+
+    // A name that's nothing but punctuation is left alone rather than lost.
+    if slug.is_empty() {
+        return segment;
+    }
+    match extension {
+        Some(extension) => format!("{}.{}", slug, extension),
+        None => slug.to_owned(),
+    }
+}
+
+/// Why a canonical path can't be used for a new page, if it can't.
+pub fn page_path_error(path: &str) -> Option<&'static str> {
+    if path.is_empty() {
+        Some("A page needs a path")
+    } else if !path.ends_with(".md") {
+        Some("Page paths must end in .md")
+    } else if path.split('/').any(|s| s.starts_with('.')) {
+        Some("Page paths can't have hidden files or folders")
+    } else if path.chars().any(char::is_control) {
+        Some("Page paths can't contain control characters")
+    } else {
+        None
+    }
+}
+
+/// The URL a page is served from.
+pub fn wiki_url(path: &str) -> String {
+    if path.is_empty() {
+        "/w".to_owned()
+    } else {
+        format!("/w/{}", utf8_percent_encode(path, PATH_ENCODE_SET))
+    }
+}
+
+/// Why `path` can't be used for an attachment, if it can't.
+pub fn attachment_path_error(path: &str) -> Option<&'static str> {
+    let name = path.rsplit('/').next().unwrap_or_default();
+    if name.is_empty() {
+        Some("An attachment needs a file name")
+    } else if name.ends_with(".md") {
+        Some("Markdown files should be added as pages")
+    } else if !name.contains('.') {
+        Some("Attachment names need a file extension")
+    } else if path.split('/').any(|s| s.starts_with('.')) {
+        Some("Attachment paths can't have hidden files or folders")
+    } else if path.chars().any(char::is_control) {
+        Some("Attachment paths can't contain control characters")
+    } else {
+        None
+    }
+}
+
+/// Markdown that embeds the attachment at `path` if it's an image and links to it otherwise.
+pub fn attachment_markdown(path: &str) -> String {
+    let name = path.rsplit('/').next().unwrap_or_default();
+    let label = name.replace('[', "\\[").replace(']', "\\]");
+    let embed = if content_type(path).starts_with("image/") {
+        "!"
+    } else {
+        ""
+    };
+
+    format!("{}[{}]({})", embed, label, attachment_url(path))
+}
+
+/// The URL an attachment is served from.
+pub fn attachment_url(path: &str) -> String {
+    format!("/a/{}", utf8_percent_encode(path, PATH_ENCODE_SET))
+}
+
+/// Points a link from the page at `from` to a file that isn't a page at where its attachment
+/// is served, if that's what it links to. Root relative links are resolved from the root and
+/// relative ones from the page's directory.
+pub fn attachment_link(url: &str, from: &str) -> Option<String> {
+    let split = url.find(['?', '#']).unwrap_or(url.len());
+    let (target, suffix) = url.split_at(split);
+    // Links with a scheme or host go somewhere else entirely.
+    if target.starts_with("//") || target.split('/').next().is_some_and(|s| s.contains(':')) {
+        return None;
+    }
+
+    let name = target.rsplit('/').next().unwrap_or_default();
+    if !name.contains('.') || name.to_lowercase().ends_with(".md") {
+        return None;
+    }
+
+    let path = resolve_path(&percent_decode_str(target).decode_utf8_lossy(), from);
+    Some(format!("{}{}", attachment_url(&path), suffix))
+}
+
+/// The canonical path `target` refers to from the page at `from`, root relative targets are
+/// resolved from the root and relative ones from the page's directory.
+pub fn resolve_path(target: &str, from: &str) -> String {
+    match target.strip_prefix('/') {
+        Some(target) => canonical_path(target),
+        None => {
+            let dir = from.rsplit_once('/').map_or("", |(dir, _)| dir);
+            canonical_path(&format!("{}/{}", dir, target))
+        }
+    }
+}
+
+/// The content type to serve an attachment with, from its extension.
+pub fn content_type(path: &str) -> &'static str {
+    let extension = path.rsplit_once('.').map_or("", |(_, e)| e);
+    match extension {
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "svg" => "image/svg+xml",
+        "ico" => "image/x-icon",
+        "pdf" => "application/pdf",
+        "txt" | "log" => "text/plain; charset=utf-8",
+        "csv" => "text/csv; charset=utf-8",
+        "json" => "application/json",
+        "mp3" => "audio/mpeg",
+        "mp4" => "video/mp4",
+        "webm" => "video/webm",
+        "zip" => "application/zip",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Points a root relative link from a page at the canonical URL of the page it links to,
+/// keeping any query or fragment.
+pub fn wiki_link(url: &str) -> String {
+    let split = url.find(['?', '#']).unwrap_or(url.len());
+    let (path, suffix) = url.split_at(split);
+    let path = percent_decode_str(path).decode_utf8_lossy();
+
+    format!("{}{}", wiki_url(&canonical_path(&path)), suffix)
+}
+
+/// Orders page paths the way they sit in their folders: a folder's `index.md` first, then the other
+/// pages in it, then the folders inside it.
+pub fn tree_order(a: &str, b: &str) -> Ordering {
+    tree_key(a).cmp(&tree_key(b))
+}
+
+fn tree_key(path: &str) -> Vec<(u8, &str)> {
+    let (dirs, name) = path.rsplit_once('/').unwrap_or(("", path));
+    let mut key: Vec<_> = dirs
+        .split('/')
+        .filter(|dir| !dir.is_empty())
+        .map(|dir| (2, dir))
+        .collect();
+    key.push(match name {
+        "index.md" => (0, ""),
+        name => (1, name),
+    });
+
+    key
+}
+
+/// Whether `path` is `prefix` itself or a page somewhere under it, an empty prefix covers everything.
+pub fn is_under(path: &str, prefix: &str) -> bool {
+    prefix.is_empty()
+        || path == prefix
+        || path
+            .strip_prefix(prefix)
+            .is_some_and(|rest| rest.starts_with('/'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn canonical_paths_are_lowercase() {
+        assert_eq!(canonical_path("Notes/Meeting.MD"), "notes/meeting.md");
+    }
+
+    #[test]
+    fn canonical_paths_drop_leading_and_trailing_slashes() {
+        assert_eq!(canonical_path("/notes/meeting.md/"), "notes/meeting.md");
+        assert_eq!(canonical_path("notes//meeting.md"), "notes/meeting.md");
+        assert_eq!(canonical_path("/"), "");
+    }
+
+    #[test]
+    fn canonical_paths_use_forward_slashes() {
+        assert_eq!(canonical_path(r"notes\meeting.md"), "notes/meeting.md");
+    }
+
+    #[test]
+    fn canonical_paths_resolve_dot_segments() {
+        assert_eq!(canonical_path("notes/./meeting.md"), "notes/meeting.md");
+        assert_eq!(
+            canonical_path("notes/drafts/../meeting.md"),
+            "notes/meeting.md"
+        );
+    }
+
+    #[test]
+    fn links_to_pages_are_not_attachments_whatever_their_case() {
+        assert_eq!(attachment_link("Guides/Setup.MD", "index.md"), None);
+        assert!(attachment_link("Guides/Diagram.PNG", "index.md").is_some());
+    }
+
+    #[test]
+    fn canonical_paths_stay_inside_the_wiki() {
+        assert_eq!(canonical_path("../../etc/passwd"), "etc/passwd");
+        assert_eq!(canonical_path(r"..\..\secret.md"), "secret.md");
+    }
+}
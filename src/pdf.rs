@@ -0,0 +1,78 @@
+use std::process::Stdio;
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use lazy_static::lazy_static;
+use percent_encoding::percent_decode_str;
+use regex::{Captures, Regex};
+use tokio::{io::AsyncWriteExt, process::Command};
+
+use crate::{
+    config,
+    error::{Error, Result},
+    paths::content_type,
+    state::State,
+};
+
+lazy_static! {
+    static ref ATTACHMENT_SRC_RE: Regex = Regex::new(r#"src="/a/([^"]+)""#).unwrap();
+}
+
+/// Turns a standalone HTML document into a PDF with `knowbase_PDF_COMMAND`, which is given the
+/// HTML on stdin and has to write the PDF to stdout, like the default `wkhtmltopdf --quiet - -`.
+pub async fn html_to_pdf(state: &State, html: &str) -> Result<Vec<u8>> {
+    let html = embed_attachments(state, html).await?;
+    let command = config::var("knowbase_PDF_COMMAND")
+        .unwrap_or_else(|_| "wkhtmltopdf --quiet - -".to_owned());
+    let mut args = command.split_whitespace();
+    let program = args.next().unwrap_or("wkhtmltopdf");
+
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| Error::Pdf(format!("couldn't run {}: {}", program, e)))?;
+    let mut stdin = child.stdin.take().unwrap();
+    let (written, output) = tokio::join!(
+        async move { stdin.write_all(html.as_bytes()).await },
+        child.wait_with_output()
+    );
+    let output = output.map_err(|e| Error::Pdf(e.to_string()))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(Error::Pdf(format!("{} failed: {}", program, stderr.trim())));
+    }
+    written.map_err(|e| Error::Pdf(e.to_string()))?;
+
+    Ok(output.stdout)
+}
+
+/// Points images at attachments somewhere the PDF command can load them from without logging in:
+/// a link to the bucket they're kept in, or the image itself inlined as a data URL.
+async fn embed_attachments(state: &State, html: &str) -> Result<String> {
+    let mut sources = Vec::new();
+    for src in ATTACHMENT_SRC_RE.captures_iter(html) {
+        let path = percent_decode_str(&src[1]).decode_utf8_lossy().into_owned();
+        let embedded = match state.attachment_url(&path) {
+            Some(url) => Some(url),
+            None => state.attachment(&path).await?.map(|data| {
+                format!(
+                    "data:{};base64,{}",
+                    content_type(&path),
+                    STANDARD.encode(data)
+                )
+            }),
+        };
+        sources.push(embedded);
+    }
+
+    let mut sources = sources.into_iter();
+    let html =
+        ATTACHMENT_SRC_RE.replace_all(html, |src: &Captures| match sources.next().flatten() {
+            Some(embedded) => format!("src=\"{}\"", embedded.replace('&', "&amp;")),
+            None => src[0].to_owned(),
+        });
+
+    Ok(html.into_owned())
+}
@@ -0,0 +1,160 @@
+use hmac::{Hmac, Mac};
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use reqwest::{header::CONTENT_TYPE, Client};
+use sha2::{Digest, Sha256};
+use time::{macros::format_description, OffsetDateTime};
+
+use crate::{
+    config,
+    error::{Error, Result},
+};
+
+/// Everything but `A-Za-z0-9-_.~` is percent encoded when requests are signed.
+const SIGV4_ENCODE_SET: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.')
+    .remove(b'~');
+
+/// How long a presigned link to an attachment keeps working, in seconds.
+const PRESIGN_TTL: u64 = 300;
+
+/// An S3 compatible bucket attachments are kept in instead of the store, so big files don't
+/// end up in Redis.
+#[derive(Debug, Clone)]
+pub struct Bucket {
+    client: Client,
+    /// Like `https://s3.eu-west-1.amazonaws.com`, the bucket is addressed by path so any
+    /// provider works.
+    endpoint: String,
+    /// The `host[:port]` part of the endpoint, which every request is signed with.
+    host: String,
+    bucket: String,
+    region: String,
+    access_key: String,
+    secret_key: String,
+    /// Put in front of every object key, so a bucket can be shared.
+    prefix: String,
+}
+
+impl Bucket {
+    /// Reads the bucket's settings from the config, if a bucket is configured.
+    pub fn from_config() -> Option<Self> {
+        let bucket = config::var("knowbase_S3_BUCKET").ok()?;
+        let endpoint = config::var("knowbase_S3_ENDPOINT")
+            .expect("knowbase_S3_ENDPOINT should be set")
+            .trim_end_matches('/')
+            .to_owned();
+        let host = endpoint
+            .split_once("://")
+            .map_or(endpoint.as_str(), |(_, host)| host)
+            .to_owned();
+        let region = config::var("knowbase_S3_REGION").unwrap_or_else(|_| "us-east-1".to_owned());
+        let access_key =
+            config::var("knowbase_S3_ACCESS_KEY").expect("knowbase_S3_ACCESS_KEY should be set");
+        let secret_key =
+            config::var("knowbase_S3_SECRET_KEY").expect("knowbase_S3_SECRET_KEY should be set");
+        let prefix = config::var("knowbase_S3_PREFIX").unwrap_or_default();
+
+        Some(Self {
+            client: Client::new(),
+            endpoint,
+            host,
+            bucket,
+            region,
+            access_key,
+            secret_key,
+            prefix,
+        })
+    }
+
+    /// Uploads the attachment at `path`, replacing any that's already there.
+    pub async fn put(&self, path: &str, data: Vec<u8>, content_type: &str) -> Result<()> {
+        self.client
+            .put(self.presign("PUT", path))
+            .header(CONTENT_TYPE, content_type)
+            .body(data)
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            // The URL carries a working signature, so it's left out of the error.
+            .map_err(|e| Error::ObjectStorage(e.without_url().to_string()))?;
+
+        Ok(())
+    }
+
+    /// A link the attachment at `path` can be downloaded from for the next few minutes.
+    pub fn get_url(&self, path: &str) -> String {
+        self.presign("GET", path)
+    }
+
+    /// Signs a request for the object at `path` into its query string, following AWS
+    /// Signature Version 4.
+    fn presign(&self, method: &str, path: &str) -> String {
+        let now = OffsetDateTime::now_utc();
+        let date = now
+            .format(format_description!("[year][month][day]"))
+            .unwrap();
+        let timestamp = now
+            .format(format_description!(
+                "[year][month][day]T[hour][minute][second]Z"
+            ))
+            .unwrap();
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+
+        let key = format!("{}{}", self.prefix, path);
+        let uri = format!(
+            "/{}/{}",
+            encode(&self.bucket),
+            key.split('/').map(encode).collect::<Vec<_>>().join("/")
+        );
+        // Already in the sorted order the signature needs.
+        let query = [
+            ("X-Amz-Algorithm", "AWS4-HMAC-SHA256".to_owned()),
+            ("X-Amz-Credential", format!("{}/{}", self.access_key, scope)),
+            ("X-Amz-Date", timestamp.clone()),
+            ("X-Amz-Expires", PRESIGN_TTL.to_string()),
+            ("X-Amz-SignedHeaders", "host".to_owned()),
+        ]
+        .iter()
+        .map(|(k, v)| format!("{}={}", k, encode(v)))
+        .collect::<Vec<_>>()
+        .join("&");
+
+        let request = format!(
+            "{}\n{}\n{}\nhost:{}\n\nhost\nUNSIGNED-PAYLOAD",
+            method, uri, query, self.host
+        );
+        let to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            timestamp,
+            scope,
+            hex(&Sha256::digest(request.as_bytes()))
+        );
+
+        let signing_key = [self.region.as_str(), "s3", "aws4_request"].iter().fold(
+            hmac(format!("AWS4{}", self.secret_key).as_bytes(), &date),
+            |key, part| hmac(&key, part),
+        );
+        let signature = hex(&hmac(&signing_key, &to_sign));
+
+        format!(
+            "{}{}?{}&X-Amz-Signature={}",
+            self.endpoint, uri, query, signature
+        )
+    }
+}
+
+fn encode(s: &str) -> String {
+    utf8_percent_encode(s, SIGV4_ENCODE_SET).to_string()
+}
+
+fn hmac(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
@@ -0,0 +1,122 @@
+mod query;
+mod redisearch;
+mod store;
+#[cfg(feature = "tantivy")]
+mod tantivy;
+
+use std::{fmt::Debug, ops::Range};
+
+use async_trait::async_trait;
+
+use crate::error::Result;
+
+#[cfg(feature = "tantivy")]
+pub use self::tantivy::TantivyIndex;
+pub use self::{query::Query, redisearch::RediSearchIndex, store::StoreIndex};
+
+/// Somewhere to look up which pages mention the words being searched for.
+///
+/// Page titles are matched against paths by the `Store` itself, indexes only deal with the body.
+#[async_trait]
+pub trait SearchIndex: Debug + Send + Sync {
+    /// Indexes the markdown of the page at `path`, replacing whatever was indexed for it before.
+    async fn index_page(&self, path: &str, md: &str) -> Result<()>;
+
+    async fn remove_page(&self, path: &str) -> Result<()>;
+
+    /// Whether nothing has been indexed yet, so the index can be built from the stored pages.
+    async fn is_empty(&self) -> Result<bool>;
+
+    /// The paths of pages matching `query` with how well they matched, higher is better.
+    async fn search(&self, query: &str) -> Result<Vec<(String, f32)>>;
+}
+
+/// Splits text into the lowercase words it's indexed and searched by.
+pub fn tokenize(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty() && word.len() <= MAX_WORD_BYTES)
+        .map(str::to_lowercase)
+}
+
+/// Longer "words" are almost always encoded data rather than anything someone would search for.
+const MAX_WORD_BYTES: usize = 64;
+
+/// How much of a page search results show.
+const SNIPPET_BYTES: usize = 300;
+/// How much of the page before the first match is shown for context.
+const CONTEXT_BYTES: usize = 80;
+
+/// HTML for the part of `text` around the first word matching any of `terms`, with every
+/// matching word in a `<mark>`. Falls back to the start of the text if nothing matches.
+pub fn snippet(text: &str, terms: &[String]) -> String {
+    let words = word_ranges(text);
+    let matches = |range: &Range<usize>| {
+        let word = text[range.clone()].to_lowercase();
+        terms.iter().any(|term| word.starts_with(term.as_str()))
+    };
+
+    let first = words.iter().find(|r| matches(r)).map_or(0, |r| r.start);
+    let start = words
+        .iter()
+        .map(|r| r.start)
+        .find(|&start| start >= first.saturating_sub(CONTEXT_BYTES))
+        .unwrap_or(first);
+    let mut end = (start + SNIPPET_BYTES).min(text.len());
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    // Stop after the last whole word rather than partway through one.
+    if end < text.len() {
+        end = words
+            .iter()
+            .rev()
+            .find(|r| r.start >= start && r.end <= end)
+            .map_or(end, |r| r.end);
+    }
+
+    let mut html = String::new();
+    if words.first().is_some_and(|r| r.start < start) {
+        html.push('…');
+    }
+    let mut shown = start;
+    for word in words.iter().filter(|r| r.start >= start && r.end <= end) {
+        if matches(word) {
+            html.push_str(&escape_html(&text[shown..word.start]));
+            html.push_str("<mark>");
+            html.push_str(&escape_html(&text[word.clone()]));
+            html.push_str("</mark>");
+            shown = word.end;
+        }
+    }
+    html.push_str(&escape_html(&text[shown..end]));
+    if end < text.len() {
+        html.push('…');
+    }
+
+    html
+}
+
+/// Where each of the words `tokenize` would find are in `text`.
+fn word_ranges(text: &str) -> Vec<Range<usize>> {
+    let mut ranges = Vec::new();
+    let mut start = None;
+    for (i, c) in text.char_indices() {
+        if c.is_alphanumeric() {
+            start.get_or_insert(i);
+        } else if let Some(start) = start.take() {
+            ranges.push(start..i);
+        }
+    }
+    if let Some(start) = start {
+        ranges.push(start..text.len());
+    }
+
+    ranges
+}
+
+pub fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
@@ -0,0 +1,125 @@
+use crate::paths::{canonical_path, is_under};
+
+use super::tokenize;
+
+/// A search broken into what pages have to match, parsed from something like
+/// `deploy "release notes" -staging tag:ops path:projects/alpha/`.
+#[derive(Debug, Default, Clone)]
+pub struct Query {
+    /// Words that have to appear somewhere in the page.
+    pub terms: Vec<String>,
+    /// Runs of words that have to appear together and in order.
+    pub phrases: Vec<Vec<String>>,
+    /// Words or phrases the page can't contain.
+    pub excluded: Vec<Vec<String>>,
+    /// Tags the page has to carry.
+    pub tags: Vec<String>,
+    /// Directories the page has to be under.
+    pub paths: Vec<String>,
+}
+
+impl Query {
+    pub fn parse(search: &str) -> Self {
+        let mut query = Self::default();
+        let mut rest = search.trim_start();
+        while !rest.is_empty() {
+            let excluded = rest.starts_with('-');
+            if excluded {
+                rest = &rest[1..];
+            }
+
+            let (token, quoted) = match rest.strip_prefix('"') {
+                Some(quoted) => {
+                    // An unclosed quote runs to the end of the search.
+                    let end = quoted.find('"').unwrap_or(quoted.len());
+                    rest = quoted.get(end + 1..).unwrap_or("");
+                    (&quoted[..end], true)
+                }
+                None => {
+                    let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+                    let token = &rest[..end];
+                    rest = &rest[end..];
+                    (token, false)
+                }
+            };
+            rest = rest.trim_start();
+
+            if !quoted && !excluded {
+                if let Some(tag) = token.strip_prefix("tag:") {
+                    let tag = tag.trim().to_lowercase();
+                    if !tag.is_empty() {
+                        query.tags.push(tag);
+                    }
+                    continue;
+                }
+                if let Some(path) = token.strip_prefix("path:") {
+                    query.paths.push(canonical_path(path));
+                    continue;
+                }
+            }
+
+            let words: Vec<String> = tokenize(token).collect();
+            if words.is_empty() {
+                continue;
+            }
+            if excluded {
+                query.excluded.push(words);
+            } else if quoted && words.len() > 1 {
+                query.phrases.push(words);
+            } else {
+                query.terms.extend(words);
+            }
+        }
+
+        query
+    }
+
+    /// Whether there's nothing to search for or narrow results down by.
+    pub fn is_empty(&self) -> bool {
+        self.text().is_empty() && self.tags.is_empty() && self.paths.is_empty()
+    }
+
+    /// Every word a page has to contain, for matching titles and highlighting snippets.
+    pub fn text(&self) -> String {
+        self.terms
+            .iter()
+            .chain(self.phrases.iter().flatten())
+            .cloned()
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    /// What's handed to the search index, phrases stay quoted for the indexes that understand
+    /// them and the rest are checked by `matches_text`.
+    pub fn index_query(&self) -> String {
+        self.terms
+            .iter()
+            .cloned()
+            .chain(self.phrases.iter().map(|p| format!("\"{}\"", p.join(" "))))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    /// Whether the page at `path` carrying `tags` passes the `tag:` and `path:` filters.
+    pub fn matches_page(&self, path: &str, tags: &[String]) -> bool {
+        self.tags.iter().all(|tag| tags.contains(tag))
+            && self.paths.iter().all(|prefix| is_under(path, prefix))
+    }
+
+    /// Whether checking a page needs its text, because of phrases or exclusions.
+    pub fn needs_text(&self) -> bool {
+        !self.phrases.is_empty() || !self.excluded.is_empty()
+    }
+
+    /// Whether `text` has every phrase and none of the excluded words.
+    pub fn matches_text(&self, text: &str) -> bool {
+        let words: Vec<String> = tokenize(text).collect();
+        let contains = |phrase: &Vec<String>| {
+            words
+                .windows(phrase.len())
+                .any(|window| window == phrase.as_slice())
+        };
+
+        self.phrases.iter().all(contains) && !self.excluded.iter().any(contains)
+    }
+}
@@ -0,0 +1,171 @@
+use async_trait::async_trait;
+use redis::{FromRedisValue, Value};
+use tokio::sync::OnceCell;
+
+use crate::{
+    error::{Error, Result},
+    store::RedisStore,
+};
+
+use super::SearchIndex;
+
+const INDEX_NAME: &str = "knowbase_search";
+/// Prefix of the hashes RediSearch indexes, one for each page.
+const DOC_KEY: &str = "search_doc";
+/// The most results a single search returns.
+const MAX_RESULTS: usize = 200;
+
+/// Indexes pages with the RediSearch module of Redis Stack.
+#[derive(Debug)]
+pub struct RediSearchIndex {
+    redis: RedisStore,
+    created: OnceCell<()>,
+}
+
+impl RediSearchIndex {
+    pub fn from_config() -> Self {
+        Self {
+            redis: RedisStore::from_config(),
+            created: OnceCell::new(),
+        }
+    }
+
+    /// Creates the index the first time it's used, unless an earlier run already did.
+    async fn create(&self) -> Result<()> {
+        self.created
+            .get_or_try_init(|| async {
+                let mut con = self.redis.con().await?;
+                let created = self
+                    .redis
+                    .timed(
+                        redis::cmd("FT.CREATE")
+                            .arg(INDEX_NAME)
+                            .arg("ON")
+                            .arg("HASH")
+                            .arg("PREFIX")
+                            .arg(1)
+                            .arg(format!("{}:", DOC_KEY))
+                            .arg("SCHEMA")
+                            .arg("body")
+                            .arg("TEXT")
+                            .query_async::<_, ()>(&mut con),
+                    )
+                    .await;
+
+                match created {
+                    Err(Error::Redis(e)) if e.to_string().contains("Index already exists") => {
+                        Ok(())
+                    }
+                    created => created,
+                }
+            })
+            .await?;
+
+        Ok(())
+    }
+}
+
+fn doc_key(path: &str) -> String {
+    format!("{}:{}", DOC_KEY, path)
+}
+
+/// Keeps only words and quoted phrases, so punctuation can't be read as query syntax.
+fn sanitize(query: &str) -> String {
+    let mut query: String = query
+        .chars()
+        .map(|c| match c {
+            c if c.is_alphanumeric() || c.is_whitespace() || c == '"' => c,
+            _ => ' ',
+        })
+        .collect();
+    if query.matches('"').count() % 2 == 1 {
+        query.retain(|c| c != '"');
+    }
+
+    query
+}
+
+#[async_trait]
+impl SearchIndex for RediSearchIndex {
+    async fn index_page(&self, path: &str, md: &str) -> Result<()> {
+        self.create().await?;
+        let mut con = self.redis.con().await?;
+        self.redis
+            .timed(
+                redis::cmd("HSET")
+                    .arg(doc_key(path))
+                    .arg("body")
+                    .arg(md)
+                    .query_async::<_, ()>(&mut con),
+            )
+            .await
+    }
+
+    async fn remove_page(&self, path: &str) -> Result<()> {
+        let mut con = self.redis.con().await?;
+        self.redis
+            .timed(
+                redis::cmd("DEL")
+                    .arg(doc_key(path))
+                    .query_async::<_, ()>(&mut con),
+            )
+            .await
+    }
+
+    async fn is_empty(&self) -> Result<bool> {
+        self.create().await?;
+        let mut con = self.redis.con().await?;
+        let info: Vec<Value> = self
+            .redis
+            .timed(redis::cmd("FT.INFO").arg(INDEX_NAME).query_async(&mut con))
+            .await?;
+
+        // The reply alternates between the name of each field and its value.
+        let docs = info
+            .chunks(2)
+            .find(|field| String::from_redis_value(&field[0]).is_ok_and(|name| name == "num_docs"))
+            .and_then(|field| field.get(1))
+            .and_then(|docs| u64::from_redis_value(docs).ok());
+
+        Ok(docs.unwrap_or(0) == 0)
+    }
+
+    async fn search(&self, query: &str) -> Result<Vec<(String, f32)>> {
+        let query = sanitize(query);
+        if !query.chars().any(char::is_alphanumeric) {
+            return Ok(Vec::new());
+        }
+
+        self.create().await?;
+        let mut con = self.redis.con().await?;
+        let reply: Vec<Value> = self
+            .redis
+            .timed(
+                redis::cmd("FT.SEARCH")
+                    .arg(INDEX_NAME)
+                    .arg(query)
+                    .arg("NOCONTENT")
+                    .arg("WITHSCORES")
+                    .arg("LIMIT")
+                    .arg(0)
+                    .arg(MAX_RESULTS)
+                    .query_async(&mut con),
+            )
+            .await?;
+
+        // The total number of matches comes first, then each key is followed by its score.
+        Ok(reply
+            .get(1..)
+            .unwrap_or_default()
+            .chunks(2)
+            .filter_map(|hit| {
+                let key = String::from_redis_value(&hit[0]).ok()?;
+                let score = String::from_redis_value(hit.get(1)?).ok()?.parse().ok()?;
+                Some((
+                    key.strip_prefix(&format!("{}:", DOC_KEY))?.to_owned(),
+                    score,
+                ))
+            })
+            .collect())
+    }
+}
@@ -0,0 +1,92 @@
+use std::{collections::HashMap, sync::Arc};
+
+use async_trait::async_trait;
+
+use crate::{error::Result, store::Store};
+
+use super::{tokenize, SearchIndex};
+
+const INDEX_KEY: &str = "search";
+
+/// Keeps how often each word appears in a page next to everything else in the wiki's storage.
+///
+/// Searching reads every indexed page, which is fine for a few thousand pages.
+#[derive(Debug)]
+pub struct StoreIndex {
+    store: Arc<dyn Store>,
+}
+
+impl StoreIndex {
+    pub fn new(store: Arc<dyn Store>) -> Self {
+        Self { store }
+    }
+}
+
+fn index_key(path: &str) -> String {
+    format!("{}:{}", INDEX_KEY, path)
+}
+
+#[async_trait]
+impl SearchIndex for StoreIndex {
+    async fn index_page(&self, path: &str, md: &str) -> Result<()> {
+        let mut counts: HashMap<String, u32> = HashMap::new();
+        for word in tokenize(md) {
+            *counts.entry(word).or_default() += 1;
+        }
+
+        self.store
+            .set(&index_key(path), &serde_json::to_vec(&counts).unwrap())
+            .await
+    }
+
+    async fn remove_page(&self, path: &str) -> Result<()> {
+        self.store.delete(&index_key(path)).await
+    }
+
+    async fn is_empty(&self) -> Result<bool> {
+        Ok(self
+            .store
+            .keys(&format!("{}:", INDEX_KEY))
+            .await?
+            .is_empty())
+    }
+
+    async fn search(&self, query: &str) -> Result<Vec<(String, f32)>> {
+        let terms: Vec<String> = tokenize(query).collect();
+        if terms.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let mut results = Vec::new();
+        for key in self.store.keys(&format!("{}:", INDEX_KEY)).await? {
+            let Some(counts) = self.store.get(&key).await? else {
+                continue;
+            };
+            let counts: HashMap<String, u32> = serde_json::from_slice(&counts).unwrap_or_default();
+
+            // Every term has to appear, words merely starting with a term count for half.
+            let mut score = 0.0;
+            for term in &terms {
+                let hits: f32 = counts
+                    .iter()
+                    .map(|(word, &count)| match word {
+                        word if word == term => count as f32,
+                        word if word.starts_with(term.as_str()) => count as f32 / 2.0,
+                        _ => 0.0,
+                    })
+                    .sum();
+                if hits == 0.0 {
+                    score = 0.0;
+                    break;
+                }
+                score += hits.ln_1p();
+            }
+
+            if score > 0.0 {
+                results.push((key[INDEX_KEY.len() + 1..].to_owned(), score));
+            }
+        }
+
+        Ok(results)
+    }
+}
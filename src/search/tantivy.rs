@@ -0,0 +1,126 @@
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use tantivy::{
+    collector::TopDocs,
+    directory::MmapDirectory,
+    doc,
+    query::QueryParser,
+    schema::{Field, Schema, STORED, STRING, TEXT},
+    Index, IndexReader, IndexWriter, ReloadPolicy, Term,
+};
+
+use crate::{
+    config,
+    error::{Error, Result},
+};
+
+use super::SearchIndex;
+
+/// How much memory the writer may buffer documents in before flushing them to disk.
+const WRITER_BYTES: usize = 50 * 1024 * 1024;
+/// The most results a single search returns.
+const MAX_RESULTS: usize = 200;
+
+/// A Tantivy index in its own directory, giving BM25 ranking and phrase queries.
+#[derive(Clone)]
+pub struct TantivyIndex {
+    index: Index,
+    reader: IndexReader,
+    writer: Arc<Mutex<IndexWriter>>,
+    path: Field,
+    body: Field,
+}
+
+impl std::fmt::Debug for TantivyIndex {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TantivyIndex").finish_non_exhaustive()
+    }
+}
+
+impl TantivyIndex {
+    pub fn from_config() -> Self {
+        let dir = config::var("knowbase_TANTIVY_DIR").unwrap_or("knowbase-index".to_owned());
+        std::fs::create_dir_all(&dir).expect("knowbase_TANTIVY_DIR should be a writable directory");
+
+        let mut schema = Schema::builder();
+        let path = schema.add_text_field("path", STRING | STORED);
+        let body = schema.add_text_field("body", TEXT);
+
+        let index = Index::open_or_create(
+            MmapDirectory::open(&dir).expect("knowbase_TANTIVY_DIR should be openable"),
+            schema.build(),
+        )
+        .expect("knowbase_TANTIVY_DIR should hold a knowbase search index");
+        let reader = index
+            .reader_builder()
+            .reload_policy(ReloadPolicy::Manual)
+            .try_into()
+            .expect("Search index should be readable");
+        let writer = index
+            .writer(WRITER_BYTES)
+            .expect("Search index should be writable by only one process");
+
+        Self {
+            index,
+            reader,
+            writer: Arc::new(Mutex::new(writer)),
+            path,
+            body,
+        }
+    }
+
+    /// Replaces the page at `path` with `md`, or just removes it without any markdown,
+    /// on a blocking thread as committing waits on the disk.
+    async fn write(&self, path: &str, md: Option<&str>) -> Result<()> {
+        let index = self.clone();
+        let (path, md) = (path.to_owned(), md.map(str::to_owned));
+
+        tokio::task::spawn_blocking(move || -> Result<()> {
+            let mut writer = index.writer.lock().unwrap();
+            writer.delete_term(Term::from_field_text(index.path, &path));
+            if let Some(md) = md {
+                writer.add_document(doc!(index.path => path, index.body => md))?;
+            }
+            writer.commit()?;
+            index.reader.reload()?;
+
+            Ok(())
+        })
+        .await
+        .map_err(|e| Error::Search(e.to_string()))?
+    }
+}
+
+#[async_trait]
+impl SearchIndex for TantivyIndex {
+    async fn index_page(&self, path: &str, md: &str) -> Result<()> {
+        self.write(path, Some(md)).await
+    }
+
+    async fn remove_page(&self, path: &str) -> Result<()> {
+        self.write(path, None).await
+    }
+
+    async fn is_empty(&self) -> Result<bool> {
+        Ok(self.reader.searcher().num_docs() == 0)
+    }
+
+    async fn search(&self, query: &str) -> Result<Vec<(String, f32)>> {
+        let mut parser = QueryParser::for_index(&self.index, vec![self.body]);
+        parser.set_conjunction_by_default();
+        // Searches are typed by people, so anything the query syntax can't make sense of is ignored.
+        let (query, _) = parser.parse_query_lenient(query);
+
+        let searcher = self.reader.searcher();
+        let mut results = Vec::new();
+        for (score, address) in searcher.search(&query, &TopDocs::with_limit(MAX_RESULTS))? {
+            let doc = searcher.doc(address)?;
+            if let Some(path) = doc.get_first(self.path).and_then(|p| p.as_text()) {
+                results.push((path.to_owned(), score));
+            }
+        }
+
+        Ok(results)
+    }
+}
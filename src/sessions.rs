@@ -0,0 +1,143 @@
+use std::{collections::HashMap, sync::Arc};
+
+use actix_session::storage::{
+    CookieSessionStore, LoadError, SaveError, SessionKey, SessionStore, UpdateError,
+};
+use actix_web::cookie::time::Duration;
+use rand::{distributions::Alphanumeric, Rng};
+use sha2::{Digest, Sha256};
+
+use crate::store::Store;
+
+pub const SESSION_KEY: &str = "session";
+
+type SessionState = HashMap<String, String>;
+
+/// Where sessions are kept, picked with `knowbase_SESSION_STORE`.
+pub enum SessionBackend {
+    /// Sessions live entirely in the signed cookie, so they can't be revoked one at a time.
+    Cookie(CookieSessionStore),
+    /// Sessions live in the wiki's storage and the cookie only holds a random key.
+    Storage(Arc<dyn Store>),
+}
+
+/// Where a session is stored, hashed so the key in someone's cookie never appears in storage.
+pub fn storage_key(session_key: &str) -> String {
+    let hash: String = Sha256::digest(session_key.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+
+    format!("{}:{}", SESSION_KEY, hash)
+}
+
+fn ttl_duration(ttl: &Duration) -> std::time::Duration {
+    std::time::Duration::from_secs(ttl.whole_seconds().max(1) as u64)
+}
+
+impl SessionBackend {
+    async fn save_state(
+        store: &dyn Store,
+        session_key: &str,
+        state: &SessionState,
+        ttl: &Duration,
+    ) -> anyhow::Result<()> {
+        store
+            .set_ex(
+                &storage_key(session_key),
+                &serde_json::to_vec(state)?,
+                ttl_duration(ttl),
+            )
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait(?Send)]
+impl SessionStore for SessionBackend {
+    async fn load(&self, session_key: &SessionKey) -> Result<Option<SessionState>, LoadError> {
+        let store = match self {
+            SessionBackend::Cookie(cookie) => return cookie.load(session_key).await,
+            SessionBackend::Storage(store) => store,
+        };
+
+        let state = store
+            .get(&storage_key(session_key.as_ref()))
+            .await
+            .map_err(|e| LoadError::Other(e.into()))?;
+
+        state
+            .map(|s| serde_json::from_slice(&s))
+            .transpose()
+            .map_err(|e| LoadError::Deserialization(e.into()))
+    }
+
+    async fn save(
+        &self,
+        session_state: SessionState,
+        ttl: &Duration,
+    ) -> Result<SessionKey, SaveError> {
+        let store = match self {
+            SessionBackend::Cookie(cookie) => return cookie.save(session_state, ttl).await,
+            SessionBackend::Storage(store) => store,
+        };
+
+        let session_key: String = rand::rngs::OsRng
+            .sample_iter(&Alphanumeric)
+            .take(64)
+            .map(char::from)
+            .collect();
+        Self::save_state(store.as_ref(), &session_key, &session_state, ttl)
+            .await
+            .map_err(SaveError::Other)?;
+
+        Ok(session_key
+            .try_into()
+            .map_err(Into::into)
+            .map_err(SaveError::Other)?)
+    }
+
+    async fn update(
+        &self,
+        session_key: SessionKey,
+        session_state: SessionState,
+        ttl: &Duration,
+    ) -> Result<SessionKey, UpdateError> {
+        let store = match self {
+            SessionBackend::Cookie(cookie) => {
+                return cookie.update(session_key, session_state, ttl).await
+            }
+            SessionBackend::Storage(store) => store,
+        };
+
+        Self::save_state(store.as_ref(), session_key.as_ref(), &session_state, ttl)
+            .await
+            .map_err(UpdateError::Other)?;
+
+        Ok(session_key)
+    }
+
+    async fn update_ttl(&self, session_key: &SessionKey, ttl: &Duration) -> anyhow::Result<()> {
+        let store = match self {
+            SessionBackend::Cookie(cookie) => return cookie.update_ttl(session_key, ttl).await,
+            SessionBackend::Storage(store) => store,
+        };
+
+        let key = storage_key(session_key.as_ref());
+        if let Some(state) = store.get(&key).await? {
+            store.set_ex(&key, &state, ttl_duration(ttl)).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn delete(&self, session_key: &SessionKey) -> anyhow::Result<()> {
+        match self {
+            SessionBackend::Cookie(cookie) => cookie.delete(session_key).await,
+            SessionBackend::Storage(store) => {
+                Ok(store.delete(&storage_key(session_key.as_ref())).await?)
+            }
+        }
+    }
+}
@@ -1,24 +1,258 @@
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, HashMap},
+    fmt::Display,
+    net::IpAddr,
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
+
+use actix_session::storage::CookieSessionStore;
+
 use actix_web::cookie::Key;
+use argon2::{
+    password_hash::{rand_core::OsRng, SaltString},
+    Argon2, PasswordHash, PasswordHasher, PasswordVerifier,
+};
 use comrak::{
-    nodes::{AstNode, NodeValue},
-    Arena, ComrakExtensionOptions, ComrakOptions, ComrakParseOptions, ComrakRenderOptions,
+    arena_tree::Node,
+    nodes::{Ast, AstNode, NodeCode, NodeLink, NodeValue},
+    plugins::syntect::SyntectAdapter,
+    Anchorizer, Arena, ComrakExtensionOptions, ComrakOptions, ComrakParseOptions, ComrakPlugins,
+    ComrakRenderOptions,
 };
+use futures_util::{future::BoxFuture, FutureExt};
 use lazy_static::lazy_static;
-use redis::AsyncCommands;
+use percent_encoding::percent_decode_str;
+use rand::seq::SliceRandom;
 use regex::Regex;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use syntect::highlighting::ThemeSet;
 
-const PAGE_KEY: &str = "pages";
+#[cfg(feature = "tantivy")]
+use crate::search::TantivyIndex;
+use crate::{
+    acl::{Acl, Viewer},
+    backup::Backup,
+    config,
+    error::{Error, Result},
+    image_type, math,
+    nav::{LinkGraph, NavCache, NavFolder, NavPage, PageTitle},
+    oidc::Oidc,
+    paths::{
+        self, attachment_link, canonical_path, is_under, resolve_path, slug_path, tree_order,
+        wiki_link, wiki_url,
+    },
+    s3::Bucket,
+    search::{self, Query, RediSearchIndex, SearchIndex, StoreIndex},
+    sessions::{SessionBackend, SESSION_KEY},
+    store::{MemoryStore, PostgresStore, RedisStore, SqliteStore, Store, CHUNKS_KEY},
+};
+
+const FAILURES_KEY: &str = "login_failures";
+const LOCKED_KEY: &str = "login_locked";
+const LOGO_KEY: &str = "logo";
+const LOGO_TYPE_KEY: &str = "logo_type";
+const FAVICON_KEY: &str = "favicon";
+const FAVICON_TYPE_KEY: &str = "favicon_type";
+const CUSTOM_CSS_KEY: &str = "custom_css";
+const SOURCE_KEY: &str = "source";
+const REVISIONS_KEY: &str = "revisions";
+const TRASH_KEY: &str = "trash";
+const REDIRECT_KEY: &str = "redirect";
+const TOKENS_KEY: &str = "api_tokens";
+const USERS_KEY: &str = "users";
+const TOTP_KEY: &str = "totp";
+/// The last time step a two-factor code was accepted at for each secret, so codes can't be
+/// replayed.
+const TOTP_STEP_KEY: &str = "totp_step";
+const SESSION_EPOCH_KEY: &str = "session_epoch";
+const TOKEN_KEY: &str = "api_token";
+const TRASHED_KEY: &str = "trashed";
+const REVISION_KEY: &str = "revision";
+const AUDIT_KEY: &str = "audit";
+const TAG_KEY: &str = "tag";
+const BACKLINKS_KEY: &str = "backlinks";
+const ATTACHMENT_KEY: &str = "attachment";
+const INCLUDED_KEY: &str = "included";
+/// The pages linking to or embedding each attachment.
+const EMBEDDED_KEY: &str = "embedded";
+const ALIAS_KEY: &str = "alias";
+/// Remembers what pages were last rendered with, to tell when they need rendering again.
+const RENDER_OPTIONS_KEY: &str = "render-options";
+/// The commit of the synced git repository its pages were last imported from.
+const GIT_COMMIT_KEY: &str = "git-commit";
+/// The pages written most recently, newest first.
+const RECENT_KEY: &str = "recent";
+/// How many recently written pages are remembered.
+const RECENT_PAGES: usize = 100;
+/// How many words a minute reading times assume.
+const WORDS_PER_MINUTE: usize = 200;
+/// The pages someone starred, followed by their username. Logins without an account share the
+/// list without one.
+const STARRED_KEY: &str = "starred";
+/// The markdown extensions pages are rendered with unless `knowbase_MARKDOWN_EXTENSIONS` says otherwise.
+const DEFAULT_MARKDOWN_EXTENSIONS: &str =
+    "strikethrough,tagfilter,table,autolink,tasklist,superscript,footnotes,shortcodes";
+/// Added to the score of pages whose path matches a search, so they rank above pages that only
+/// mention it.
+const TITLE_MATCH_SCORE: f32 = 100.0;
+/// The size of each piece a large page's content is split into.
+const CHUNK_BYTES: usize = 64 * 1024;
+/// Stands in for a `[TOC]` paragraph until the page's outline can be put there.
+const TOC_MARKER: &str = "KNOWBASETOCX";
+/// A dark theme, to match the rest of the wiki.
+const DEFAULT_CODE_THEME: &str = "base16-ocean.dark";
 
 lazy_static! {
     static ref INDEX_RE: Regex = Regex::new(r"(?s)\+\+\+INDEX\+\+\+\n(.*?)\n---INDEX---").unwrap();
+    static ref INCLUDE_RE: Regex = Regex::new(r"\{\{\s*include:\s*([^{}]+?)\s*\}\}").unwrap();
+    static ref CALLOUT_RE: Regex = Regex::new(r"^\[!([A-Za-z][\w-]*)\][+-]?[ \t]*").unwrap();
+    static ref WIKILINK_RE: Regex = Regex::new(r"\[\[([^\[\]|]+)(?:\|([^\[\]]+))?\]\]").unwrap();
+    static ref FRONT_MATTER_RE: Regex =
+        Regex::new(r"(?s)\A---\r?\n(.*?)\r?\n---(?:\r?\n|\z)").unwrap();
 }
 
 #[derive(Debug, Clone)]
 pub struct State {
     name: String,
-    client: redis::Client,
-    access_code: String,
+    store: Arc<dyn Store>,
+    search: Arc<dyn SearchIndex>,
+    pages_dir: Option<PathBuf>,
+    access_code: Option<AccessCode>,
+    /// A second code that only gives read access.
+    guest_code: Option<AccessCode>,
+    /// The account created or updated on startup so there's always someone who can log in.
+    admin: Option<(String, String)>,
+    oidc: Option<Oidc>,
+    /// Where attachments are kept when they're not in the store.
+    bucket: Option<Bucket>,
+    public_read: bool,
+    /// Path prefixes whose pages anyone can read, even when public read is off.
+    public_prefixes: Vec<String>,
+    /// Proxies whose `X-Forwarded-For` headers are believed.
+    trusted_proxies: Vec<IpAddr>,
+    acl: Acl,
+    lockout: Lockout,
+    stream_threshold: usize,
+    max_pages: Option<usize>,
+    max_bytes: Option<u64>,
+    max_revisions: usize,
+    /// The largest file from an upload that's kept as an attachment.
+    max_attachment_bytes: usize,
+    /// The most markdown a single page can have.
+    max_page_bytes: usize,
+    /// The largest upload form accepted, files included.
+    max_upload_bytes: usize,
+    workers: usize,
+    /// How long a login lasts, in seconds.
+    session_ttl: u64,
+    /// How long a login lasts when "remember me" is ticked, in seconds.
+    remember_ttl: u64,
+    /// Whether sessions are kept in storage rather than in their cookie.
+    server_sessions: bool,
+    /// How long audit log events are kept.
+    audit_retention: Duration,
+    /// Colours fenced code blocks, unless highlighting was turned off.
+    highlighter: Option<Arc<SyntectAdapter>>,
+    /// The comrak options every page is rendered with.
+    markdown: ComrakOptions,
+    /// Everything from the configuration that changes how pages render, so a change can be noticed.
+    render_options: String,
+    nav: Arc<NavCache>,
+    /// Held while an index entry or a stored list, like a page's revisions or the users, is read
+    /// and written back, so updates made at the same time don't lose each other.
+    update_lock: Arc<tokio::sync::Mutex<()>>,
+    /// A stylesheet served after bootstrap's, unless one was uploaded through the admin page.
+    custom_css_file: Option<PathBuf>,
+    /// Images to brand the wiki with, unless others were uploaded through the admin page.
+    logo_file: Option<PathBuf>,
+    favicon_file: Option<PathBuf>,
+}
+
+/// The shared code used to log in without an account.
+#[derive(Debug, Clone)]
+enum AccessCode {
+    /// An argon2 hash in PHC string format.
+    Hash(String),
+    Plain(String),
+}
+
+impl AccessCode {
+    /// Reads a code from `{var}_HASH`, or failing that the plain text `{var}`.
+    fn from_config(var: &str) -> Option<Self> {
+        match config::var(&format!("{}_HASH", var)) {
+            Ok(hash) => {
+                PasswordHash::new(&hash).unwrap_or_else(|_| {
+                    panic!("{}_HASH should be an argon2 hash in PHC format", var)
+                });
+                Some(AccessCode::Hash(hash))
+            }
+            Err(_) => config::var(var).ok().map(|code| {
+                eprintln!(
+                    "{} is kept in plain text, consider setting {}_HASH instead",
+                    var, var
+                );
+                AccessCode::Plain(code)
+            }),
+        }
+    }
+
+    fn matches(&self, password: &str) -> bool {
+        let password = password.trim();
+        match self {
+            AccessCode::Hash(hash) => PasswordHash::new(hash).is_ok_and(|hash| {
+                Argon2::default()
+                    .verify_password(password.as_bytes(), &hash)
+                    .is_ok()
+            }),
+            AccessCode::Plain(code) => constant_time_eq(code, password),
+        }
+    }
+}
+
+/// What failed logins are counted against. Each kind is kept apart from the others, so an IP
+/// can't be mistaken for an account.
+#[derive(Debug, Clone, Copy)]
+pub enum Attempts<'a> {
+    /// Every login from one IP.
+    Ip(&'a str),
+    /// The two-factor codes entered for an account, `None` being the access code.
+    Account(Option<&'a str>),
+}
+
+impl Display for Attempts<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Attempts::Ip(ip) => write!(f, "ip:{}", ip),
+            Attempts::Account(Some(user)) => write!(f, "user:{}", user),
+            Attempts::Account(None) => write!(f, "access_code"),
+        }
+    }
+}
+
+/// How failed logins from one IP escalate into an enforced wait.
+#[derive(Debug, Clone)]
+struct Lockout {
+    /// Failures allowed before a wait is enforced.
+    threshold: u64,
+    /// The first wait in seconds, doubled for each further failure.
+    base: u64,
+    /// The longest wait in seconds.
+    max: u64,
+    /// How long in seconds failures are remembered after the last one.
+    window: u64,
+}
+
+/// How much of the wiki's storage is in use, alongside the configured limits.
+#[derive(Debug, Default, Clone)]
+pub struct Usage {
+    pub pages: usize,
+    pub bytes: u64,
+    pub max_pages: Option<usize>,
+    pub max_bytes: Option<u64>,
 }
 
 #[derive(Debug, Default, Clone, Serialize, Deserialize)]
@@ -26,180 +260,3312 @@ pub struct Page {
     pub content: String,
     pub index: String,
     pub preview: String,
+    #[serde(default)]
+    pub visibility: Visibility,
+    /// Whether the page asked to be readable without logging in with `public: true`.
+    #[serde(default)]
+    pub public: bool,
+    /// Lowercase topics from the page's `tags:` front matter.
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Other paths from the page's `aliases:` front matter that redirect to it.
+    #[serde(default)]
+    pub aliases: Vec<String>,
+    /// What the page's front matter said to call it, instead of its file name.
+    #[serde(default)]
+    pub title: Option<String>,
+    /// A short description from the front matter, shown when the page is listed.
+    #[serde(default)]
+    pub summary: Option<String>,
+    /// When the front matter says the page was written, as it was written there.
+    #[serde(default)]
+    pub date: Option<String>,
+    /// An outline of the page's headings, shown beside it when the front matter has `toc: true`.
+    #[serde(default)]
+    pub toc: String,
+    /// The other pages this page links to, for their "Linked from" lists.
+    #[serde(default)]
+    pub links: Vec<String>,
+    /// The pages included into this one, directly or through other includes, so it can be
+    /// re-rendered when they change.
+    #[serde(default)]
+    pub includes: Vec<String>,
+    /// The attachments this page links to or embeds, which anyone who can read it can download.
+    #[serde(default)]
+    pub attachments: Vec<String>,
+    /// Whether the page has mermaid diagrams, so the wiki knows to load the script that draws them.
+    #[serde(default)]
+    pub diagrams: bool,
+    /// How many words are in the page once includes are filled in, not counting diagrams.
+    #[serde(default)]
+    pub words: usize,
+    /// How many chunks the content was split into because it was too large to store inline,
+    /// `content` is empty if this isn't zero.
+    #[serde(default)]
+    pub chunks: usize,
+    /// A hash of the markdown the page was rendered from, so writing the same markdown again
+    /// can be skipped.
+    #[serde(default)]
+    pub hash: String,
+    /// When the page was first written, in seconds since the Unix epoch. Pages written before
+    /// this was kept don't know.
+    #[serde(default)]
+    pub created: Option<u64>,
+    /// When the page's markdown last changed, in seconds since the Unix epoch.
+    #[serde(default)]
+    pub updated: Option<u64>,
+    /// The username of whoever last changed the markdown, if they were logged in as a user.
+    #[serde(default)]
+    pub updated_by: Option<String>,
+}
+
+impl Page {
+    pub fn created_time(&self) -> Option<String> {
+        self.created.map(format_timestamp)
+    }
+
+    pub fn updated_time(&self) -> Option<String> {
+        self.updated.map(format_timestamp)
+    }
+
+    /// Roughly how many minutes the page takes to read, at least one.
+    pub fn reading_minutes(&self) -> usize {
+        self.words.div_ceil(WORDS_PER_MINUTE).max(1)
+    }
+
+    /// The title from the page's front matter, or failing that its capitalised file name.
+    pub fn title(&self, path: &str) -> String {
+        if let Some(title) = &self.title {
+            return title.clone();
+        }
+
+        let title = page_title(path);
+        let mut chars = title.chars();
+        match chars.next() {
+            Some(first) => first.to_uppercase().chain(chars).collect(),
+            None => title,
+        }
+    }
+}
+
+/// Whether a page can be read without logging in when public read is enabled.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Visibility {
+    #[default]
+    Public,
+    Private,
+}
+
+/// An image the wiki is branded with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BrandImage {
+    /// Shown next to the wiki's name in the navbar.
+    Logo,
+    Favicon,
+}
+
+impl BrandImage {
+    fn keys(self) -> (&'static str, &'static str) {
+        match self {
+            BrandImage::Logo => (LOGO_KEY, LOGO_TYPE_KEY),
+            BrandImage::Favicon => (FAVICON_KEY, FAVICON_TYPE_KEY),
+        }
+    }
+
+    fn other(self) -> Self {
+        match self {
+            BrandImage::Logo => BrandImage::Favicon,
+            BrandImage::Favicon => BrandImage::Logo,
+        }
+    }
+}
+
+impl Display for BrandImage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BrandImage::Logo => write!(f, "logo"),
+            BrandImage::Favicon => write!(f, "favicon"),
+        }
+    }
+}
+
+/// Metadata from the `---` delimited YAML block at the top of a page.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct FrontMatter {
+    visibility: Visibility,
+    public: bool,
+    tags: TextList,
+    title: Option<String>,
+    summary: Option<String>,
+    date: Option<String>,
+    toc: bool,
+    /// Other paths the page can be reached at.
+    aliases: TextList,
+}
+
+/// Tags and aliases can be written as a YAML list or a single comma separated string.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum TextList {
+    List(Vec<String>),
+    Text(String),
+}
+
+impl Default for TextList {
+    fn default() -> Self {
+        TextList::List(Vec::new())
+    }
+}
+
+impl TextList {
+    fn items(self) -> Vec<String> {
+        match self {
+            TextList::List(items) => items,
+            TextList::Text(text) => text.split(',').map(str::to_owned).collect(),
+        }
+    }
+
+    /// Lowercase, trimmed and without duplicates, in the order they were written.
+    fn normalize(self) -> Vec<String> {
+        let mut normalized: Vec<String> = Vec::new();
+        for tag in self.items() {
+            let tag = tag.trim().to_lowercase();
+            if !tag.is_empty() && !normalized.contains(&tag) {
+                normalized.push(tag);
+            }
+        }
+
+        normalized
+    }
+
+    /// Canonical page paths, with `.md` added to any that don't end with it, leaving out `path`
+    /// itself.
+    fn page_paths(self, path: &str) -> Vec<String> {
+        let mut paths: Vec<String> = Vec::new();
+        for item in self.items() {
+            let mut alias = canonical_path(&item);
+            if alias.is_empty() {
+                continue;
+            }
+            if !alias.ends_with(".md") {
+                alias.push_str(".md");
+            }
+            if alias != path && !paths.contains(&alias) {
+                paths.push(alias);
+            }
+        }
+
+        paths
+    }
+}
+
+/// A version of a page's markdown, recorded every time it's written.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Revision {
+    pub id: u64,
+    /// When the revision was written, in seconds since the Unix epoch.
+    pub timestamp: u64,
+    /// The user who wrote the revision, if it was written by someone logged in to an account.
+    #[serde(default)]
+    pub author: Option<String>,
+}
+
+impl Revision {
+    pub fn time(&self) -> String {
+        format_timestamp(self.timestamp)
+    }
+}
+
+/// The last time a page was written, as remembered for the recent changes page.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct PageChange {
+    path: String,
+    /// In seconds since the Unix epoch.
+    timestamp: u64,
+    /// Whether the write created the page.
+    created: bool,
+    author: Option<String>,
+}
+
+/// A recently created or updated page, previewed like a search result.
+#[derive(Debug, Clone)]
+pub struct RecentChange {
+    pub title: String,
+    pub url: String,
+    pub preview: String,
+    /// When the page was last written, in seconds since the Unix epoch.
+    pub timestamp: u64,
+    /// Whether that write created the page.
+    pub created: bool,
+    pub author: Option<String>,
+}
+
+impl RecentChange {
+    pub fn time(&self) -> String {
+        format_timestamp(self.timestamp)
+    }
+}
+
+/// A deleted page whose markdown is kept so it can be restored.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrashedPage {
+    pub path: String,
+    /// When the page was deleted, in seconds since the Unix epoch.
+    pub timestamp: u64,
+}
+
+impl TrashedPage {
+    pub fn time(&self) -> String {
+        format_timestamp(self.timestamp)
+    }
+}
+
+/// What a user is allowed to do, each role can do everything the ones before it can.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// Can read and search pages.
+    Reader,
+    /// Can also upload, edit, move and delete pages.
+    #[default]
+    Editor,
+    /// Can also manage users, API tokens and the trash.
+    Admin,
+}
+
+impl Display for Role {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Role::Reader => write!(f, "reader"),
+            Role::Editor => write!(f, "editor"),
+            Role::Admin => write!(f, "admin"),
+        }
+    }
+}
+
+/// Someone who logs in with their own username and password rather than the access code.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct User {
+    pub username: String,
+    password_hash: String,
+    #[serde(default)]
+    pub role: Role,
+    /// When the account was created, in seconds since the Unix epoch.
+    pub timestamp: u64,
+    /// The single sign-on subject an admin linked to the account, which logs in as it.
+    #[serde(default)]
+    pub sso_subject: Option<String>,
+}
+
+impl User {
+    pub fn time(&self) -> String {
+        format_timestamp(self.timestamp)
+    }
+}
+
+/// A logged in session kept in storage.
+#[derive(Debug, Clone)]
+pub struct SessionInfo {
+    /// The hash the session is stored under, not the key in its cookie.
+    pub id: String,
+    pub user: Option<String>,
+    /// When the login expires, in seconds since the Unix epoch.
+    pub expires: Option<u64>,
+}
+
+impl SessionInfo {
+    pub fn expires_at(&self) -> String {
+        self.expires.map(format_timestamp).unwrap_or_default()
+    }
+}
+
+/// Something done on the wiki that's worth keeping a record of.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditEvent {
+    /// When it happened, in seconds since the Unix epoch.
+    pub timestamp: u64,
+    pub action: AuditAction,
+    /// Who did it, an account name or how they logged in.
+    pub actor: String,
+    pub ip: String,
+    /// The page it was done to, or whatever else there is to know about it.
+    pub detail: String,
+}
+
+impl AuditEvent {
+    pub fn time(&self) -> String {
+        format_timestamp(self.timestamp)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditAction {
+    Login,
+    FailedLogin,
+    Logout,
+    Upload,
+    Write,
+    Move,
+    Delete,
+    Restore,
+    /// An API token was created.
+    Create,
+    /// Sessions or an API token stopped working.
+    Revoke,
+    /// A user was added or changed.
+    SaveUser,
+    /// A user was linked to a single sign-on subject, or unlinked from one.
+    Link,
+}
+
+impl Display for AuditAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AuditAction::Login => write!(f, "login"),
+            AuditAction::FailedLogin => write!(f, "failed login"),
+            AuditAction::Logout => write!(f, "logout"),
+            AuditAction::Upload => write!(f, "upload"),
+            AuditAction::Write => write!(f, "write"),
+            AuditAction::Move => write!(f, "move"),
+            AuditAction::Delete => write!(f, "delete"),
+            AuditAction::Restore => write!(f, "restore"),
+            AuditAction::Create => write!(f, "create"),
+            AuditAction::Revoke => write!(f, "revoke"),
+            AuditAction::SaveUser => write!(f, "save user"),
+            AuditAction::Link => write!(f, "link"),
+        }
+    }
+}
+
+/// A bearer token that can be used in place of a session. Only its hash is stored.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApiToken {
+    pub hash: String,
+    pub name: String,
+    /// When the token was created, in seconds since the Unix epoch.
+    pub timestamp: u64,
+}
+
+impl ApiToken {
+    /// A short form of the hash to tell tokens apart by.
+    pub fn id(&self) -> &str {
+        self.hash.get(..12).unwrap_or(&self.hash)
+    }
+
+    pub fn time(&self) -> String {
+        format_timestamp(self.timestamp)
+    }
+}
+
+/// A link to a page, by its title.
+#[derive(Debug, Clone)]
+pub struct PageLink {
+    pub title: String,
+    pub url: String,
+}
+
+/// Why a page is being rendered and stored, which decides the times it keeps.
+#[derive(Clone, Copy)]
+enum Write<'a> {
+    /// Someone changed its markdown, the inner value being who.
+    Edit(Option<&'a str>),
+    /// It's only being rendered again, so it keeps the times it already had.
+    Rerender,
+    /// It's the page being moved here, whose times it keeps and whose place it takes in the
+    /// quota.
+    Move(&'a Page),
+}
+
+/// What writing a page did.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageWrite {
+    Created,
+    Updated,
+    /// The page already had that markdown, so it was left alone.
+    Unchanged,
 }
 
 #[derive(Debug, Default, Clone, Serialize, Deserialize)]
 pub struct SearchResult {
     pub title: String,
     pub url: String,
+    /// HTML of the part of the page around the first match, with matching words in `<mark>`.
     pub preview: String,
 }
 
 impl State {
-    pub fn new() -> Self {
-        let name = std::env::var("knowbase_NAME").unwrap_or("knowbase".to_owned());
-        let access_code =
-            std::env::var("knowbase_ACCESS_CODE").expect("knowbase_ACCESS_CODE should be set");
-        let client = redis::Client::open(
-            std::env::var("knowbase_REDIS_URL").expect("knowbase_REDIS_URL should be set"),
-        )
-        .expect("Redis URL should be valid");
+    pub async fn new() -> Self {
+        if config::var("knowbase_ACCESS_CODE_HASH").is_err()
+            && config::var("knowbase_ACCESS_CODE").is_err()
+            && config::var("knowbase_ADMIN_PASSWORD").is_err()
+            && config::var("knowbase_OIDC_ISSUER").is_err()
+        {
+            panic!(
+                "knowbase_ACCESS_CODE_HASH, knowbase_ACCESS_CODE, knowbase_ADMIN_PASSWORD or knowbase_OIDC_ISSUER should be set"
+            );
+        }
+        let store: Arc<dyn Store> = match config::var("knowbase_STORAGE").as_deref() {
+            Ok("redis") | Err(_) => Arc::new(RedisStore::from_config()),
+            Ok("sqlite") => Arc::new(SqliteStore::from_config().await),
+            Ok("postgres") => Arc::new(PostgresStore::from_config().await),
+            Ok("filesystem") => Arc::new(MemoryStore::default()),
+            Ok(other) => panic!(
+                "knowbase_STORAGE should be redis, sqlite, postgres or filesystem, not {}",
+                other
+            ),
+        };
+        let pages_dir =
+            (config::var("knowbase_STORAGE").as_deref() == Ok("filesystem")).then(|| {
+                config::var("knowbase_PAGES_DIR")
+                    .expect("knowbase_PAGES_DIR should be set")
+                    .into()
+            });
+
+        Self::with_store(store, pages_dir)
+    }
+
+    /// A wiki kept in `store`, with the rest of its settings read from the config.
+    fn with_store(store: Arc<dyn Store>, pages_dir: Option<PathBuf>) -> Self {
+        let name = config::var("knowbase_NAME").unwrap_or("knowbase".to_owned());
+        let access_code = AccessCode::from_config("knowbase_ACCESS_CODE");
+        let guest_code = AccessCode::from_config("knowbase_GUEST_CODE");
+        let admin = config::var("knowbase_ADMIN_PASSWORD").ok().map(|password| {
+            let username = config::var("knowbase_ADMIN_USER").unwrap_or("admin".to_owned());
+            (username, password)
+        });
+
+        let public_read = config::var_or("knowbase_PUBLIC_READ", false);
+        let public_prefixes = config::var("knowbase_PUBLIC_PREFIXES")
+            .map(|prefixes| {
+                prefixes
+                    .split(',')
+                    .map(|prefix| canonical_path(prefix.trim()))
+                    .filter(|prefix| !prefix.is_empty())
+                    .collect()
+            })
+            .unwrap_or_default();
+        let trusted_proxies = config::var("knowbase_TRUSTED_PROXIES")
+            .map(|proxies| {
+                proxies
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|proxy| !proxy.is_empty())
+                    .map(|proxy| {
+                        proxy.parse().unwrap_or_else(|_| {
+                            panic!(
+                                "knowbase_TRUSTED_PROXIES should be a list of IP addresses, not {}",
+                                proxy
+                            )
+                        })
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        let lockout_max = config::var_or("knowbase_LOCKOUT_MAX_SECS", 3600);
+        let lockout = Lockout {
+            threshold: config::var_or("knowbase_LOCKOUT_THRESHOLD", 5),
+            base: config::var_or("knowbase_LOCKOUT_BASE_SECS", 2),
+            max: lockout_max,
+            window: config::var_or("knowbase_LOCKOUT_WINDOW_SECS", lockout_max * 2),
+        };
+        let stream_threshold = config::var_or("knowbase_STREAM_THRESHOLD_BYTES", 1024 * 1024);
+        let max_pages = config::parsed("knowbase_MAX_PAGES");
+        let max_bytes = config::parsed("knowbase_MAX_STORAGE_BYTES");
+        let max_revisions = config::var_or("knowbase_MAX_REVISIONS", 50);
+        let max_attachment_bytes =
+            config::var_or("knowbase_MAX_ATTACHMENT_BYTES", 10 * 1024 * 1024);
+        let max_page_bytes = config::var_or("knowbase_MAX_PAGE_BYTES", 5 * 1024 * 1024);
+        let max_upload_bytes = config::var_or("knowbase_MAX_UPLOAD_BYTES", 50 * 1024 * 1024);
+        let workers = config::var_or(
+            "knowbase_WORKERS",
+            std::thread::available_parallelism().map_or(2, |n| n.get().min(4)),
+        );
+        if workers == 0 {
+            panic!("knowbase_WORKERS should be at least 1, not 0");
+        }
+
+        let session_ttl = config::var_or("knowbase_SESSION_TTL_SECS", 12 * 60 * 60);
+        let remember_ttl = config::var_or("knowbase_REMEMBER_TTL_SECS", 30 * 24 * 60 * 60);
+        let server_sessions = match config::var("knowbase_SESSION_STORE").as_deref() {
+            Ok("cookie") | Err(_) => false,
+            Ok("server") => true,
+            Ok(other) => panic!(
+                "knowbase_SESSION_STORE should be cookie or server, not {}",
+                other
+            ),
+        };
+        let audit_retention =
+            Duration::from_secs(config::var_or("knowbase_AUDIT_RETENTION_DAYS", 90) * 24 * 60 * 60);
+
+        let markdown = markdown_options();
+        // Which pages anyone can read decides which includes are allowed.
+        let render_options = format!(
+            "{:?} {:?} {:?} {:?}",
+            markdown,
+            config::var("knowbase_CODE_THEME").ok(),
+            public_read,
+            public_prefixes
+        );
+        let highlighter = match config::var("knowbase_CODE_THEME").as_deref() {
+            Ok("none") => None,
+            theme => {
+                let theme = theme.unwrap_or(DEFAULT_CODE_THEME);
+                let themes = ThemeSet::load_defaults().themes;
+                if !themes.contains_key(theme) {
+                    let names: Vec<&str> = themes.keys().map(String::as_str).collect();
+                    panic!(
+                        "knowbase_CODE_THEME should be none or one of {}, not {}",
+                        names.join(", "),
+                        theme
+                    );
+                }
+                Some(Arc::new(SyntectAdapter::new(theme)))
+            }
+        };
+
+        let search: Arc<dyn SearchIndex> = match config::var("knowbase_SEARCH_INDEX").as_deref() {
+            Ok("storage") | Err(_) => Arc::new(StoreIndex::new(store.clone())),
+            Ok("redisearch") => Arc::new(RediSearchIndex::from_config()),
+            #[cfg(feature = "tantivy")]
+            Ok("tantivy") => Arc::new(TantivyIndex::from_config()),
+            #[cfg(not(feature = "tantivy"))]
+            Ok("tantivy") => panic!("knowbase was built without the tantivy feature"),
+            Ok(other) => panic!(
+                "knowbase_SEARCH_INDEX should be storage, redisearch or tantivy, not {}",
+                other
+            ),
+        };
 
         Self {
-            client,
+            search,
+            store,
+            pages_dir,
             name,
             access_code,
+            guest_code,
+            admin,
+            oidc: Oidc::from_config(),
+            bucket: Bucket::from_config(),
+            public_read,
+            public_prefixes,
+            trusted_proxies,
+            acl: Acl::from_config(),
+            lockout,
+            stream_threshold,
+            max_pages,
+            max_bytes,
+            max_revisions,
+            max_attachment_bytes,
+            max_page_bytes,
+            max_upload_bytes,
+            workers,
+            session_ttl,
+            remember_ttl,
+            server_sessions,
+            audit_retention,
+            highlighter,
+            markdown,
+            render_options,
+            nav: Arc::default(),
+            update_lock: Arc::default(),
+            custom_css_file: config::var("knowbase_CUSTOM_CSS").ok().map(PathBuf::from),
+            logo_file: config::var("knowbase_LOGO").ok().map(PathBuf::from),
+            favicon_file: config::var("knowbase_FAVICON").ok().map(PathBuf::from),
         }
     }
 
+    /// A wiki kept in memory that anyone can read, for tests.
+    #[cfg(test)]
+    pub fn in_memory() -> Self {
+        let mut state = Self::with_store(Arc::new(MemoryStore::default()), None);
+        state.public_read = true;
+        state
+    }
+
     pub fn name(&self) -> &str {
         &self.name
     }
 
     pub fn is_access_code_correct(&self, password: &str) -> bool {
-        self.access_code == password.trim()
+        self.access_code
+            .as_ref()
+            .is_some_and(|code| code.matches(password))
     }
 
-    pub async fn con(&self) -> redis::aio::Connection {
-        self.client
-            .get_async_connection()
-            .await
-            .expect("Redis should be available")
+    /// Whether `password` is the guest code, which logs in with read only access.
+    pub fn is_guest_code_correct(&self, password: &str) -> bool {
+        self.guest_code
+            .as_ref()
+            .is_some_and(|code| code.matches(password))
     }
 
-    pub async fn master_key(&self) -> Key {
-        let master_key: Option<Vec<u8>> = self.con().await.get("master_key").await.unwrap();
-        let master_bytes = if master_key.is_none() {
-            let key = Key::generate().master().to_vec();
-            self.con()
-                .await
-                .set::<&str, Vec<u8>, ()>("master_key", key.clone())
-                .await
-                .unwrap();
-            key
-        } else {
-            master_key.unwrap()
-        };
+    /// Creates the admin account from the config, resetting its password if it already exists.
+    pub async fn bootstrap_admin(&self) -> Result<()> {
+        match &self.admin {
+            Some((username, password)) => self.set_user(username, password, Role::Admin).await,
+            None => Ok(()),
+        }
+    }
 
-        Key::from(&master_bytes)
+    /// Every user account, sorted by username.
+    pub async fn users(&self) -> Result<Vec<User>> {
+        let users = self.store.get(USERS_KEY).await?;
+
+        Ok(users
+            .map(|u| serde_json::from_slice(&u).unwrap())
+            .unwrap_or_default())
     }
 
-    pub async fn get_page(&self, path: &str) -> Option<Page> {
-        let page_json: Option<String> = self.con().await.hget(PAGE_KEY, path).await.unwrap();
+    /// Creates a user or changes their password and role.
+    pub async fn set_user(&self, username: &str, password: &str, role: Role) -> Result<()> {
+        let username = username.trim().to_lowercase();
+        let password_hash = Argon2::default()
+            .hash_password(password.as_bytes(), &SaltString::generate(&mut OsRng))
+            .unwrap()
+            .to_string();
+
+        let _lock = self.update_lock.lock().await;
+        let mut users = self.users().await?;
+        match users.iter_mut().find(|u| u.username == username) {
+            Some(user) => {
+                user.password_hash = password_hash;
+                user.role = role;
+            }
+            None => users.push(User {
+                username,
+                password_hash,
+                role,
+                timestamp: unix_time(),
+                sso_subject: None,
+            }),
+        }
+        users.sort_by(|a, b| a.username.cmp(&b.username));
 
-        page_json.map(|p| serde_json::from_str(&p).unwrap())
+        self.set_users(&users).await
     }
 
-    pub async fn set_page(&self, path: &str, mut md: String) {
-        let arena = Arena::new();
-        let opts = ComrakOptions {
-            extension: ComrakExtensionOptions {
-                strikethrough: true,
-                tagfilter: true,
-                table: true,
-                autolink: true,
-                tasklist: true,
-                superscript: true,
-                header_ids: Some(String::new()),
-                ..Default::default()
-            },
-            parse: ComrakParseOptions::default(),
-            render: ComrakRenderOptions {
-                hardbreaks: true,
-                ..Default::default()
-            },
-        };
+    /// The role of a user, or `None` if they no longer exist.
+    pub async fn user_role(&self, username: &str) -> Result<Option<Role>> {
+        let users = self.users().await?;
 
-        let mut page = Page::default();
+        Ok(users
+            .into_iter()
+            .find(|u| u.username == username)
+            .map(|u| u.role))
+    }
 
-        if let Some(index_match) = INDEX_RE.captures(&md) {
-            page.index.push_str(&comrak::markdown_to_html(
-                index_match.get(1).unwrap().as_str(),
-                &opts,
-            ));
-            md.replace_range(index_match.get(0).unwrap().range(), "");
-        }
+    /// The account a single sign-on subject was linked to, if it was.
+    pub async fn sso_user(&self, subject: &str) -> Result<Option<String>> {
+        let users = self.users().await?;
 
-        let root = comrak::parse_document(&arena, &md, &opts);
-        iter_md_nodes(root, &|n| match &mut n.data.borrow_mut().value {
-            &mut NodeValue::Link(ref mut link) => {
-                if link.url.starts_with("/") {
-                    link.url.insert_str(0, "/w");
-                }
+        Ok(users
+            .into_iter()
+            .find(|u| u.sso_subject.as_deref() == Some(subject))
+            .map(|u| u.username))
+    }
+
+    /// Links a single sign-on subject to a user so it logs in as them, or unlinks theirs if
+    /// `subject` is `None`. A subject can only be linked to one user at a time.
+    pub async fn link_sso(&self, username: &str, subject: Option<&str>) -> Result<()> {
+        let _lock = self.update_lock.lock().await;
+        let mut users = self.users().await?;
+        for user in &mut users {
+            if user.username == username {
+                user.sso_subject = subject.map(str::to_owned);
+            } else if subject.is_some() && user.sso_subject.as_deref() == subject {
+                user.sso_subject = None;
             }
-            _ => (),
+        }
+
+        self.set_users(&users).await
+    }
+
+    pub async fn delete_user(&self, username: &str) -> Result<()> {
+        let lock = self.update_lock.lock().await;
+        let mut users = self.users().await?;
+        users.retain(|u| u.username != username);
+        self.set_users(&users).await?;
+        drop(lock);
+        self.store.delete(&starred_key(Some(username))).await
+    }
+
+    /// Checks a username and password, returning the canonical username if they match.
+    pub async fn verify_user(&self, username: &str, password: &str) -> Result<Option<String>> {
+        let username = username.trim().to_lowercase();
+        let users = self.users().await?;
+        let Some(user) = users.into_iter().find(|u| u.username == username) else {
+            return Ok(None);
+        };
+
+        let verified = PasswordHash::new(&user.password_hash).is_ok_and(|hash| {
+            Argon2::default()
+                .verify_password(password.as_bytes(), &hash)
+                .is_ok()
         });
 
-        let mut preview_len = md.len().min(500);
-        while !md.is_char_boundary(preview_len) {
-            preview_len += 1;
+        Ok(verified.then_some(user.username))
+    }
+
+    /// The two-factor secret of a user, or of the access code when `user` is `None`.
+    pub async fn totp_secret(&self, user: Option<&str>) -> Result<Option<Vec<u8>>> {
+        self.store.get(&totp_key(user)).await
+    }
+
+    pub async fn set_totp_secret(&self, user: Option<&str>, secret: &[u8]) -> Result<()> {
+        self.store.set(&totp_key(user), secret).await
+    }
+
+    /// Remembers that a code for `user`'s secret was accepted at `step`, returning `false` if a
+    /// code from that step or a later one already was.
+    pub async fn accept_totp_step(&self, user: Option<&str>, step: u64) -> Result<bool> {
+        let key = format!("{}:{}", TOTP_STEP_KEY, totp_key(user));
+        // Held so the same code can't be accepted by two logins at once.
+        let _lock = self.update_lock.lock().await;
+        let last = self
+            .store
+            .get(&key)
+            .await?
+            .and_then(|last| String::from_utf8(last).ok()?.parse::<u64>().ok());
+        if last.is_some_and(|last| last >= step) {
+            return Ok(false);
         }
 
-        page.preview = md[0..preview_len].to_owned();
+        self.store.set(&key, step.to_string().as_bytes()).await?;
+        Ok(true)
+    }
 
-        let mut html = Vec::new();
-        comrak::format_html(root, &opts, &mut html).unwrap();
-        page.content.push_str(&String::from_utf8(html).unwrap());
+    pub async fn delete_totp_secret(&self, user: Option<&str>) -> Result<()> {
+        self.store.delete(&totp_key(user)).await
+    }
 
-        self.con()
+    async fn set_users(&self, users: &[User]) -> Result<()> {
+        self.store
+            .set(USERS_KEY, &serde_json::to_vec(users).unwrap())
             .await
-            .hset::<&str, &str, String, ()>(PAGE_KEY, path, serde_json::to_string(&page).unwrap())
-            .await
-            .unwrap();
     }
 
-    pub async fn run_search(&self, search: &str) -> Vec<SearchResult> {
-        let search = search.to_lowercase();
-        let mut con = self.con().await;
-        let mut async_iter = con
-            .hscan_match::<&str, String, Vec<String>>(PAGE_KEY, format!("*{}*", search))
-            .await
-            .unwrap();
-        let mut matches: Vec<String> = Vec::new();
-
-        let mut items = async_iter.next_item().await;
-        while items.is_some() {
-            matches.append(&mut items.unwrap());
-            items = async_iter.next_item().await;
-        }
-
-        let mut results: Vec<SearchResult> = matches
-            .chunks(2)
-            .map(|a| (a[0].to_owned(), &a[1]))
-            .map(|(key, val)| {
-                let last_slash = key.split('/').last();
-                let title = if last_slash.is_some() {
-                    last_slash.unwrap().to_owned()
-                } else {
-                    key.to_owned()
-                };
+    /// The single sign-on provider, if one is configured.
+    pub fn oidc(&self) -> Option<&Oidc> {
+        self.oidc.as_ref()
+    }
+
+    /// Whether unauthenticated visitors can read and search non-private pages.
+    pub fn public_read(&self) -> bool {
+        self.public_read
+    }
 
-                let page: Page = serde_json::from_str(val).unwrap();
-                let title = title.trim_end_matches(".md").replace("-", " ");
+    /// Whether the access rules let `viewer` see the page at `path`.
+    pub fn can_see(&self, path: &str, viewer: &Viewer) -> bool {
+        self.acl.allows(path, viewer)
+    }
 
-                SearchResult {
-                    title,
-                    url: format!("w/{}", key),
-                    preview: page.preview,
+    /// Every page in the order the navigation tree lists them, kept until a page changes.
+    pub async fn nav_pages(&self) -> Result<Arc<Vec<NavPage>>> {
+        match self.nav.get() {
+            Ok(pages) => Ok(pages),
+            Err(generation) => {
+                let mut paths = self.store.list_pages().await?;
+                paths.sort_by(|a, b| tree_order(a, b));
+                let mut pages = Vec::with_capacity(paths.len());
+                for path in paths {
+                    if let Some(page) = self.store.get_page(&path).await? {
+                        pages.push(NavPage {
+                            title: page.title(&path),
+                            visibility: page.visibility,
+                            public: page.public,
+                            links: page.links,
+                            path,
+                        });
+                    }
                 }
-            })
+
+                let pages = Arc::new(pages);
+                self.nav.set(generation, pages.clone());
+                Ok(pages)
+            }
+        }
+    }
+
+    /// The navigation tree of every page the viewer can read.
+    pub async fn nav_tree(&self, viewer: &Viewer) -> Result<NavFolder> {
+        let pages = self.nav_pages().await?;
+        let readable = pages.iter().filter(|page| self.can_read(page, viewer));
+        Ok(NavFolder::build(readable.cloned()))
+    }
+
+    /// Every page the viewer can read and the links between them.
+    pub async fn link_graph(&self, viewer: &Viewer) -> Result<LinkGraph> {
+        let pages = self.nav_pages().await?;
+        let readable = pages.iter().filter(|page| self.can_read(page, viewer));
+        Ok(LinkGraph::build(readable))
+    }
+
+    /// The title of every page the viewer can read, in the order the navigation tree lists them.
+    pub async fn page_titles(&self, viewer: &Viewer) -> Result<Vec<PageTitle>> {
+        let pages = self.nav_pages().await?;
+
+        Ok(pages
+            .iter()
+            .filter(|page| self.can_read(page, viewer))
+            .map(PageTitle::from)
+            .collect())
+    }
+
+    /// The pages at `paths` the viewer can still read, in the same order.
+    pub async fn listed_pages(&self, paths: &[String], viewer: &Viewer) -> Result<Vec<NavPage>> {
+        let pages = self.nav_pages().await?;
+
+        Ok(paths
+            .iter()
+            .filter_map(|path| pages.iter().find(|page| &page.path == path))
+            .filter(|page| self.can_read(page, viewer))
+            .cloned()
+            .collect())
+    }
+
+    /// The paths of the pages the viewer starred, most recently starred first.
+    pub async fn starred(&self, viewer: &Viewer) -> Result<Vec<String>> {
+        let starred = self.store.get(&starred_key(viewer.user.as_deref())).await?;
+
+        Ok(starred
+            .and_then(|s| serde_json::from_slice(&s).ok())
+            .unwrap_or_default())
+    }
+
+    /// Stars or unstars the page at `path` for the viewer.
+    pub async fn set_starred(&self, viewer: &Viewer, path: &str, starred: bool) -> Result<()> {
+        let path = canonical_path(path);
+        let mut pages = self.starred(viewer).await?;
+        pages.retain(|p| p != &path);
+        if starred {
+            pages.insert(0, path);
+        }
+
+        self.store
+            .set(
+                &starred_key(viewer.user.as_deref()),
+                &serde_json::to_vec(&pages).unwrap(),
+            )
+            .await
+    }
+
+    /// The path of a page picked at random from those the viewer can read.
+    pub async fn random_page(&self, viewer: &Viewer) -> Result<Option<String>> {
+        let pages = self.nav_pages().await?;
+        let readable: Vec<&NavPage> = pages
+            .iter()
+            .filter(|page| self.can_read(page, viewer))
             .collect();
 
-        results.sort_by(|a, b| {
-            strsim::jaro_winkler(&b.title, &search)
-                .total_cmp(&strsim::jaro_winkler(&a.title, &search))
-        });
+        Ok(readable
+            .choose(&mut rand::thread_rng())
+            .map(|page| page.path.clone()))
+    }
 
-        results
+    fn can_read(&self, page: &NavPage, viewer: &Viewer) -> bool {
+        let listed = Page {
+            visibility: page.visibility,
+            public: page.public,
+            ..Page::default()
+        };
+        self.can_read_page(&page.path, &listed, viewer)
     }
-}
 
-fn iter_md_nodes<'a, F>(node: &'a AstNode<'a>, f: &F)
-where
-    F: Fn(&'a AstNode<'a>),
-{
-    f(node);
-    for c in node.children() {
-        iter_md_nodes(c, f);
+    /// Whether the viewer can read `page`, stored at `path`.
+    pub fn can_read_page(&self, path: &str, page: &Page, viewer: &Viewer) -> bool {
+        self.can_see(path, viewer)
+            && (viewer.role.is_some() || self.is_readable_anonymously(path, page))
+    }
+
+    /// Whether someone who hasn't logged in can read the page at `path`.
+    pub fn is_readable_anonymously(&self, path: &str, page: &Page) -> bool {
+        if page.visibility == Visibility::Private || !self.acl.allows(path, &Viewer::default()) {
+            return false;
+        }
+
+        self.public_read
+            || page.public
+            || self
+                .public_prefixes
+                .iter()
+                .any(|prefix| is_under(path, prefix))
+    }
+
+    /// The directory pages are served from when using filesystem storage.
+    pub fn pages_dir(&self) -> Option<&PathBuf> {
+        self.pages_dir.as_ref()
+    }
+
+    pub fn workers(&self) -> usize {
+        self.workers
+    }
+
+    pub fn session_backend(&self) -> SessionBackend {
+        if self.server_sessions {
+            SessionBackend::Storage(self.store.clone())
+        } else {
+            SessionBackend::Cookie(CookieSessionStore::default())
+        }
+    }
+
+    /// Every live session, if sessions are kept in storage where they can be listed.
+    pub async fn sessions(&self) -> Result<Option<Vec<SessionInfo>>> {
+        if !self.server_sessions {
+            return Ok(None);
+        }
+
+        let mut sessions = Vec::new();
+        for key in self.store.keys(&format!("{}:", SESSION_KEY)).await? {
+            let Some(state) = self.store.get(&key).await? else {
+                continue;
+            };
+
+            let state: HashMap<String, String> = serde_json::from_slice(&state).unwrap_or_default();
+            if state.get("auth").map(String::as_str) != Some("true") {
+                continue;
+            }
+
+            sessions.push(SessionInfo {
+                id: key[SESSION_KEY.len() + 1..].to_owned(),
+                user: state.get("user").and_then(|u| serde_json::from_str(u).ok()),
+                expires: state.get("expires").and_then(|e| e.parse().ok()),
+            });
+        }
+        sessions.sort_by_key(|s| std::cmp::Reverse(s.expires));
+
+        Ok(Some(sessions))
+    }
+
+    pub async fn revoke_session(&self, id: &str) -> Result<()> {
+        self.store.delete(&format!("{}:{}", SESSION_KEY, id)).await
+    }
+
+    /// Adds an event to the audit log, where it's kept until the retention period passes.
+    pub async fn record(&self, event: &AuditEvent) -> Result<()> {
+        // Every event gets its own key so events recorded at the same time can't overwrite each other.
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        let key = format!(
+            "{}:{:024}:{}",
+            AUDIT_KEY,
+            nanos,
+            hex(&rand::random::<[u8; 4]>())
+        );
+
+        self.store
+            .set_ex(
+                &key,
+                &serde_json::to_vec(event).unwrap(),
+                self.audit_retention,
+            )
+            .await
+    }
+
+    /// The most recent `limit` events in the audit log, newest first.
+    pub async fn audit_log(&self, limit: usize) -> Result<Vec<AuditEvent>> {
+        let mut keys = self.store.keys(&format!("{}:", AUDIT_KEY)).await?;
+        keys.sort_unstable_by(|a, b| b.cmp(a));
+
+        let mut events = Vec::new();
+        for key in keys.into_iter().take(limit) {
+            if let Some(event) = self.store.get(&key).await? {
+                events.push(serde_json::from_slice(&event).unwrap());
+            }
+        }
+
+        Ok(events)
+    }
+
+    /// How long a login lasts in seconds, depending on whether it should be remembered.
+    pub fn session_ttl(&self, remember: bool) -> u64 {
+        if remember {
+            self.remember_ttl
+        } else {
+            self.session_ttl
+        }
+    }
+
+    /// Whether `ip` is a proxy whose `X-Forwarded-For` headers say who a request is really from.
+    pub fn trusts_proxy(&self, ip: IpAddr) -> bool {
+        self.trusted_proxies.contains(&ip)
+    }
+
+    pub async fn ping(&self) -> Result<()> {
+        self.store.ping().await
+    }
+
+    /// Seconds until `attempts` may try another login, if they're currently locked out.
+    pub async fn login_lockout(&self, attempts: Attempts<'_>) -> Result<Option<u64>> {
+        let ttl = self
+            .store
+            .ttl(&format!("{}:{}", LOCKED_KEY, attempts))
+            .await?;
+
+        Ok(ttl.map(|t| t.as_secs()))
+    }
+
+    /// Counts a failed login against `attempts`, locking them out once they pass the threshold.
+    pub async fn record_login_failure(&self, attempts: Attempts<'_>) -> Result<()> {
+        let failures = self
+            .store
+            .incr(
+                &format!("{}:{}", FAILURES_KEY, attempts),
+                Duration::from_secs(self.lockout.window),
+            )
+            .await?;
+
+        if failures >= self.lockout.threshold {
+            let doublings = (failures - self.lockout.threshold).min(63) as u32;
+            let wait = self
+                .lockout
+                .base
+                .saturating_mul(2u64.saturating_pow(doublings))
+                .min(self.lockout.max);
+            eprintln!(
+                "Locking out {} for {} seconds after {} failed logins",
+                attempts, wait, failures
+            );
+            self.store
+                .set_ex(
+                    &format!("{}:{}", LOCKED_KEY, attempts),
+                    b"1",
+                    Duration::from_secs(wait),
+                )
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    pub async fn clear_login_failures(&self, attempts: Attempts<'_>) -> Result<()> {
+        self.store
+            .delete(&format!("{}:{}", FAILURES_KEY, attempts))
+            .await?;
+        self.store
+            .delete(&format!("{}:{}", LOCKED_KEY, attempts))
+            .await
+    }
+
+    pub async fn master_key(&self) -> Result<Key> {
+        self.store.master_key().await
+    }
+
+    /// Logs everyone out: sessions started before now stop working straight away, and the
+    /// cookie signing key is replaced from the next restart.
+    pub async fn invalidate_sessions(&self) -> Result<()> {
+        self.store.rotate_master_key().await?;
+        for key in self.store.keys(&format!("{}:", SESSION_KEY)).await? {
+            self.store.delete(&key).await?;
+        }
+        let epoch = self.session_epoch().await? + 1;
+        self.store
+            .set(SESSION_EPOCH_KEY, epoch.to_string().as_bytes())
+            .await
+    }
+
+    /// Bumped every time sessions are invalidated, sessions started under an older one are ignored.
+    pub async fn session_epoch(&self) -> Result<u64> {
+        let epoch = self.store.get(SESSION_EPOCH_KEY).await?;
+
+        Ok(epoch
+            .and_then(|e| String::from_utf8_lossy(&e).parse().ok())
+            .unwrap_or(0))
+    }
+
+    /// The content type and bytes of the logo or favicon, the one uploaded through the admin page if
+    /// there is one, otherwise the file configured for it. Each falls back on the other, so one
+    /// image can brand both, and `None` means the bundled favicon should be used.
+    pub async fn brand_image(&self, image: BrandImage) -> Result<Option<(String, Vec<u8>)>> {
+        match self.own_brand_image(image).await? {
+            Some(found) => Ok(Some(found)),
+            None => self.own_brand_image(image.other()).await,
+        }
+    }
+
+    async fn own_brand_image(&self, image: BrandImage) -> Result<Option<(String, Vec<u8>)>> {
+        let (key, type_key) = image.keys();
+        let content_type = self.store.get(type_key).await?;
+        if let Some(uploaded) = content_type.zip(self.store.get(key).await?) {
+            let content_type = String::from_utf8_lossy(&uploaded.0).into_owned();
+            return Ok(Some((content_type, uploaded.1)));
+        }
+
+        let file = match image {
+            BrandImage::Logo => &self.logo_file,
+            BrandImage::Favicon => &self.favicon_file,
+        };
+        let Some(file) = file else {
+            return Ok(None);
+        };
+        let data = match tokio::fs::read(file).await {
+            Ok(data) => data,
+            Err(e) => {
+                eprintln!("Error reading {}: {}", file.display(), e);
+                return Ok(None);
+            }
+        };
+        // Files come from whoever runs the wiki, so unlike uploads they can be SVGs.
+        let content_type = match file.extension().is_some_and(|ext| ext == "svg") {
+            true => Some("image/svg+xml"),
+            false => image_type(&data),
+        };
+        match content_type {
+            Some(content_type) => Ok(Some((content_type.to_owned(), data))),
+            None => {
+                eprintln!(
+                    "{} isn't a PNG, JPEG, GIF, WebP, ICO or SVG image",
+                    file.display()
+                );
+                Ok(None)
+            }
+        }
+    }
+
+    pub async fn set_brand_image(
+        &self,
+        image: BrandImage,
+        content_type: &str,
+        data: &[u8],
+    ) -> Result<()> {
+        let (key, type_key) = image.keys();
+        self.store.set(key, data).await?;
+        self.store.set(type_key, content_type.as_bytes()).await
+    }
+
+    /// The stylesheet pages load after bootstrap's, the one uploaded through the admin page if there
+    /// is one, otherwise `knowbase_CUSTOM_CSS`. The file is read every time so changes to it show
+    /// up without a restart.
+    pub async fn custom_css(&self) -> Result<Option<String>> {
+        if let Some(css) = self.store.get(CUSTOM_CSS_KEY).await? {
+            return Ok(Some(String::from_utf8_lossy(&css).into_owned()));
+        }
+
+        Ok(match &self.custom_css_file {
+            Some(file) => match tokio::fs::read_to_string(file).await {
+                Ok(css) => Some(css),
+                Err(e) => {
+                    eprintln!("Error reading {}: {}", file.display(), e);
+                    None
+                }
+            },
+            None => None,
+        })
+    }
+
+    pub async fn has_uploaded_css(&self) -> Result<bool> {
+        Ok(self.store.get(CUSTOM_CSS_KEY).await?.is_some())
+    }
+
+    /// Replaces the uploaded stylesheet, or removes it to go back to `knowbase_CUSTOM_CSS`.
+    pub async fn set_custom_css(&self, css: Option<&str>) -> Result<()> {
+        match css {
+            Some(css) => self.store.set(CUSTOM_CSS_KEY, css.as_bytes()).await,
+            None => self.store.delete(CUSTOM_CSS_KEY).await,
+        }
+    }
+
+    pub async fn get_page(&self, path: &str) -> Result<Option<Page>> {
+        self.store.get_page(path).await
+    }
+
+    /// One chunk of the content of a page too large to store inline.
+    pub async fn page_chunk(&self, path: &str, index: usize) -> Result<Vec<u8>> {
+        let chunk = self.store.get(&chunk_key(path, index)).await?;
+
+        Ok(chunk.unwrap_or_default())
+    }
+
+    /// A page's full rendered content, joining its chunks back together if it was split.
+    pub async fn page_content(&self, path: &str, page: &Page) -> Result<String> {
+        let mut content = page.content.clone();
+        for i in 0..page.chunks {
+            content.push_str(&String::from_utf8_lossy(
+                &self.page_chunk(&canonical_path(path), i).await?,
+            ));
+        }
+
+        Ok(content)
+    }
+
+    /// The path of every stored page, sorted.
+    pub async fn list_pages(&self) -> Result<Vec<String>> {
+        let mut pages = self.store.list_pages().await?;
+        pages.sort();
+
+        Ok(pages)
+    }
+
+    /// Reports how many pages are stored and roughly how many bytes are used to store them.
+    pub async fn usage(&self) -> Result<Usage> {
+        Ok(Usage {
+            pages: self.store.page_count().await?,
+            bytes: self.store.storage_bytes().await?,
+            max_pages: self.max_pages,
+            max_bytes: self.max_bytes,
+        })
+    }
+
+    pub fn max_attachment_bytes(&self) -> usize {
+        self.max_attachment_bytes
+    }
+
+    pub fn max_page_bytes(&self) -> usize {
+        self.max_page_bytes
+    }
+
+    pub fn max_upload_bytes(&self) -> usize {
+        self.max_upload_bytes
+    }
+
+    /// The bytes of the file attached at `path`, if there is one.
+    pub async fn attachment(&self, path: &str) -> Result<Option<Vec<u8>>> {
+        self.store.get(&attachment_key(&canonical_path(path))).await
+    }
+
+    /// The paths of every attachment kept in the store.
+    pub async fn attachment_paths(&self) -> Result<Vec<String>> {
+        let prefix = format!("{}:", ATTACHMENT_KEY);
+        let mut paths: Vec<String> = self
+            .store
+            .keys(&prefix)
+            .await?
+            .into_iter()
+            .filter_map(|k| k.strip_prefix(&prefix).map(str::to_owned))
+            .collect();
+        paths.sort();
+
+        Ok(paths)
+    }
+
+    /// A presigned link to download the attachment at `path` from, if attachments are kept in
+    /// a bucket rather than the store.
+    pub fn attachment_url(&self, path: &str) -> Option<String> {
+        let bucket = self.bucket.as_ref()?;
+        Some(bucket.get_url(&canonical_path(path)))
+    }
+
+    /// Stores a file that isn't a page at `path`, such as an image pages embed.
+    pub async fn set_attachment(&self, path: &str, data: &[u8]) -> Result<()> {
+        let path = canonical_path(path);
+        // Buckets have their own limits, the storage quota only covers the store.
+        if let Some(bucket) = &self.bucket {
+            return bucket
+                .put(&path, data.to_vec(), paths::content_type(&path))
+                .await;
+        }
+
+        if let Some(max_bytes) = self.max_bytes {
+            if self.usage().await?.bytes + data.len() as u64 > max_bytes {
+                return Err(Error::QuotaExceeded(format!(
+                    "the wiki is limited to {} bytes of storage",
+                    max_bytes
+                )));
+            }
+        }
+
+        self.store.set(&attachment_key(&path), data).await
+    }
+
+    /// Whether someone who hasn't logged in can download the attachment at `path`, which they
+    /// can if they could read a page that links to or embeds it.
+    pub async fn is_attachment_readable_anonymously(&self, path: &str) -> Result<bool> {
+        if !self.acl.allows(path, &Viewer::default()) {
+            return Ok(false);
+        }
+        if self.public_read
+            || self
+                .public_prefixes
+                .iter()
+                .any(|prefix| is_under(path, prefix))
+        {
+            return Ok(true);
+        }
+
+        for from in self.indexed_paths(&embedded_key(path)).await? {
+            if let Some(page) = self.store.get_page(&from).await? {
+                if self.is_readable_anonymously(&from, &page) {
+                    return Ok(true);
+                }
+            }
+        }
+
+        Ok(false)
+    }
+
+    /// Checks that replacing `old` with a page of `new_bytes` keeps the wiki within its limits.
+    async fn check_quota(&self, old: Option<&Page>, new_bytes: u64) -> Result<()> {
+        if self.max_pages.is_none() && self.max_bytes.is_none() {
+            return Ok(());
+        }
+
+        let usage = self.usage().await?;
+
+        if let Some(max_pages) = self.max_pages {
+            if old.is_none() && usage.pages >= max_pages {
+                return Err(Error::QuotaExceeded(format!(
+                    "the wiki is limited to {} pages",
+                    max_pages
+                )));
+            }
+        }
+
+        if let Some(max_bytes) = self.max_bytes {
+            let old_bytes = old.map_or(0, page_bytes);
+            if new_bytes > old_bytes && usage.bytes + (new_bytes - old_bytes) > max_bytes {
+                return Err(Error::QuotaExceeded(format!(
+                    "the wiki is limited to {} bytes of storage",
+                    max_bytes
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    pub async fn set_page(&self, path: &str, md: String) -> Result<()> {
+        self.set_page_as(path, md, None).await?;
+        Ok(())
+    }
+
+    /// Writes a page, recording who wrote it in its history. Nothing is written if the page
+    /// already has exactly this markdown.
+    pub async fn set_page_as(
+        &self,
+        path: &str,
+        md: String,
+        author: Option<&str>,
+    ) -> Result<PageWrite> {
+        let path = canonical_path(path);
+        if md.len() > self.max_page_bytes {
+            return Err(Error::TooLarge(format!(
+                "pages are limited to {} bytes of markdown",
+                self.max_page_bytes
+            )));
+        }
+        let write = match self.store.get_page(&path).await? {
+            None => PageWrite::Created,
+            Some(old) if old.hash == content_hash(&md) => return Ok(PageWrite::Unchanged),
+            Some(_) => PageWrite::Updated,
+        };
+
+        self.write_page(&path, &md, Write::Edit(author)).await?;
+        self.store.set(&source_key(&path), md.as_bytes()).await?;
+        self.search.index_page(&path, searchable_text(&md)).await?;
+        self.record_revision(&path, &md, author).await?;
+        self.record_change(&path, write == PageWrite::Created, author)
+            .await?;
+        self.refresh_includers(&path).await?;
+        Ok(write)
+    }
+
+    async fn recent_changes(&self) -> Result<Vec<PageChange>> {
+        let changes = self.store.get(RECENT_KEY).await?;
+        Ok(changes
+            .and_then(|c| serde_json::from_slice(&c).ok())
+            .unwrap_or_default())
+    }
+
+    async fn set_recent_changes(&self, changes: &[PageChange]) -> Result<()> {
+        self.store
+            .set(RECENT_KEY, &serde_json::to_vec(changes).unwrap())
+            .await
+    }
+
+    /// Puts the page at `path` at the top of the recent changes, forgetting the oldest past the
+    /// limit.
+    async fn record_change(&self, path: &str, created: bool, author: Option<&str>) -> Result<()> {
+        let _lock = self.update_lock.lock().await;
+        let mut changes = self.recent_changes().await?;
+        changes.retain(|c| c.path != path);
+        changes.insert(
+            0,
+            PageChange {
+                path: path.to_owned(),
+                timestamp: unix_time(),
+                created,
+                author: author.map(str::to_owned),
+            },
+        );
+        changes.truncate(RECENT_PAGES);
+
+        self.set_recent_changes(&changes).await
+    }
+
+    /// The pages `viewer` can see that were written most recently, newest first.
+    pub async fn recent_pages(&self, viewer: &Viewer, limit: usize) -> Result<Vec<RecentChange>> {
+        let mut results = Vec::new();
+        for change in self.recent_changes().await? {
+            if results.len() >= limit {
+                break;
+            }
+            let Some(page) = self.store.get_page(&change.path).await? else {
+                continue;
+            };
+            if !self.can_read_page(&change.path, &page, viewer) {
+                continue;
+            }
+
+            let preview = match &page.summary {
+                Some(summary) => search::snippet(summary, &[]),
+                None => search::snippet(searchable_text(&page.preview), &[]),
+            };
+            results.push(RecentChange {
+                title: page.title(&change.path),
+                url: wiki_url(&change.path),
+                preview,
+                timestamp: change.timestamp,
+                created: change.created,
+                author: change.author,
+            });
+        }
+
+        Ok(results)
+    }
+
+    /// Renders and stores a page, keeping the indexes of its tags, links and includes up to date.
+    async fn write_page(&self, path: &str, md: &str, write: Write<'_>) -> Result<()> {
+        let mut page = self.render_markdown(path, md).await?;
+        page.hash = content_hash(md);
+        let old = self.store.get_page(path).await?;
+        let kept = match write {
+            Write::Edit(author) => {
+                let now = unix_time();
+                page.created = Some(old.as_ref().and_then(|o| o.created).unwrap_or(now));
+                page.updated = Some(now);
+                page.updated_by = author.map(str::to_owned);
+                None
+            }
+            Write::Rerender => old.as_ref(),
+            Write::Move(moved) => Some(moved),
+        };
+        if let Some(kept) = kept {
+            page.created = kept.created;
+            page.updated = kept.updated;
+            page.updated_by = kept.updated_by.clone();
+        }
+        let replaced = match write {
+            Write::Move(moved) => Some(moved),
+            _ => old.as_ref(),
+        };
+        self.check_quota(replaced, page_bytes(&page)).await?;
+        let old_tags = old.as_ref().map(|o| o.tags.clone()).unwrap_or_default();
+        let old_links = old.as_ref().map(|o| o.links.clone()).unwrap_or_default();
+        let old_includes = old.as_ref().map(|o| o.includes.clone()).unwrap_or_default();
+        let old_attachments = old
+            .as_ref()
+            .map(|o| o.attachments.clone())
+            .unwrap_or_default();
+        let old_aliases = old.as_ref().map(|o| o.aliases.clone()).unwrap_or_default();
+
+        if page.content.len() > self.stream_threshold {
+            let content = std::mem::take(&mut page.content);
+            let chunks: Vec<&[u8]> = content.as_bytes().chunks(CHUNK_BYTES).collect();
+            page.chunks = chunks.len();
+            for (i, chunk) in chunks.into_iter().enumerate() {
+                self.store.set(&chunk_key(path, i), chunk).await?;
+            }
+        }
+
+        self.store.set_page(path, &page).await?;
+        self.nav.invalidate();
+        self.update_index(tag_key, path, &old_tags, &page.tags)
+            .await?;
+        self.update_index(backlinks_key, path, &old_links, &page.links)
+            .await?;
+        self.update_index(included_key, path, &old_includes, &page.includes)
+            .await?;
+        self.update_index(embedded_key, path, &old_attachments, &page.attachments)
+            .await?;
+        self.update_index(alias_key, path, &old_aliases, &page.aliases)
+            .await?;
+
+        // Only clean up chunks the new page didn't overwrite once it's safely stored.
+        for i in page.chunks..old.map_or(0, |o| o.chunks) {
+            self.store.delete(&chunk_key(path, i)).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Re-renders the pages that include the page at `path`, so they show what it says now.
+    async fn refresh_includers(&self, path: &str) -> Result<()> {
+        for includer in self.indexed_paths(&included_key(path)).await? {
+            if let Some(md) = self.page_markdown(&includer).await? {
+                self.write_page(&includer, &md, Write::Rerender).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Replaces every `{{include: page.md}}` outside of code with the markdown of the page it
+    /// names, expanding the includes in that too. `stack` holds the page being rendered and the
+    /// pages being included into it, so a page that ends up including itself is caught.
+    fn expand_includes<'a>(
+        &'a self,
+        md: &'a str,
+        stack: &'a mut Vec<String>,
+        page: &'a mut Page,
+    ) -> BoxFuture<'a, Result<String>> {
+        async move {
+            let mut out = String::with_capacity(md.len());
+            let mut fence: Option<String> = None;
+            for line in md.split_inclusive('\n') {
+                if let Some(open) = &fence {
+                    if math::closing_fence(line, open) {
+                        fence = None;
+                    }
+                    out.push_str(line);
+                    continue;
+                }
+                if let Some((open, _)) = math::opening_fence(line) {
+                    fence = Some(open);
+                    out.push_str(line);
+                    continue;
+                }
+
+                let mut copied = 0;
+                for directive in INCLUDE_RE.captures_iter(line) {
+                    let whole = directive.get(0).unwrap();
+                    out.push_str(&line[copied..whole.start()]);
+                    copied = whole.end();
+
+                    let from = stack.last().unwrap();
+                    let mut target = resolve_path(&directive[1], from);
+                    if !target.ends_with(".md") {
+                        target.push_str(".md");
+                    }
+                    // Kept even when there's nothing there yet, so creating it fills the gap.
+                    if !page.includes.contains(&target) {
+                        page.includes.push(target.clone());
+                    }
+                    let included = self.store.get_page(&target).await?;
+                    let problem = match &included {
+                        _ if stack.contains(&target) => Some("it includes this page"),
+                        None => Some("there's no page there"),
+                        // Pages can't be used to show what's hidden to people who can't see it.
+                        _ if !self.acl.no_stricter(&target, &stack[0]) => {
+                            Some("fewer people can see it than this page")
+                        }
+                        Some(included)
+                            if included.visibility == Visibility::Private
+                                && page.visibility != Visibility::Private =>
+                        {
+                            Some("it's private")
+                        }
+                        Some(included)
+                            if self.is_readable_anonymously(&stack[0], page)
+                                && !self.is_readable_anonymously(&target, included) =>
+                        {
+                            Some("it can't be read without logging in")
+                        }
+                        _ => None,
+                    };
+                    if let Some(problem) = problem {
+                        out.push_str(&format!("**Can't include `{}`, {}**", target, problem));
+                        continue;
+                    }
+
+                    let mut md = self.page_markdown(&target).await?.unwrap_or_default();
+                    if let Some(front_matter) = FRONT_MATTER_RE.find(&md) {
+                        md.replace_range(front_matter.range(), "");
+                    }
+                    if let Some(index) = INDEX_RE.find(&md) {
+                        md.replace_range(index.range(), "");
+                    }
+
+                    stack.push(target);
+                    out.push_str(self.expand_includes(&md, stack, page).await?.trim_end());
+                    stack.pop();
+                }
+                out.push_str(&line[copied..]);
+            }
+
+            Ok(out)
+        }
+        .boxed()
+    }
+
+    /// The markdown a page was last written with, falling back to its newest revision
+    /// for pages written before sources were kept.
+    pub async fn page_markdown(&self, path: &str) -> Result<Option<String>> {
+        let path = canonical_path(path);
+        if let Some(md) = self.store.get(&source_key(&path)).await? {
+            return Ok(Some(String::from_utf8_lossy(&md).into_owned()));
+        }
+
+        match self.revisions(&path).await?.first() {
+            Some(revision) => self.revision_markdown(&path, revision.id).await,
+            None => Ok(None),
+        }
+    }
+
+    /// Every stored revision of a page, newest first.
+    pub async fn revisions(&self, path: &str) -> Result<Vec<Revision>> {
+        let revisions = self
+            .store
+            .get(&format!("{}:{}", REVISIONS_KEY, canonical_path(path)))
+            .await?;
+
+        Ok(revisions
+            .map(|r| serde_json::from_slice(&r).unwrap())
+            .unwrap_or_default())
+    }
+
+    /// The markdown a page had at a revision, if that revision is still stored.
+    pub async fn revision_markdown(&self, path: &str, id: u64) -> Result<Option<String>> {
+        let md = self
+            .store
+            .get(&revision_key(&canonical_path(path), id))
+            .await?;
+
+        Ok(md.map(|md| String::from_utf8_lossy(&md).into_owned()))
+    }
+
+    /// Keeps the markdown just written to a page, forgetting the oldest revisions past the limit.
+    async fn record_revision(&self, path: &str, md: &str, author: Option<&str>) -> Result<()> {
+        if self.max_revisions == 0 {
+            return Ok(());
+        }
+
+        let _lock = self.update_lock.lock().await;
+        let mut revisions = self.revisions(path).await?;
+        let revision = Revision {
+            id: revisions.first().map_or(1, |r| r.id + 1),
+            timestamp: unix_time(),
+            author: author.map(str::to_owned),
+        };
+        self.store
+            .set(&revision_key(path, revision.id), md.as_bytes())
+            .await?;
+        revisions.insert(0, revision);
+
+        for old in revisions.split_off(self.max_revisions.min(revisions.len())) {
+            self.store.delete(&revision_key(path, old.id)).await?;
+        }
+
+        self.store
+            .set(
+                &format!("{}:{}", REVISIONS_KEY, path),
+                &serde_json::to_vec(&revisions).unwrap(),
+            )
+            .await
+    }
+
+    pub async fn delete_page(&self, path: &str) -> Result<()> {
+        let path = canonical_path(path);
+        let Some(page) = self.store.get_page(&path).await? else {
+            return Ok(());
+        };
+
+        self.store.delete_page(&path).await?;
+        self.nav.invalidate();
+        self.store.delete(&source_key(&path)).await?;
+        {
+            let _lock = self.update_lock.lock().await;
+            let mut changes = self.recent_changes().await?;
+            if changes.iter().any(|c| c.path == path) {
+                changes.retain(|c| c.path != path);
+                self.set_recent_changes(&changes).await?;
+            }
+        }
+        self.search.remove_page(&path).await?;
+        self.update_index(tag_key, &path, &page.tags, &[]).await?;
+        self.update_index(backlinks_key, &path, &page.links, &[])
+            .await?;
+        self.update_index(included_key, &path, &page.includes, &[])
+            .await?;
+        self.update_index(embedded_key, &path, &page.attachments, &[])
+            .await?;
+        self.update_index(alias_key, &path, &page.aliases, &[])
+            .await?;
+        for i in 0..page.chunks {
+            self.store.delete(&chunk_key(&path, i)).await?;
+        }
+
+        self.refresh_includers(&path).await
+    }
+
+    /// Deletes a page, keeping its markdown in the trash when it's known.
+    /// Returns false if there was no page to delete.
+    pub async fn trash_page(&self, path: &str) -> Result<bool> {
+        let path = canonical_path(path);
+        if self.store.get_page(&path).await?.is_none() {
+            return Ok(false);
+        }
+
+        if let Some(md) = self.page_markdown(&path).await? {
+            self.store
+                .set(&format!("{}:{}", TRASHED_KEY, path), md.as_bytes())
+                .await?;
+
+            let mut trash = self.trash().await?;
+            trash.retain(|t| t.path != path);
+            trash.insert(
+                0,
+                TrashedPage {
+                    path: path.clone(),
+                    timestamp: unix_time(),
+                },
+            );
+            self.set_trash(&trash).await?;
+        }
+
+        self.delete_page(&path).await?;
+        Ok(true)
+    }
+
+    /// Moves a page and its history to a new path, leaving a redirect behind at the old one.
+    pub async fn move_page(&self, from: &str, to: &str) -> Result<()> {
+        let (from, to) = (canonical_path(from), canonical_path(to));
+        let Some(page) = self.store.get_page(&from).await? else {
+            return Ok(());
+        };
+        let md = self.page_markdown(&from).await?;
+
+        // The history only follows the page once it's safely at its new path, so a move that
+        // fails leaves the page as it was.
+        match &md {
+            Some(md) => {
+                self.write_page(&to, md, Write::Move(&page)).await?;
+                self.store.set(&source_key(&to), md.as_bytes()).await?;
+                self.search.index_page(&to, searchable_text(md)).await?;
+            }
+            None => {
+                // Without the markdown the rendered page and its chunks are copied as they are.
+                self.store.set_page(&to, &page).await?;
+                self.nav.invalidate();
+                self.search.index_page(&to, &page.preview).await?;
+                self.update_index(tag_key, &to, &[], &page.tags).await?;
+                self.update_index(backlinks_key, &to, &[], &page.links)
+                    .await?;
+                self.update_index(included_key, &to, &[], &page.includes)
+                    .await?;
+                self.update_index(embedded_key, &to, &[], &page.attachments)
+                    .await?;
+                self.update_index(alias_key, &to, &[], &page.aliases)
+                    .await?;
+                for i in 0..page.chunks {
+                    let chunk = self.page_chunk(&from, i).await?;
+                    self.store.set(&chunk_key(&to, i), &chunk).await?;
+                }
+            }
+        }
+
+        let revisions = self.revisions(&from).await?;
+        for revision in &revisions {
+            if let Some(md) = self.store.get(&revision_key(&from, revision.id)).await? {
+                self.store.set(&revision_key(&to, revision.id), &md).await?;
+                self.store.delete(&revision_key(&from, revision.id)).await?;
+            }
+        }
+        self.store
+            .set(
+                &format!("{}:{}", REVISIONS_KEY, to),
+                &serde_json::to_vec(&revisions).unwrap(),
+            )
+            .await?;
+        self.store
+            .delete(&format!("{}:{}", REVISIONS_KEY, from))
+            .await?;
+
+        if md.is_some() {
+            self.record_change(&to, true, None).await?;
+            self.refresh_includers(&to).await?;
+        }
+        self.delete_page(&from).await?;
+        self.store
+            .set(&format!("{}:{}", REDIRECT_KEY, from), to.as_bytes())
+            .await
+    }
+
+    /// Every page with links to pages that don't exist, with the paths it links to, sorted by
+    /// path. Links to pages that were moved still work, so they don't count.
+    pub async fn broken_links(&self) -> Result<Vec<(String, Vec<String>)>> {
+        let prefix = format!("{}:", BACKLINKS_KEY);
+        let mut broken: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        for key in self.store.keys(&prefix).await? {
+            let target = &key[prefix.len()..];
+            if self.store.get_page(target).await?.is_some()
+                || self.redirect(target).await?.is_some()
+            {
+                continue;
+            }
+
+            for from in self.indexed_paths(&key).await? {
+                broken.entry(from).or_default().push(target.to_owned());
+            }
+        }
+
+        Ok(broken
+            .into_iter()
+            .map(|(from, mut targets)| {
+                targets.sort();
+                (from, targets)
+            })
+            .collect())
+    }
+
+    /// The paths of every page with `tag`, sorted.
+    pub async fn tagged(&self, tag: &str) -> Result<Vec<String>> {
+        self.indexed_paths(&tag_key(tag)).await
+    }
+
+    /// The pages linking to the page at `path` that `viewer` can see, by title.
+    pub async fn backlinks(&self, path: &str, viewer: &Viewer) -> Result<Vec<PageLink>> {
+        let mut links = Vec::new();
+        for from in self.indexed_paths(&backlinks_key(path)).await? {
+            let Some(page) = self.store.get_page(&from).await? else {
+                continue;
+            };
+            if !self.can_read_page(&from, &page, viewer) {
+                continue;
+            }
+
+            links.push(PageLink {
+                title: page.title(&from),
+                url: wiki_url(&from),
+            });
+        }
+
+        Ok(links)
+    }
+
+    /// Every tag carried by a page `viewer` can see, with how many of those pages carry it.
+    pub async fn tags(&self, viewer: &Viewer) -> Result<Vec<(String, usize)>> {
+        let prefix = format!("{}:", TAG_KEY);
+        let mut tags = Vec::new();
+        for key in self.store.keys(&prefix).await? {
+            let tag = &key[prefix.len()..];
+            let count = self.tagged_pages(tag, viewer).await?.len();
+            if count > 0 {
+                tags.push((tag.to_owned(), count));
+            }
+        }
+        tags.sort();
+
+        Ok(tags)
+    }
+
+    /// The pages with `tag` that `viewer` can see, previewed like search results.
+    pub async fn tagged_pages(&self, tag: &str, viewer: &Viewer) -> Result<Vec<SearchResult>> {
+        let mut results = Vec::new();
+        for path in self.tagged(tag).await? {
+            let Some(page) = self.store.get_page(&path).await? else {
+                continue;
+            };
+            if !self.can_read_page(&path, &page, viewer) {
+                continue;
+            }
+
+            let preview = match &page.summary {
+                Some(summary) => search::snippet(summary, &[]),
+                None => search::snippet(searchable_text(&page.preview), &[]),
+            };
+            results.push(SearchResult {
+                title: page.title(&path),
+                url: wiki_url(&path),
+                preview,
+            });
+        }
+
+        Ok(results)
+    }
+
+    /// The sorted page paths kept under `key` by one of the reverse indexes.
+    async fn indexed_paths(&self, key: &str) -> Result<Vec<String>> {
+        let paths = self.store.get(key).await?;
+
+        Ok(paths
+            .map(|p| serde_json::from_slice(&p).unwrap())
+            .unwrap_or_default())
+    }
+
+    /// Moves the page at `path` from the index entries for the `old` values it had, such as its
+    /// tags, to the entries for the `new` ones. `key` is where each value's entry is kept.
+    async fn update_index(
+        &self,
+        key: fn(&str) -> String,
+        path: &str,
+        old: &[String],
+        new: &[String],
+    ) -> Result<()> {
+        let _lock = self.update_lock.lock().await;
+        for value in old.iter().filter(|v| !new.contains(v)) {
+            let mut paths = self.indexed_paths(&key(value)).await?;
+            paths.retain(|p| p != path);
+            if paths.is_empty() {
+                self.store.delete(&key(value)).await?;
+            } else {
+                self.set_indexed_paths(&key(value), &paths).await?;
+            }
+        }
+
+        for value in new.iter().filter(|v| !old.contains(v)) {
+            let mut paths = self.indexed_paths(&key(value)).await?;
+            if let Err(i) = paths.binary_search_by(|p| p.as_str().cmp(path)) {
+                paths.insert(i, path.to_owned());
+                self.set_indexed_paths(&key(value), &paths).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn set_indexed_paths(&self, key: &str, paths: &[String]) -> Result<()> {
+        self.store
+            .set(key, &serde_json::to_vec(paths).unwrap())
+            .await
+    }
+
+    /// Where the page with `path` as an alias is, or where a page that was moved away from it can
+    /// now be found.
+    pub async fn redirect(&self, path: &str) -> Result<Option<String>> {
+        let path = canonical_path(path);
+        if let Some(page) = self
+            .indexed_paths(&alias_key(&path))
+            .await?
+            .into_iter()
+            .next()
+        {
+            return Ok(Some(page));
+        }
+
+        let to = self
+            .store
+            .get(&format!("{}:{}", REDIRECT_KEY, path))
+            .await?;
+
+        Ok(to.map(|to| String::from_utf8_lossy(&to).into_owned()))
+    }
+
+    /// Creates a new API token, returning it. It can't be recovered after this.
+    pub async fn create_token(&self, name: &str) -> Result<String> {
+        let token = format!("kb_{}", hex(&rand::random::<[u8; 32]>()));
+        let hash = token_hash(&token);
+
+        self.store
+            .set(&format!("{}:{}", TOKEN_KEY, hash), name.as_bytes())
+            .await?;
+        let mut tokens = self.tokens().await?;
+        tokens.push(ApiToken {
+            hash,
+            name: name.to_owned(),
+            timestamp: unix_time(),
+        });
+        self.set_tokens(&tokens).await?;
+
+        Ok(token)
+    }
+
+    /// Every API token that hasn't been revoked, oldest first.
+    pub async fn tokens(&self) -> Result<Vec<ApiToken>> {
+        let tokens = self.store.get(TOKENS_KEY).await?;
+
+        Ok(tokens
+            .map(|t| serde_json::from_slice(&t).unwrap())
+            .unwrap_or_default())
+    }
+
+    pub async fn revoke_token(&self, hash: &str) -> Result<()> {
+        self.store
+            .delete(&format!("{}:{}", TOKEN_KEY, hash))
+            .await?;
+        let mut tokens = self.tokens().await?;
+        tokens.retain(|t| t.hash != hash);
+        self.set_tokens(&tokens).await
+    }
+
+    /// Whether a bearer token was created here and hasn't been revoked.
+    pub async fn check_token(&self, token: &str) -> Result<bool> {
+        Ok(self.token_name(token).await?.is_some())
+    }
+
+    /// The name a token was given when it was created, if it hasn't been revoked.
+    pub async fn token_name(&self, token: &str) -> Result<Option<String>> {
+        let name = self
+            .store
+            .get(&format!("{}:{}", TOKEN_KEY, token_hash(token)))
+            .await?;
+
+        Ok(name.map(|n| String::from_utf8_lossy(&n).into_owned()))
+    }
+
+    async fn set_tokens(&self, tokens: &[ApiToken]) -> Result<()> {
+        self.store
+            .set(TOKENS_KEY, &serde_json::to_vec(tokens).unwrap())
+            .await
+    }
+
+    /// Every page in the trash, most recently deleted first.
+    pub async fn trash(&self) -> Result<Vec<TrashedPage>> {
+        let trash = self.store.get(TRASH_KEY).await?;
+
+        Ok(trash
+            .map(|t| serde_json::from_slice(&t).unwrap())
+            .unwrap_or_default())
+    }
+
+    /// Writes a trashed page back to its path and takes it out of the trash.
+    /// Returns false if the page isn't in the trash.
+    pub async fn restore_trashed(&self, path: &str) -> Result<bool> {
+        let path = canonical_path(path);
+        let key = format!("{}:{}", TRASHED_KEY, path);
+        let Some(md) = self.store.get(&key).await? else {
+            return Ok(false);
+        };
+
+        self.set_page(&path, String::from_utf8_lossy(&md).into_owned())
+            .await?;
+        self.store.delete(&key).await?;
+
+        let mut trash = self.trash().await?;
+        trash.retain(|t| t.path != path);
+        self.set_trash(&trash).await?;
+
+        Ok(true)
+    }
+
+    async fn set_trash(&self, trash: &[TrashedPage]) -> Result<()> {
+        self.store
+            .set(TRASH_KEY, &serde_json::to_vec(trash).unwrap())
+            .await
+    }
+
+    /// Moves any pages stored before paths were canonicalized to their canonical path,
+    /// without overwriting a page already stored there.
+    pub async fn canonicalize_pages(&self) -> Result<()> {
+        for path in self.store.list_pages().await? {
+            let canonical = canonical_path(&path);
+            if canonical == path {
+                continue;
+            }
+
+            if let Some(page) = self.store.get_page(&path).await? {
+                if self.store.get_page(&canonical).await?.is_none() {
+                    self.store.set_page(&canonical, &page).await?;
+                }
+            }
+            self.store.delete_page(&path).await?;
+        }
+        self.nav.invalidate();
+
+        Ok(())
+    }
+
+    /// Indexes every stored page for search if the index is empty, such as on the first start
+    /// after upgrading or switching indexes.
+    pub async fn build_search_index(&self) -> Result<()> {
+        if !self.search.is_empty().await? {
+            return Ok(());
+        }
+
+        for path in self.store.list_pages().await? {
+            self.index_stored_page(&path).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Indexes the page stored at `path` for search from its markdown.
+    async fn index_stored_page(&self, path: &str) -> Result<()> {
+        match self.page_markdown(path).await? {
+            Some(md) => self.search.index_page(path, searchable_text(&md)).await,
+            // Pages stored before their markdown was kept can at least be found by their preview.
+            None => match self.store.get_page(path).await? {
+                Some(page) => self.search.index_page(path, &page.preview).await,
+                None => Ok(()),
+            },
+        }
+    }
+
+    /// Everything in the store, for moving the wiki to another one. Values that are forgotten
+    /// after a while anyway, like server sessions and failed logins, are left out.
+    pub async fn backup(&self) -> Result<Backup> {
+        let mut backup = Backup::default();
+        for path in self.store.list_pages().await? {
+            if let Some(page) = self.store.get_page(&path).await? {
+                backup.pages.push((path, page));
+            }
+        }
+        for key in self.store.value_keys().await? {
+            if self.store.ttl(&key).await?.is_some() {
+                continue;
+            }
+            if let Some(value) = self.store.get(&key).await? {
+                backup.values.push((key, value));
+            }
+        }
+
+        Ok(backup)
+    }
+
+    /// Replaces everything in the store with what's in `backup`, and indexes the pages from it
+    /// for search.
+    pub async fn restore_backup(&self, backup: &Backup) -> Result<()> {
+        for path in self.store.list_pages().await? {
+            self.store.delete_page(&path).await?;
+            self.search.remove_page(&path).await?;
+        }
+        for key in self.store.value_keys().await? {
+            if self.store.ttl(&key).await?.is_none() {
+                self.store.delete(&key).await?;
+            }
+        }
+
+        for (key, value) in &backup.values {
+            self.store.set(key, value).await?;
+        }
+        for (path, page) in &backup.pages {
+            self.store.set_page(path, page).await?;
+            self.index_stored_page(path).await?;
+        }
+        self.nav.invalidate();
+
+        Ok(())
+    }
+
+    /// Renders every page again if the options they're rendered with changed since the wiki last
+    /// started, so pages written before the change match the ones written after.
+    pub async fn rerender_pages(&self) -> Result<()> {
+        let rendered = self.store.get(RENDER_OPTIONS_KEY).await?;
+        if rendered.as_deref() == Some(self.render_options.as_bytes()) {
+            return Ok(());
+        }
+
+        for path in self.store.list_pages().await? {
+            let Some(md) = self.page_markdown(&path).await? else {
+                continue;
+            };
+            match self.write_page(&path, &md, Write::Rerender).await {
+                Ok(()) => {}
+                // The page keeps how it was rendered before rather than stopping the wiki starting.
+                Err(e @ Error::QuotaExceeded(_)) => eprintln!("Error re-rendering {}: {}", path, e),
+                Err(e) => return Err(e),
+            }
+        }
+
+        self.store
+            .set(RENDER_OPTIONS_KEY, self.render_options.as_bytes())
+            .await
+    }
+
+    /// The commit of the synced git repository pages were last imported from.
+    pub async fn synced_commit(&self) -> Result<Option<String>> {
+        let commit = self.store.get(GIT_COMMIT_KEY).await?;
+
+        Ok(commit.map(|c| String::from_utf8_lossy(&c).into_owned()))
+    }
+
+    pub async fn set_synced_commit(&self, commit: &str) -> Result<()> {
+        self.store.set(GIT_COMMIT_KEY, commit.as_bytes()).await
+    }
+
+    /// Renders markdown into a page exactly as `set_page` would store it, without storing anything.
+    pub async fn render_markdown(&self, path: &str, md: &str) -> Result<Page> {
+        let mut md = md.to_owned();
+        let mut show_toc = false;
+        let arena = Arena::new();
+        let opts = &self.markdown;
+
+        let mut page = Page::default();
+
+        if let Some(front_matter) = FRONT_MATTER_RE.captures(&md) {
+            let meta: FrontMatter =
+                serde_yaml::from_str(front_matter.get(1).unwrap().as_str()).unwrap_or_default();
+            page.visibility = meta.visibility;
+            page.public = meta.public;
+            page.tags = meta.tags.normalize();
+            page.aliases = meta.aliases.page_paths(path);
+            page.title = meta.title.filter(|t| !t.trim().is_empty());
+            page.summary = meta.summary.filter(|s| !s.trim().is_empty());
+            page.date = meta.date.filter(|d| !d.trim().is_empty());
+            show_toc = meta.toc;
+            md.replace_range(front_matter.get(0).unwrap().range(), "");
+        }
+
+        if let Some(index_match) = INDEX_RE.captures(&md) {
+            page.index.push_str(&comrak::markdown_to_html(
+                index_match.get(1).unwrap().as_str(),
+                opts,
+            ));
+            md.replace_range(index_match.get(0).unwrap().range(), "");
+        }
+
+        if INCLUDE_RE.is_match(&md) {
+            let mut stack = vec![path.to_owned()];
+            md = self.expand_includes(&md, &mut stack, &mut page).await?;
+        }
+
+        let (source, formulas) = math::extract(&md);
+        let root = comrak::parse_document(&arena, &source, opts);
+        iter_md_nodes(root, &|n| {
+            let mut ast = n.data.borrow_mut();
+            let (link, image) = match &mut ast.value {
+                NodeValue::Link(link) => (link, false),
+                NodeValue::Image(link) => (link, true),
+                _ => return,
+            };
+
+            if let Some(url) = attachment_link(&link.url, path) {
+                link.url = url;
+            } else if !image && link.url.starts_with('/') {
+                link.url = wiki_link(&link.url);
+            }
+        });
+        let mut new_page_links = Vec::new();
+        if md.contains("[[") {
+            let pages = self.store.list_pages().await?;
+            new_page_links = link_wikilinks(&arena, root, path, &pages);
+        }
+        page.links = linked_pages(root, path);
+        page.attachments = linked_attachments(root);
+        let diagrams = take_diagrams(&arena, root);
+        page.diagrams = !diagrams.is_empty();
+        let mut text = String::new();
+        collect_text(root, &mut text);
+        // Each diagram left a marker behind, which isn't something anyone reads.
+        page.words = text.split_whitespace().count() - diagrams.len();
+        let toc = toc_html(root);
+        let toc_markers = mark_toc(&arena, root);
+        let callouts = mark_callouts(&arena, root);
+        let new_pages = mark_new_page_links(&arena, &new_page_links);
+        if show_toc {
+            page.toc = toc.clone();
+        }
+
+        page.preview = preview_text(&md, opts);
+
+        let mut plugins = ComrakPlugins::default();
+        if let Some(highlighter) = &self.highlighter {
+            plugins.render.codefence_syntax_highlighter = Some(highlighter.as_ref());
+        }
+
+        let mut html = Vec::new();
+        comrak::format_html_with_plugins(root, opts, &mut html, &plugins).unwrap();
+        let mut html = math::restore(&String::from_utf8(html).unwrap(), &formulas);
+        if toc_markers {
+            html = html.replace(
+                &format!("<p>{}</p>", TOC_MARKER),
+                &format!("<nav class=\"toc\">{}</nav>", toc),
+            );
+        }
+        for (i, diagram) in diagrams.iter().enumerate() {
+            html = html.replace(
+                &format!("<p>{}</p>", diagram_marker(i)),
+                &format!(
+                    "<pre class=\"mermaid\">{}</pre>",
+                    search::escape_html(diagram)
+                ),
+            );
+        }
+        for (i, kind) in callouts.iter().enumerate() {
+            html = html.replace(
+                &format!("<blockquote>\n<p>{}", callout_marker(i)),
+                &format!(
+                    "<blockquote class=\"callout callout-{}\">\n<p class=\"callout-title\">",
+                    kind
+                ),
+            );
+        }
+        for (i, (url, label)) in new_pages.iter().enumerate() {
+            html = html.replace(
+                &new_page_marker(i),
+                &format!(
+                    "<a class=\"new-page\" href=\"{}\">{}</a>",
+                    search::escape_html(url),
+                    search::escape_html(label)
+                ),
+            );
+        }
+        page.content.push_str(&html);
+
+        Ok(page)
+    }
+
+    /// Finds pages whose path or content matches `search`, leaving out pages `viewer` can't see.
+    /// Pages whose path matches are ranked above those that only mention it. Only pages under
+    /// `scope` are searched, an empty scope searches everything.
+    ///
+    /// Returns at most `limit` results starting `offset` into the ranking, and how many there are in total.
+    pub async fn run_search(
+        &self,
+        search: &str,
+        scope: &str,
+        viewer: &Viewer,
+        offset: usize,
+        limit: usize,
+    ) -> Result<(Vec<SearchResult>, usize)> {
+        let mut query = Query::parse(search);
+        if query.is_empty() {
+            return Ok((Vec::new(), 0));
+        }
+        let scope = canonical_path(scope);
+        if !scope.is_empty() {
+            query.paths.push(scope);
+        }
+        let search = query.text();
+
+        let mut scores: HashMap<String, f32> = HashMap::new();
+        let mut pages: HashMap<String, Page> = HashMap::new();
+        if search.is_empty() {
+            // Only filters were given, so every page they let through is a result.
+            let paths = match query.tags.first() {
+                Some(tag) => self.tagged(tag).await?,
+                None => self.store.list_pages().await?,
+            };
+            scores.extend(paths.into_iter().map(|path| (path, 0.0)));
+        } else {
+            for (path, page) in self.store.find_pages(&search).await? {
+                let title = page_title(&path);
+                scores.insert(
+                    path.clone(),
+                    TITLE_MATCH_SCORE + strsim::jaro_winkler(&title, &search) as f32,
+                );
+                pages.insert(path, page);
+            }
+            for (path, score) in self.search.search(&query.index_query()).await? {
+                *scores.entry(path).or_default() += score;
+            }
+        }
+
+        let terms: Vec<String> = search::tokenize(&search).collect();
+        let mut ranked: Vec<(String, f32)> = scores.into_iter().collect();
+        // Ties are broken by path so pages of results don't overlap.
+        ranked.sort_by(|a, b| b.1.total_cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+
+        let mut visible = Vec::new();
+        for (path, _) in ranked {
+            let page = match pages.remove(&path) {
+                Some(page) => page,
+                None => match self.store.get_page(&path).await? {
+                    Some(page) => page,
+                    None => continue,
+                },
+            };
+
+            if !self.can_read_page(&path, &page, viewer) || !query.matches_page(&path, &page.tags) {
+                continue;
+            }
+            if query.needs_text() {
+                let matches = match self.page_markdown(&path).await? {
+                    Some(md) => query.matches_text(searchable_text(&md)),
+                    None => query.matches_text(&page.preview),
+                };
+                if !matches {
+                    continue;
+                }
+            }
+
+            visible.push((path, page));
+        }
+
+        // Snippets need each page's markdown, so they're only made for the results being returned.
+        let mut results = Vec::new();
+        for (path, page) in visible.iter().skip(offset).take(limit) {
+            let preview = match self.page_markdown(path).await? {
+                Some(md) => {
+                    search::snippet(&plain_text(searchable_text(&md), &self.markdown), &terms)
+                }
+                None => search::snippet(&page.preview, &terms),
+            };
+            results.push(SearchResult {
+                title: page.title(path),
+                url: wiki_url(path),
+                preview,
+            });
+        }
+
+        Ok((results, visible.len()))
+    }
+}
+
+/// Why a username can't be used for a new account, if it can't.
+pub fn username_error(username: &str) -> Option<&'static str> {
+    if username.is_empty() {
+        Some("A user needs a username")
+    } else if !username
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+    {
+        Some("Usernames can only contain letters, numbers, '-', '_' and '.'")
+    } else {
+        None
+    }
+}
+
+fn totp_key(user: Option<&str>) -> String {
+    match user {
+        Some(user) => format!("{}:user:{}", TOTP_KEY, user),
+        None => format!("{}:access_code", TOTP_KEY),
+    }
+}
+
+/// Whether two secrets are the same. Their digests are compared so the time taken doesn't give
+/// away how much of a secret was right, or how long it is.
+pub fn constant_time_eq(a: &str, b: &str) -> bool {
+    Sha256::digest(a.as_bytes())
+        .iter()
+        .zip(Sha256::digest(b.as_bytes()).iter())
+        .fold(0, |acc, (a, b)| acc | (a ^ b))
+        == 0
+}
+
+fn token_hash(token: &str) -> String {
+    hex(&Sha256::digest(token.as_bytes()))
+}
+
+fn content_hash(md: &str) -> String {
+    hex(&Sha256::digest(md.as_bytes()))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// How a page is titled in search results, from its file name.
+fn page_title(path: &str) -> String {
+    path.split('/')
+        .next_back()
+        .unwrap_or(path)
+        .trim_end_matches(".md")
+        .replace('-', " ")
+}
+
+/// The part of a page's markdown worth searching, without its front matter.
+fn searchable_text(md: &str) -> &str {
+    match FRONT_MATTER_RE.find(md) {
+        Some(front_matter) => &md[front_matter.end()..],
+        None => md,
+    }
+}
+
+/// The markdown options pages are rendered with, from `knowbase_MARKDOWN_EXTENSIONS`,
+/// `knowbase_HARD_BREAKS` and `knowbase_SMART_PUNCTUATION`.
+fn markdown_options() -> ComrakOptions {
+    let mut extension = ComrakExtensionOptions {
+        // Headings always get ids, the table of contents links to them.
+        header_ids: Some(String::new()),
+        ..Default::default()
+    };
+    let extensions = config::var("knowbase_MARKDOWN_EXTENSIONS")
+        .unwrap_or_else(|_| DEFAULT_MARKDOWN_EXTENSIONS.to_owned());
+    for name in extensions
+        .split(',')
+        .map(str::trim)
+        .filter(|n| !n.is_empty())
+    {
+        let enabled = match name {
+            "strikethrough" => &mut extension.strikethrough,
+            "tagfilter" => &mut extension.tagfilter,
+            "table" => &mut extension.table,
+            "autolink" => &mut extension.autolink,
+            "tasklist" => &mut extension.tasklist,
+            "superscript" => &mut extension.superscript,
+            "footnotes" => &mut extension.footnotes,
+            "description_lists" => &mut extension.description_lists,
+            "shortcodes" => &mut extension.shortcodes,
+            other => panic!(
+                "knowbase_MARKDOWN_EXTENSIONS should only list strikethrough, tagfilter, table, \
+                 autolink, tasklist, superscript, footnotes, description_lists or shortcodes, not {}",
+                other
+            ),
+        };
+        *enabled = true;
+    }
+
+    ComrakOptions {
+        extension,
+        parse: ComrakParseOptions {
+            smart: config::var_or("knowbase_SMART_PUNCTUATION", false),
+            ..Default::default()
+        },
+        render: ComrakRenderOptions {
+            hardbreaks: config::var_or("knowbase_HARD_BREAKS", true),
+            ..Default::default()
+        },
+    }
+}
+
+/// The words of `node` and everything in it without any markdown syntax, with blocks and line
+/// breaks kept apart by spaces.
+fn collect_text<'a>(node: &'a AstNode<'a>, text: &mut String) {
+    match &node.data.borrow().value {
+        NodeValue::Text(t) => text.push_str(t),
+        NodeValue::Code(NodeCode { literal, .. }) => text.push_str(literal),
+        NodeValue::CodeBlock(block) => text.push_str(&block.literal),
+        NodeValue::SoftBreak | NodeValue::LineBreak => text.push(' '),
+        NodeValue::HtmlBlock(_) | NodeValue::HtmlInline(_) => {}
+        value => {
+            let block = value.block();
+            for child in node.children() {
+                collect_text(child, text);
+            }
+            if block && !text.ends_with(' ') {
+                text.push(' ');
+            }
+        }
+    }
+}
+
+/// Tidies text pulled out of markdown, turning wikilinks into their labels and squashing runs
+/// of whitespace.
+fn tidy_text(text: &str) -> String {
+    let text = WIKILINK_RE.replace_all(text, |caps: &regex::Captures| {
+        caps.get(2)
+            .unwrap_or_else(|| caps.get(1).unwrap())
+            .as_str()
+            .to_owned()
+    });
+
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// The text of some markdown without its syntax, for search result snippets.
+fn plain_text(md: &str, opts: &ComrakOptions) -> String {
+    let arena = Arena::new();
+    let root = comrak::parse_document(&arena, md, opts);
+    let mut text = String::new();
+    collect_text(root, &mut text);
+
+    tidy_text(&text)
+}
+
+/// The first paragraph of a page as plain text, cut down to about 500 bytes, to preview the page
+/// where it's listed. Falls back to the start of the page's text if it has no paragraphs.
+fn preview_text(md: &str, opts: &ComrakOptions) -> String {
+    let arena = Arena::new();
+    let root = comrak::parse_document(&arena, md, opts);
+    let paragraph = root
+        .descendants()
+        .filter(|n| matches!(n.data.borrow().value, NodeValue::Paragraph))
+        .map(|n| {
+            let mut text = String::new();
+            collect_text(n, &mut text);
+            tidy_text(CALLOUT_RE.replace(text.trim_start(), "").as_ref())
+        })
+        .find(|text| !text.is_empty() && text != "[TOC]");
+
+    let mut text = paragraph.unwrap_or_else(|| {
+        let mut text = String::new();
+        collect_text(root, &mut text);
+        tidy_text(&text)
+    });
+    if text.len() > 500 {
+        let mut end = 500;
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+        let end = text[..end].rfind(' ').unwrap_or(end);
+        text.truncate(end);
+        text.push('…');
+    }
+
+    text
+}
+
+fn tag_key(tag: &str) -> String {
+    format!("{}:{}", TAG_KEY, tag)
+}
+
+fn alias_key(path: &str) -> String {
+    format!("{}:{}", ALIAS_KEY, path)
+}
+
+fn included_key(path: &str) -> String {
+    format!("{}:{}", INCLUDED_KEY, path)
+}
+
+fn attachment_key(path: &str) -> String {
+    format!("{}:{}", ATTACHMENT_KEY, path)
+}
+
+fn embedded_key(path: &str) -> String {
+    format!("{}:{}", EMBEDDED_KEY, path)
+}
+
+fn backlinks_key(path: &str) -> String {
+    format!("{}:{}", BACKLINKS_KEY, path)
+}
+
+fn starred_key(user: Option<&str>) -> String {
+    match user {
+        Some(user) => format!("{}:{}", STARRED_KEY, user),
+        None => STARRED_KEY.to_owned(),
+    }
+}
+
+fn source_key(path: &str) -> String {
+    format!("{}:{}", SOURCE_KEY, path)
+}
+
+fn revision_key(path: &str, id: u64) -> String {
+    format!("{}:{}:{}", REVISION_KEY, path, id)
+}
+
+pub fn unix_time() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Formats seconds since the Unix epoch for display, like `2023-09-14 08:30 UTC`.
+pub fn format_timestamp(timestamp: u64) -> String {
+    time::OffsetDateTime::from_unix_timestamp(timestamp as i64)
+        .ok()
+        .and_then(|t| {
+            t.format(time::macros::format_description!(
+                "[year]-[month]-[day] [hour]:[minute] UTC"
+            ))
+            .ok()
+        })
+        .unwrap_or_default()
+}
+
+fn chunk_key(path: &str, index: usize) -> String {
+    format!("{}:{}:{}", CHUNKS_KEY, path, index)
+}
+
+/// Roughly how many bytes a page takes to store, used to enforce the storage quota.
+fn page_bytes(page: &Page) -> u64 {
+    (page.content.len() + page.index.len() + page.preview.len() + page.chunks * CHUNK_BYTES) as u64
+}
+
+/// The paths of the pages linked to from under `root`, other than the page at `from` itself.
+fn linked_pages<'a>(root: &'a AstNode<'a>, from: &str) -> Vec<String> {
+    let mut links = Vec::new();
+    for node in root.descendants() {
+        let NodeValue::Link(link) = &node.data.borrow().value else {
+            continue;
+        };
+        let Some(path) = link.url.strip_prefix("/w/") else {
+            continue;
+        };
+
+        let path = path.split(['?', '#']).next().unwrap_or_default();
+        let path = canonical_path(&percent_decode_str(path).decode_utf8_lossy());
+        if !path.is_empty() && path != from && !links.contains(&path) {
+            links.push(path);
+        }
+    }
+
+    links
+}
+
+/// The paths of the attachments linked to or embedded from under `root`.
+fn linked_attachments<'a>(root: &'a AstNode<'a>) -> Vec<String> {
+    let mut attachments = Vec::new();
+    for node in root.descendants() {
+        let (NodeValue::Link(link) | NodeValue::Image(link)) = &node.data.borrow().value else {
+            continue;
+        };
+        let Some(path) = link.url.strip_prefix("/a/") else {
+            continue;
+        };
+
+        let path = path.split(['?', '#']).next().unwrap_or_default();
+        let path = canonical_path(&percent_decode_str(path).decode_utf8_lossy());
+        if !path.is_empty() && !attachments.contains(&path) {
+            attachments.push(path);
+        }
+    }
+
+    attachments
+}
+
+/// An outline of every heading under `root`, as nested lists linking to each heading's anchor.
+fn toc_html<'a>(root: &'a AstNode<'a>) -> String {
+    // Ids are made the same way comrak makes them for `header_ids`, so they line up.
+    let mut anchorizer = Anchorizer::new();
+    let mut html = String::new();
+    let mut levels: Vec<u8> = Vec::new();
+    for node in root.descendants() {
+        let NodeValue::Heading(heading) = node.data.borrow().value else {
+            continue;
+        };
+        let text = heading_text(node);
+        let id = anchorizer.anchorize(text.clone());
+
+        while levels.last().is_some_and(|&l| l > heading.level) {
+            html.push_str("</li></ul>");
+            levels.pop();
+        }
+        if levels.last() == Some(&heading.level) {
+            html.push_str("</li>");
+        } else {
+            html.push_str("<ul>");
+            levels.push(heading.level);
+        }
+        html.push_str(&format!(
+            "<li><a href=\"#{}\">{}</a>",
+            id,
+            search::escape_html(&text)
+        ));
+    }
+    for _ in levels {
+        html.push_str("</li></ul>");
+    }
+
+    html
+}
+
+fn heading_text<'a>(node: &'a AstNode<'a>) -> String {
+    let mut text = String::new();
+    for n in node.descendants().skip(1) {
+        match &n.data.borrow().value {
+            NodeValue::Text(literal) | NodeValue::Code(NodeCode { literal, .. }) => {
+                text.push_str(literal)
+            }
+            NodeValue::LineBreak | NodeValue::SoftBreak => text.push(' '),
+            _ => {}
+        }
+    }
+
+    text
+}
+
+/// Swaps paragraphs of just `[TOC]` for a marker the outline can replace once the page is
+/// rendered, returning whether there were any.
+fn mark_toc<'a>(arena: &'a Arena<AstNode<'a>>, root: &'a AstNode<'a>) -> bool {
+    let markers: Vec<_> = root
+        .descendants()
+        .filter(|n| matches!(n.data.borrow().value, NodeValue::Paragraph))
+        .filter(|n| {
+            n.first_child().is_some_and(|c| {
+                c.next_sibling().is_none()
+                    && matches!(&c.data.borrow().value, NodeValue::Text(t) if t.trim() == "[TOC]")
+            })
+        })
+        .collect();
+
+    for node in &markers {
+        let child = node.first_child().unwrap();
+        child.detach();
+        let start = node.data.borrow().sourcepos.start;
+        node.append(arena.alloc(Node::new(RefCell::new(Ast::new(
+            NodeValue::Text(TOC_MARKER.to_owned()),
+            start,
+        )))));
+    }
+
+    !markers.is_empty()
+}
+
+fn callout_marker(i: usize) -> String {
+    format!("KNOWBASECALLOUT{}X", i)
+}
+
+/// The colour a callout type is shown in, grouping Obsidian's types and their aliases.
+fn callout_kind(callout: &str) -> &'static str {
+    match callout {
+        "tip" | "hint" | "important" | "success" | "check" | "done" => "success",
+        "question" | "help" | "faq" | "warning" | "caution" | "attention" => "warning",
+        "failure" | "fail" | "missing" | "danger" | "error" | "bug" => "danger",
+        "example" | "quote" | "cite" => "secondary",
+        _ => "info",
+    }
+}
+
+/// Gives blockquotes starting with `[!note]`, `[!warning]` and the like a title paragraph
+/// holding the rest of that line, or the type if there's nothing else on it, with a marker so
+/// the quote can be styled as a callout once it's rendered. Returns each callout's colour.
+fn mark_callouts<'a>(arena: &'a Arena<AstNode<'a>>, root: &'a AstNode<'a>) -> Vec<&'static str> {
+    let new_node = |value, start| arena.alloc(Node::new(RefCell::new(Ast::new(value, start))));
+    let quotes: Vec<_> = root
+        .descendants()
+        .filter(|n| matches!(n.data.borrow().value, NodeValue::BlockQuote))
+        .collect();
+
+    let mut kinds = Vec::new();
+    for quote in quotes {
+        let Some(first) = quote
+            .first_child()
+            .filter(|c| matches!(c.data.borrow().value, NodeValue::Paragraph))
+        else {
+            continue;
+        };
+        let Some(text) = first.first_child() else {
+            continue;
+        };
+
+        let callout = {
+            let mut ast = text.data.borrow_mut();
+            let NodeValue::Text(text) = &mut ast.value else {
+                continue;
+            };
+            let Some(found) = CALLOUT_RE.captures(text) else {
+                continue;
+            };
+
+            let callout = found[1].to_lowercase();
+            let end = found.get(0).unwrap().end();
+            text.replace_range(..end, "");
+            callout
+        };
+
+        let start = first.data.borrow().sourcepos.start;
+        let title = new_node(NodeValue::Paragraph, start);
+        while let Some(child) = first.first_child() {
+            child.detach();
+            if matches!(
+                child.data.borrow().value,
+                NodeValue::SoftBreak | NodeValue::LineBreak
+            ) {
+                break;
+            }
+            title.append(child);
+        }
+
+        let untitled = title.children().all(|c| match &c.data.borrow().value {
+            NodeValue::Text(text) => text.trim().is_empty(),
+            _ => false,
+        });
+        if untitled {
+            let mut name = callout.replace('-', " ");
+            name[..1].make_ascii_uppercase();
+            title.append(new_node(NodeValue::Text(name), start));
+        }
+
+        title.prepend(new_node(
+            NodeValue::Text(callout_marker(kinds.len())),
+            start,
+        ));
+        quote.prepend(title);
+        if first.first_child().is_none() {
+            first.detach();
+        }
+        kinds.push(callout_kind(&callout));
+    }
+
+    kinds
+}
+
+fn diagram_marker(i: usize) -> String {
+    format!("KNOWBASEDIAGRAM{}X", i)
+}
+
+/// Swaps every ```` ```mermaid ```` block for a marker paragraph, so it isn't highlighted as
+/// code and can be replaced with something mermaid draws once the page is rendered.
+fn take_diagrams<'a>(arena: &'a Arena<AstNode<'a>>, root: &'a AstNode<'a>) -> Vec<String> {
+    let blocks: Vec<_> = root
+        .descendants()
+        .filter(|n| match &n.data.borrow().value {
+            NodeValue::CodeBlock(block) => block.fenced && block.info.trim() == "mermaid",
+            _ => false,
+        })
+        .collect();
+
+    let mut diagrams = Vec::new();
+    for node in blocks {
+        let mut ast = node.data.borrow_mut();
+        let NodeValue::CodeBlock(block) = std::mem::replace(&mut ast.value, NodeValue::Paragraph)
+        else {
+            continue;
+        };
+
+        let text = NodeValue::Text(diagram_marker(diagrams.len()));
+        node.append(arena.alloc(Node::new(RefCell::new(Ast::new(text, ast.sourcepos.start)))));
+        diagrams.push(block.literal);
+    }
+
+    diagrams
+}
+
+/// Turns `[[Page Name]]` and `[[path/page|label]]` in the text of the page at `from` into links.
+/// Returns the links to pages that don't exist yet, so they can be marked as such.
+fn link_wikilinks<'a>(
+    arena: &'a Arena<AstNode<'a>>,
+    root: &'a AstNode<'a>,
+    from: &str,
+    pages: &[String],
+) -> Vec<&'a AstNode<'a>> {
+    let new_node = |value, start| arena.alloc(Node::new(RefCell::new(Ast::new(value, start))));
+    let mut new_pages = Vec::new();
+
+    let texts: Vec<_> = root
+        .descendants()
+        .filter(|n| matches!(n.data.borrow().value, NodeValue::Text(_)))
+        .filter(|n| {
+            !n.ancestors().any(|a| {
+                matches!(
+                    a.data.borrow().value,
+                    NodeValue::Link(_) | NodeValue::Image(_)
+                )
+            })
+        })
+        .collect();
+
+    for node in texts {
+        let (text, start) = match &node.data.borrow().value {
+            NodeValue::Text(text) => (text.clone(), node.data.borrow().sourcepos.start),
+            _ => continue,
+        };
+
+        let mut rest = 0;
+        for caps in WIKILINK_RE.captures_iter(&text) {
+            let whole = caps.get(0).unwrap();
+            let target = caps[1].trim();
+            let label = caps.get(2).map_or(target, |l| l.as_str().trim());
+
+            if whole.start() > rest {
+                let before = text[rest..whole.start()].to_owned();
+                node.insert_before(new_node(NodeValue::Text(before), start));
+            }
+
+            let (url, exists) = resolve_wikilink(target, from, pages);
+            let link: &AstNode = new_node(
+                NodeValue::Link(NodeLink {
+                    url,
+                    title: String::new(),
+                }),
+                start,
+            );
+            link.append(new_node(NodeValue::Text(label.to_owned()), start));
+            node.insert_before(link);
+            if !exists {
+                new_pages.push(link);
+            }
+
+            rest = whole.end();
+        }
+
+        if rest == text.len() {
+            node.detach();
+        } else if rest > 0 {
+            node.data.borrow_mut().value = NodeValue::Text(text[rest..].to_owned());
+        }
+    }
+
+    new_pages
+}
+
+fn new_page_marker(i: usize) -> String {
+    format!("KNOWBASENEWPAGE{}X", i)
+}
+
+/// Swaps each of `links` for a marker, so it can be put back with the class new page links are
+/// styled with once the page is rendered. Returns each link's URL and text. Links in headings
+/// are left as they are, a marker would change the id the heading is given.
+fn mark_new_page_links<'a>(
+    arena: &'a Arena<AstNode<'a>>,
+    links: &[&'a AstNode<'a>],
+) -> Vec<(String, String)> {
+    let mut marked = Vec::new();
+    for link in links {
+        let in_heading = link
+            .ancestors()
+            .any(|a| matches!(a.data.borrow().value, NodeValue::Heading(_)));
+        if in_heading {
+            continue;
+        }
+        let url = match &link.data.borrow().value {
+            NodeValue::Link(link) => link.url.clone(),
+            _ => continue,
+        };
+        let mut label = String::new();
+        collect_text(link, &mut label);
+
+        let start = link.data.borrow().sourcepos.start;
+        let text = NodeValue::Text(new_page_marker(marked.len()));
+        link.insert_before(arena.alloc(Node::new(RefCell::new(Ast::new(text, start)))));
+        link.detach();
+        marked.push((url, label.trim().to_owned()));
+    }
+
+    marked
+}
+
+/// The URL a wikilink on the page at `from` points to, and whether there's a page there yet.
+/// Targets are looked for next to `from`, then from the root, then by file name in any folder
+/// like Obsidian does.
+fn resolve_wikilink(target: &str, from: &str, pages: &[String]) -> (String, bool) {
+    let (target, fragment) = match target.split_once('#') {
+        Some((target, fragment)) => (target.trim(), Some(fragment.trim())),
+        None => (target, None),
+    };
+    let fragment = fragment
+        .map(|f| format!("#{}", Anchorizer::new().anchorize(f.to_owned())))
+        .unwrap_or_default();
+    if target.is_empty() {
+        return (fragment, true);
+    }
+
+    let mut name = canonical_path(target);
+    if !name.ends_with(".md") {
+        name.push_str(".md");
+    }
+    // Page titles come from file names with `-` for spaces, but uploaded notes often keep theirs.
+    // Uploads may also have been given slugs, either way.
+    let mut names = vec![name.replace(' ', "-"), name.clone()];
+    for dashes in [true, false] {
+        let slug = canonical_path(&slug_path(&name, dashes));
+        if !names.contains(&slug) {
+            names.push(slug);
+        }
+    }
+    let dir = from.rsplit_once('/').map_or("", |(dir, _)| dir);
+
+    let mut candidates = Vec::new();
+    for name in &names {
+        if !dir.is_empty() {
+            candidates.push(format!("{}/{}", dir, name));
+        }
+        candidates.push(name.clone());
+    }
+
+    let found = candidates
+        .into_iter()
+        .find(|c| pages.contains(c))
+        .or_else(|| {
+            if target.contains('/') {
+                return None;
+            }
+            names.iter().find_map(|name| {
+                pages
+                    .iter()
+                    .filter(|p| p.rsplit('/').next() == Some(name.as_str()))
+                    .min_by_key(|p| p.len())
+                    .cloned()
+            })
+        });
+
+    let exists = found.is_some();
+    let path = found.unwrap_or_else(|| names[0].clone());
+
+    (format!("{}{}", wiki_url(&path), fragment), exists)
+}
+
+fn iter_md_nodes<'a, F>(node: &'a AstNode<'a>, f: &F)
+where
+    F: Fn(&'a AstNode<'a>),
+{
+    f(node);
+    for c in node.children() {
+        iter_md_nodes(c, f);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PRIVATE_PAGE: &str =
+        "---\nvisibility: private\n---\n# Payroll\n\nSalaries are reviewed in March.";
+
+    fn logged_in() -> Viewer {
+        Viewer {
+            role: Some(Role::Reader),
+            user: None,
+        }
+    }
+
+    #[actix_web::test]
+    async fn public_pages_cannot_include_pages_that_need_a_login() {
+        let mut state = State::in_memory();
+        state.public_read = false;
+        state
+            .set_page("payroll.md", "Salaries are reviewed in March.".to_owned())
+            .await
+            .unwrap();
+        state
+            .set_page(
+                "handbook.md",
+                "---\npublic: true\n---\n{{include: payroll.md}}".to_owned(),
+            )
+            .await
+            .unwrap();
+
+        let handbook = state.get_page("handbook.md").await.unwrap().unwrap();
+        assert!(!handbook.content.contains("Salaries"));
+        assert!(handbook.content.contains("Can't include"));
+
+        state
+            .set_page(
+                "payroll.md",
+                "---\npublic: true\n---\nSalaries are reviewed in March.".to_owned(),
+            )
+            .await
+            .unwrap();
+        let handbook = state.get_page("handbook.md").await.unwrap().unwrap();
+        assert!(handbook.content.contains("Salaries"));
+    }
+
+    #[actix_web::test]
+    async fn backlinks_only_list_pages_the_viewer_can_read() {
+        let mut state = State::in_memory();
+        state.public_read = false;
+        state
+            .set_page("faq.md", "---\npublic: true\n---\n# FAQ".to_owned())
+            .await
+            .unwrap();
+        state
+            .set_page("payroll.md", "See the [[faq]].".to_owned())
+            .await
+            .unwrap();
+
+        let anonymous = state.backlinks("faq.md", &Viewer::default()).await.unwrap();
+        assert!(anonymous.is_empty());
+        let backlinks = state.backlinks("faq.md", &logged_in()).await.unwrap();
+        assert_eq!(backlinks.len(), 1);
+    }
+
+    #[actix_web::test]
+    async fn attachments_of_public_pages_can_be_read_anonymously() {
+        let mut state = State::in_memory();
+        state.public_read = false;
+        state
+            .set_page(
+                "guides/setup.md",
+                "---\npublic: true\n---\n![Diagram](images/diagram.png)".to_owned(),
+            )
+            .await
+            .unwrap();
+        state
+            .set_page("payroll.md", "![Chart](chart.png)".to_owned())
+            .await
+            .unwrap();
+
+        assert!(state
+            .is_attachment_readable_anonymously("guides/images/diagram.png")
+            .await
+            .unwrap());
+        assert!(!state
+            .is_attachment_readable_anonymously("chart.png")
+            .await
+            .unwrap());
+
+        state
+            .set_page("guides/setup.md", "Nothing to see.".to_owned())
+            .await
+            .unwrap();
+        assert!(!state
+            .is_attachment_readable_anonymously("guides/images/diagram.png")
+            .await
+            .unwrap());
+    }
+
+    /// A store that takes a moment to read from, like one across a network, so updates made at
+    /// the same time overlap.
+    #[derive(Debug, Default)]
+    struct SlowStore(MemoryStore);
+
+    #[async_trait::async_trait]
+    impl Store for SlowStore {
+        async fn ping(&self) -> Result<()> {
+            self.0.ping().await
+        }
+
+        async fn get_page(&self, path: &str) -> Result<Option<Page>> {
+            self.0.get_page(path).await
+        }
+
+        async fn set_page(&self, path: &str, page: &Page) -> Result<()> {
+            self.0.set_page(path, page).await
+        }
+
+        async fn delete_page(&self, path: &str) -> Result<()> {
+            self.0.delete_page(path).await
+        }
+
+        async fn list_pages(&self) -> Result<Vec<String>> {
+            self.0.list_pages().await
+        }
+
+        async fn storage_bytes(&self) -> Result<u64> {
+            self.0.storage_bytes().await
+        }
+
+        async fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+            let value = self.0.get(key).await;
+            tokio::time::sleep(Duration::from_millis(1)).await;
+            value
+        }
+
+        async fn set(&self, key: &str, value: &[u8]) -> Result<()> {
+            self.0.set(key, value).await
+        }
+
+        async fn set_ex(&self, key: &str, value: &[u8], ttl: Duration) -> Result<()> {
+            self.0.set_ex(key, value, ttl).await
+        }
+
+        async fn delete(&self, key: &str) -> Result<()> {
+            self.0.delete(key).await
+        }
+
+        async fn keys(&self, prefix: &str) -> Result<Vec<String>> {
+            self.0.keys(prefix).await
+        }
+
+        async fn ttl(&self, key: &str) -> Result<Option<Duration>> {
+            self.0.ttl(key).await
+        }
+
+        async fn incr(&self, key: &str, ttl: Duration) -> Result<u64> {
+            self.0.incr(key, ttl).await
+        }
+    }
+
+    #[actix_web::test]
+    async fn concurrent_saves_keep_every_revision() {
+        let state = State::with_store(Arc::new(SlowStore::default()), None);
+        let saves = (0..20).map(|i| state.set_page("log.md", format!("# Entry {}", i)));
+        futures_util::future::try_join_all(saves).await.unwrap();
+
+        let revisions = state.revisions("log.md").await.unwrap();
+        let mut ids: Vec<u64> = revisions.iter().map(|r| r.id).collect();
+        ids.dedup();
+        assert_eq!(ids.len(), 20);
+        for revision in &revisions {
+            let md = state
+                .revision_markdown("log.md", revision.id)
+                .await
+                .unwrap();
+            assert!(md.is_some());
+        }
+    }
+
+    #[actix_web::test]
+    async fn concurrent_saves_keep_every_index_entry() {
+        let state = State::with_store(Arc::new(SlowStore::default()), None);
+        let paths: Vec<String> = (0..20).map(|i| format!("page{}.md", i)).collect();
+        let saves = paths
+            .iter()
+            .map(|path| state.set_page(path, "---\ntags: [home]\n---\n[[faq]]".to_owned()));
+        futures_util::future::try_join_all(saves).await.unwrap();
+
+        assert_eq!(state.tagged("home").await.unwrap().len(), 20);
+        let backlinks = state.backlinks("faq.md", &logged_in()).await.unwrap();
+        assert_eq!(backlinks.len(), 20);
+        assert_eq!(state.recent_changes().await.unwrap().len(), 20);
+    }
+
+    #[actix_web::test]
+    async fn private_pages_are_only_found_by_people_who_logged_in() {
+        let state = State::in_memory();
+        state
+            .set_page("payroll.md", PRIVATE_PAGE.to_owned())
+            .await
+            .unwrap();
+
+        let (results, total) = state
+            .run_search("salaries", "", &Viewer::default(), 0, 10)
+            .await
+            .unwrap();
+        assert!(results.is_empty());
+        assert_eq!(total, 0);
+
+        let (results, total) = state
+            .run_search("salaries", "", &logged_in(), 0, 10)
+            .await
+            .unwrap();
+        assert_eq!(total, 1);
+        assert_eq!(results[0].url, wiki_url("payroll.md"));
     }
 }
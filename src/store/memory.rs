@@ -0,0 +1,136 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+
+use super::Store;
+use crate::{error::Result, state::Page};
+
+/// A stored value and when it expires, if it does.
+type Value = (Vec<u8>, Option<Instant>);
+
+/// Keeps everything in memory, used when pages are served from a directory on disk so there's
+/// nothing else worth persisting.
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    pages: Mutex<HashMap<String, Page>>,
+    values: Mutex<HashMap<String, Value>>,
+}
+
+impl MemoryStore {
+    /// Looks up a value, dropping it if it has expired.
+    fn live_value(&self, key: &str) -> Option<Value> {
+        let mut values = self.values.lock().unwrap();
+        match values.get(key) {
+            Some((_, Some(expires))) if *expires <= Instant::now() => {
+                values.remove(key);
+                None
+            }
+            value => value.cloned(),
+        }
+    }
+}
+
+#[async_trait]
+impl Store for MemoryStore {
+    async fn ping(&self) -> Result<()> {
+        Ok(())
+    }
+
+    async fn get_page(&self, path: &str) -> Result<Option<Page>> {
+        Ok(self.pages.lock().unwrap().get(path).cloned())
+    }
+
+    async fn set_page(&self, path: &str, page: &Page) -> Result<()> {
+        self.pages
+            .lock()
+            .unwrap()
+            .insert(path.to_owned(), page.clone());
+        Ok(())
+    }
+
+    async fn delete_page(&self, path: &str) -> Result<()> {
+        self.pages.lock().unwrap().remove(path);
+        Ok(())
+    }
+
+    async fn list_pages(&self) -> Result<Vec<String>> {
+        Ok(self.pages.lock().unwrap().keys().cloned().collect())
+    }
+
+    async fn storage_bytes(&self) -> Result<u64> {
+        let pages = self.pages.lock().unwrap();
+        let page_bytes: usize = pages
+            .iter()
+            .map(|(path, p)| path.len() + p.content.len() + p.index.len() + p.preview.len())
+            .sum();
+        let value_bytes: usize = self
+            .values
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(key, (value, _))| key.len() + value.len())
+            .sum();
+
+        Ok((page_bytes + value_bytes) as u64)
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        Ok(self.live_value(key).map(|(value, _)| value))
+    }
+
+    async fn set(&self, key: &str, value: &[u8]) -> Result<()> {
+        self.values
+            .lock()
+            .unwrap()
+            .insert(key.to_owned(), (value.to_vec(), None));
+        Ok(())
+    }
+
+    async fn set_ex(&self, key: &str, value: &[u8], ttl: Duration) -> Result<()> {
+        self.values
+            .lock()
+            .unwrap()
+            .insert(key.to_owned(), (value.to_vec(), Some(Instant::now() + ttl)));
+        Ok(())
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        self.values.lock().unwrap().remove(key);
+        Ok(())
+    }
+
+    async fn keys(&self, prefix: &str) -> Result<Vec<String>> {
+        let now = Instant::now();
+        Ok(self
+            .values
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(k, (_, expires))| k.starts_with(prefix) && expires.is_none_or(|e| e > now))
+            .map(|(k, _)| k.to_owned())
+            .collect())
+    }
+
+    async fn ttl(&self, key: &str) -> Result<Option<Duration>> {
+        Ok(self
+            .live_value(key)
+            .and_then(|(_, expires)| expires)
+            .map(|e| e.saturating_duration_since(Instant::now())))
+    }
+
+    async fn incr(&self, key: &str, ttl: Duration) -> Result<u64> {
+        let current = self
+            .live_value(key)
+            .and_then(|(value, _)| String::from_utf8(value).ok())
+            .and_then(|v| v.parse::<u64>().ok())
+            .unwrap_or_default();
+
+        let value = current + 1;
+        self.set_ex(key, value.to_string().as_bytes(), ttl).await?;
+        Ok(value)
+    }
+}
@@ -0,0 +1,102 @@
+mod memory;
+mod postgres;
+mod redis;
+mod sqlite;
+
+use std::{fmt::Debug, time::Duration};
+
+use actix_web::cookie::Key;
+use async_trait::async_trait;
+
+use crate::{error::Result, state::Page};
+
+pub use self::{
+    memory::MemoryStore, postgres::PostgresStore, redis::RedisStore, sqlite::SqliteStore,
+};
+
+const MASTER_KEY: &str = "master_key";
+/// Prefix of the keys holding the content of pages too large to store inline.
+pub const CHUNKS_KEY: &str = "page_chunks";
+
+/// Somewhere to keep pages and the rest of the wiki's state.
+///
+/// Pages get dedicated methods so backends can store them however suits them best, everything
+/// else goes through the small key-value interface at the bottom.
+#[async_trait]
+pub trait Store: Debug + Send + Sync {
+    /// Checks that the backend is reachable.
+    async fn ping(&self) -> Result<()>;
+
+    async fn get_page(&self, path: &str) -> Result<Option<Page>>;
+
+    /// Stores a page, replacing any page already at `path`.
+    async fn set_page(&self, path: &str, page: &Page) -> Result<()>;
+
+    async fn delete_page(&self, path: &str) -> Result<()>;
+
+    /// The paths of every stored page.
+    async fn list_pages(&self) -> Result<Vec<String>>;
+
+    async fn page_count(&self) -> Result<usize> {
+        Ok(self.list_pages().await?.len())
+    }
+
+    /// Every page whose path contains `search`, which is already lowercase.
+    async fn find_pages(&self, search: &str) -> Result<Vec<(String, Page)>> {
+        let mut pages = Vec::new();
+        for path in self.list_pages().await? {
+            if !path.contains(search) {
+                continue;
+            }
+
+            if let Some(page) = self.get_page(&path).await? {
+                pages.push((path, page));
+            }
+        }
+
+        Ok(pages)
+    }
+
+    /// Roughly how many bytes the backend uses to store pages.
+    async fn storage_bytes(&self) -> Result<u64>;
+
+    /// The key signing session cookies, generated the first time it's asked for.
+    async fn master_key(&self) -> Result<Key> {
+        if let Some(key) = self.get(MASTER_KEY).await? {
+            return Ok(Key::from(&key));
+        }
+
+        let key = Key::generate();
+        self.set(MASTER_KEY, key.master()).await?;
+        Ok(key)
+    }
+
+    /// Replaces the master key with a new one, which is used from the next restart.
+    async fn rotate_master_key(&self) -> Result<()> {
+        self.set(MASTER_KEY, Key::generate().master()).await
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>>;
+
+    async fn set(&self, key: &str, value: &[u8]) -> Result<()>;
+
+    /// Stores a value that's forgotten once `ttl` has passed.
+    async fn set_ex(&self, key: &str, value: &[u8], ttl: Duration) -> Result<()>;
+
+    async fn delete(&self, key: &str) -> Result<()>;
+
+    /// Every stored key that starts with `prefix`, in no particular order.
+    async fn keys(&self, prefix: &str) -> Result<Vec<String>>;
+
+    /// Every key holding a value `get` can read, for backing the store up. Backends that keep
+    /// other things next to those values, like pages, leave them out.
+    async fn value_keys(&self) -> Result<Vec<String>> {
+        self.keys("").await
+    }
+
+    /// How long until a value stored with `set_ex` or `incr` is forgotten.
+    async fn ttl(&self, key: &str) -> Result<Option<Duration>>;
+
+    /// Increments a counter, returning its new value, and resets how long until it's forgotten.
+    async fn incr(&self, key: &str, ttl: Duration) -> Result<u64>;
+}
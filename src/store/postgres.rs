@@ -0,0 +1,221 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use sqlx::{postgres::PgPoolOptions, PgPool, Row};
+
+use super::Store;
+use crate::{config, error::Result, state::Page};
+
+/// Stores everything in Postgres, for teams that already run it and would rather not add Redis.
+#[derive(Debug, Clone)]
+pub struct PostgresStore {
+    pool: PgPool,
+}
+
+impl PostgresStore {
+    pub async fn from_config() -> Self {
+        let url =
+            config::var("knowbase_DATABASE_URL").expect("knowbase_DATABASE_URL should be set");
+        let pool = PgPoolOptions::new()
+            .connect(&url)
+            .await
+            .expect("Postgres should be available");
+
+        for statement in [
+            "CREATE TABLE IF NOT EXISTS pages (path TEXT PRIMARY KEY, page TEXT NOT NULL)",
+            "CREATE TABLE IF NOT EXISTS kv (
+                key TEXT PRIMARY KEY,
+                value BYTEA NOT NULL,
+                expires_at BIGINT
+            )",
+        ] {
+            sqlx::query(statement)
+                .execute(&pool)
+                .await
+                .expect("Postgres schema should be creatable");
+        }
+
+        Self { pool }
+    }
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64
+}
+
+#[async_trait]
+impl Store for PostgresStore {
+    async fn ping(&self) -> Result<()> {
+        sqlx::query("SELECT 1").execute(&self.pool).await?;
+        Ok(())
+    }
+
+    async fn get_page(&self, path: &str) -> Result<Option<Page>> {
+        let row = sqlx::query("SELECT page FROM pages WHERE path = $1")
+            .bind(path)
+            .fetch_optional(&self.pool)
+            .await?;
+
+        Ok(row.map(|r| serde_json::from_str(r.get("page")).unwrap()))
+    }
+
+    async fn set_page(&self, path: &str, page: &Page) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO pages (path, page) VALUES ($1, $2)
+            ON CONFLICT (path) DO UPDATE SET page = excluded.page",
+        )
+        .bind(path)
+        .bind(serde_json::to_string(page).unwrap())
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn delete_page(&self, path: &str) -> Result<()> {
+        sqlx::query("DELETE FROM pages WHERE path = $1")
+            .bind(path)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn list_pages(&self) -> Result<Vec<String>> {
+        let rows = sqlx::query("SELECT path FROM pages")
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(rows.iter().map(|r| r.get("path")).collect())
+    }
+
+    async fn page_count(&self) -> Result<usize> {
+        let count: i64 = sqlx::query("SELECT COUNT(*) FROM pages")
+            .fetch_one(&self.pool)
+            .await?
+            .get(0);
+
+        Ok(count as usize)
+    }
+
+    async fn find_pages(&self, search: &str) -> Result<Vec<(String, Page)>> {
+        let rows = sqlx::query("SELECT path, page FROM pages WHERE strpos(path, $1) > 0")
+            .bind(search)
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(rows
+            .iter()
+            .map(|r| (r.get("path"), serde_json::from_str(r.get("page")).unwrap()))
+            .collect())
+    }
+
+    async fn storage_bytes(&self) -> Result<u64> {
+        let bytes: i64 =
+            sqlx::query("SELECT pg_total_relation_size('pages') + pg_total_relation_size('kv')")
+                .fetch_one(&self.pool)
+                .await?
+                .get(0);
+
+        Ok(bytes as u64)
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        let row = sqlx::query(
+            "SELECT value FROM kv WHERE key = $1 AND (expires_at IS NULL OR expires_at > $2)",
+        )
+        .bind(key)
+        .bind(now())
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(row.map(|r| r.get("value")))
+    }
+
+    async fn set(&self, key: &str, value: &[u8]) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO kv (key, value, expires_at) VALUES ($1, $2, NULL)
+            ON CONFLICT (key) DO UPDATE SET value = excluded.value, expires_at = NULL",
+        )
+        .bind(key)
+        .bind(value)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn set_ex(&self, key: &str, value: &[u8], ttl: Duration) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO kv (key, value, expires_at) VALUES ($1, $2, $3)
+            ON CONFLICT (key) DO UPDATE SET value = excluded.value, expires_at = excluded.expires_at",
+        )
+        .bind(key)
+        .bind(value)
+        .bind(now() + ttl.as_secs().max(1) as i64)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        sqlx::query("DELETE FROM kv WHERE key = $1")
+            .bind(key)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn keys(&self, prefix: &str) -> Result<Vec<String>> {
+        let rows = sqlx::query(
+            "SELECT key FROM kv
+            WHERE left(key, length($1)) = $1 AND (expires_at IS NULL OR expires_at > $2)",
+        )
+        .bind(prefix)
+        .bind(now())
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.into_iter().map(|r| r.get("key")).collect())
+    }
+
+    async fn ttl(&self, key: &str) -> Result<Option<Duration>> {
+        let row = sqlx::query("SELECT expires_at FROM kv WHERE key = $1 AND expires_at > $2")
+            .bind(key)
+            .bind(now())
+            .fetch_optional(&self.pool)
+            .await?;
+
+        Ok(row.map(|r| Duration::from_secs((r.get::<i64, _>("expires_at") - now()) as u64)))
+    }
+
+    async fn incr(&self, key: &str, ttl: Duration) -> Result<u64> {
+        let now = now();
+
+        // Counters are stored as text so they read back the same way they do from Redis,
+        // the upsert keeps concurrent increments from losing counts.
+        let value: String = sqlx::query(
+            "INSERT INTO kv (key, value, expires_at) VALUES ($1, '1', $3)
+            ON CONFLICT (key) DO UPDATE SET
+                value = CASE
+                    WHEN kv.expires_at IS NOT NULL AND kv.expires_at <= $2 THEN '1'::bytea
+                    ELSE convert_to((convert_from(kv.value, 'UTF8')::bigint + 1)::text, 'UTF8')
+                END,
+                expires_at = excluded.expires_at
+            RETURNING convert_from(value, 'UTF8')",
+        )
+        .bind(key)
+        .bind(now)
+        .bind(now + ttl.as_secs().max(1) as i64)
+        .fetch_one(&self.pool)
+        .await?
+        .get(0);
+
+        Ok(value.parse().unwrap_or_default())
+    }
+}
@@ -0,0 +1,232 @@
+use std::{future::Future, time::Duration};
+
+use async_trait::async_trait;
+use deadpool_redis::{Config, Pool, Runtime};
+use redis::AsyncCommands;
+
+use super::{Store, CHUNKS_KEY};
+use crate::{config, error::Result, state::Page};
+
+const PAGE_KEY: &str = "pages";
+
+/// Stores pages as JSON in a single Redis hash and everything else as plain keys.
+#[derive(Clone)]
+pub struct RedisStore {
+    pool: Pool,
+    command_timeout: Duration,
+}
+
+impl std::fmt::Debug for RedisStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RedisStore")
+            .field("pool", &self.pool.status())
+            .field("command_timeout", &self.command_timeout)
+            .finish()
+    }
+}
+
+impl RedisStore {
+    pub fn from_config() -> Self {
+        let url = config::var("knowbase_REDIS_URL").expect("knowbase_REDIS_URL should be set");
+        let pool_size = config::var_or("knowbase_REDIS_POOL_SIZE", 16);
+        let connect_timeout =
+            Duration::from_millis(config::var_or("knowbase_REDIS_CONNECT_TIMEOUT_MS", 2000));
+        let command_timeout =
+            Duration::from_millis(config::var_or("knowbase_REDIS_COMMAND_TIMEOUT_MS", 5000));
+
+        let pool = Config::from_url(url)
+            .builder()
+            .expect("Redis URL should be valid")
+            .max_size(pool_size)
+            .wait_timeout(Some(connect_timeout))
+            .create_timeout(Some(connect_timeout))
+            .recycle_timeout(Some(connect_timeout))
+            .runtime(Runtime::Tokio1)
+            .build()
+            .expect("Redis pool should be creatable");
+
+        Self {
+            pool,
+            command_timeout,
+        }
+    }
+
+    /// Takes a connection from the pool, waiting at most the connect timeout for one to free up.
+    pub(crate) async fn con(&self) -> Result<deadpool_redis::Connection> {
+        Ok(self.pool.get().await?)
+    }
+
+    /// Runs a single Redis command, failing if it takes longer than the command timeout.
+    pub(crate) async fn timed<T>(
+        &self,
+        cmd: impl Future<Output = redis::RedisResult<T>>,
+    ) -> Result<T> {
+        Ok(tokio::time::timeout(self.command_timeout, cmd).await??)
+    }
+}
+
+#[async_trait]
+impl Store for RedisStore {
+    async fn ping(&self) -> Result<()> {
+        let mut con = self.con().await?;
+        self.timed(redis::cmd("PING").query_async::<_, ()>(&mut con))
+            .await
+    }
+
+    async fn get_page(&self, path: &str) -> Result<Option<Page>> {
+        let mut con = self.con().await?;
+        let page_json: Option<String> = self.timed(con.hget(PAGE_KEY, path)).await?;
+
+        Ok(page_json.map(|p| serde_json::from_str(&p).unwrap()))
+    }
+
+    async fn set_page(&self, path: &str, page: &Page) -> Result<()> {
+        let mut con = self.con().await?;
+        self.timed(con.hset::<&str, &str, String, ()>(
+            PAGE_KEY,
+            path,
+            serde_json::to_string(page).unwrap(),
+        ))
+        .await
+    }
+
+    async fn delete_page(&self, path: &str) -> Result<()> {
+        let mut con = self.con().await?;
+        self.timed(con.hdel(PAGE_KEY, path)).await
+    }
+
+    async fn list_pages(&self) -> Result<Vec<String>> {
+        let mut con = self.con().await?;
+        self.timed(con.hkeys(PAGE_KEY)).await
+    }
+
+    async fn page_count(&self) -> Result<usize> {
+        let mut con = self.con().await?;
+        self.timed(con.hlen(PAGE_KEY)).await
+    }
+
+    async fn find_pages(&self, search: &str) -> Result<Vec<(String, Page)>> {
+        let mut con = self.con().await?;
+        let mut matches: Vec<String> = Vec::new();
+
+        let scan = async {
+            let mut async_iter = con
+                .hscan_match::<&str, String, Vec<String>>(PAGE_KEY, format!("*{}*", search))
+                .await?;
+            while let Some(mut items) = async_iter.next_item().await {
+                matches.append(&mut items);
+            }
+            Ok(())
+        };
+        self.timed(scan).await?;
+
+        Ok(matches
+            .chunks(2)
+            .map(|a| (a[0].to_owned(), serde_json::from_str(&a[1]).unwrap()))
+            .collect())
+    }
+
+    async fn storage_bytes(&self) -> Result<u64> {
+        let mut con = self.con().await?;
+        let mut keys = vec![PAGE_KEY.to_owned()];
+        let scan = async {
+            let mut iter = con
+                .scan_match::<_, String>(format!("{}:*", CHUNKS_KEY))
+                .await?;
+            while let Some(key) = iter.next_item().await {
+                keys.push(key);
+            }
+            Ok(())
+        };
+        self.timed(scan).await?;
+
+        let mut bytes = 0;
+        for key in keys {
+            let key_bytes: Option<u64> = self
+                .timed(
+                    redis::cmd("MEMORY")
+                        .arg("USAGE")
+                        .arg(key)
+                        .query_async(&mut con),
+                )
+                .await?;
+            bytes += key_bytes.unwrap_or_default();
+        }
+
+        Ok(bytes)
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        let mut con = self.con().await?;
+        self.timed(con.get(key)).await
+    }
+
+    async fn set(&self, key: &str, value: &[u8]) -> Result<()> {
+        let mut con = self.con().await?;
+        self.timed(con.set(key, value)).await
+    }
+
+    async fn set_ex(&self, key: &str, value: &[u8], ttl: Duration) -> Result<()> {
+        let mut con = self.con().await?;
+        self.timed(con.set_ex(key, value, ttl.as_secs().max(1) as usize))
+            .await
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        let mut con = self.con().await?;
+        self.timed(con.del(key)).await
+    }
+
+    async fn keys(&self, prefix: &str) -> Result<Vec<String>> {
+        let mut con = self.con().await?;
+        let mut keys = Vec::new();
+        let scan = async {
+            let mut iter = con.scan_match::<_, String>(format!("{}*", prefix)).await?;
+            while let Some(key) = iter.next_item().await {
+                keys.push(key);
+            }
+            Ok(())
+        };
+        self.timed(scan).await?;
+
+        Ok(keys)
+    }
+
+    async fn value_keys(&self) -> Result<Vec<String>> {
+        let keys = self.keys("").await?;
+        if keys.is_empty() {
+            return Ok(keys);
+        }
+
+        // The pages hash and search documents share the database with the plain values.
+        let mut con = self.con().await?;
+        let mut pipe = redis::pipe();
+        for key in &keys {
+            pipe.cmd("TYPE").arg(key);
+        }
+        let types: Vec<String> = self.timed(pipe.query_async(&mut con)).await?;
+
+        Ok(keys
+            .into_iter()
+            .zip(types)
+            .filter(|(_, kind)| kind == "string")
+            .map(|(key, _)| key)
+            .collect())
+    }
+
+    async fn ttl(&self, key: &str) -> Result<Option<Duration>> {
+        let mut con = self.con().await?;
+        let ttl: i64 = self.timed(con.ttl(key)).await?;
+
+        Ok((ttl > 0).then(|| Duration::from_secs(ttl as u64)))
+    }
+
+    async fn incr(&self, key: &str, ttl: Duration) -> Result<u64> {
+        let mut con = self.con().await?;
+        let value = self.timed(con.incr(key, 1)).await?;
+        self.timed(con.expire::<_, ()>(key, ttl.as_secs().max(1) as usize))
+            .await?;
+
+        Ok(value)
+    }
+}
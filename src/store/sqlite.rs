@@ -0,0 +1,234 @@
+use std::{
+    str::FromStr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use async_trait::async_trait;
+use sqlx::{
+    sqlite::{SqliteConnectOptions, SqlitePoolOptions},
+    Row, SqlitePool,
+};
+
+use super::Store;
+use crate::{config, error::Result, state::Page};
+
+/// Stores everything in a single SQLite database file, for wikis that don't want to run Redis.
+#[derive(Debug, Clone)]
+pub struct SqliteStore {
+    pool: SqlitePool,
+}
+
+impl SqliteStore {
+    pub async fn from_config() -> Self {
+        let path = config::var("knowbase_SQLITE_PATH").unwrap_or("knowbase.db".to_owned());
+        let options = SqliteConnectOptions::from_str(&path)
+            .expect("SQLite path should be valid")
+            .create_if_missing(true);
+        let pool = SqlitePoolOptions::new()
+            .connect_with(options)
+            .await
+            .expect("SQLite database should be openable");
+
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS pages (path TEXT PRIMARY KEY, page TEXT NOT NULL);
+            CREATE TABLE IF NOT EXISTS kv (
+                key TEXT PRIMARY KEY,
+                value BLOB NOT NULL,
+                expires_at INTEGER
+            );",
+        )
+        .execute(&pool)
+        .await
+        .expect("SQLite schema should be creatable");
+
+        Self { pool }
+    }
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64
+}
+
+#[async_trait]
+impl Store for SqliteStore {
+    async fn ping(&self) -> Result<()> {
+        sqlx::query("SELECT 1").execute(&self.pool).await?;
+        Ok(())
+    }
+
+    async fn get_page(&self, path: &str) -> Result<Option<Page>> {
+        let row = sqlx::query("SELECT page FROM pages WHERE path = ?")
+            .bind(path)
+            .fetch_optional(&self.pool)
+            .await?;
+
+        Ok(row.map(|r| serde_json::from_str(r.get("page")).unwrap()))
+    }
+
+    async fn set_page(&self, path: &str, page: &Page) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO pages (path, page) VALUES (?, ?)
+            ON CONFLICT (path) DO UPDATE SET page = excluded.page",
+        )
+        .bind(path)
+        .bind(serde_json::to_string(page).unwrap())
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn delete_page(&self, path: &str) -> Result<()> {
+        sqlx::query("DELETE FROM pages WHERE path = ?")
+            .bind(path)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn list_pages(&self) -> Result<Vec<String>> {
+        let rows = sqlx::query("SELECT path FROM pages")
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(rows.iter().map(|r| r.get("path")).collect())
+    }
+
+    async fn page_count(&self) -> Result<usize> {
+        let count: i64 = sqlx::query("SELECT COUNT(*) FROM pages")
+            .fetch_one(&self.pool)
+            .await?
+            .get(0);
+
+        Ok(count as usize)
+    }
+
+    async fn find_pages(&self, search: &str) -> Result<Vec<(String, Page)>> {
+        let rows = sqlx::query("SELECT path, page FROM pages WHERE instr(path, ?) > 0")
+            .bind(search)
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(rows
+            .iter()
+            .map(|r| (r.get("path"), serde_json::from_str(r.get("page")).unwrap()))
+            .collect())
+    }
+
+    async fn storage_bytes(&self) -> Result<u64> {
+        let bytes: i64 = sqlx::query(
+            "SELECT page_count * page_size FROM pragma_page_count(), pragma_page_size()",
+        )
+        .fetch_one(&self.pool)
+        .await?
+        .get(0);
+
+        Ok(bytes as u64)
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        let row = sqlx::query(
+            "SELECT value FROM kv WHERE key = ? AND (expires_at IS NULL OR expires_at > ?)",
+        )
+        .bind(key)
+        .bind(now())
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(row.map(|r| r.get("value")))
+    }
+
+    async fn set(&self, key: &str, value: &[u8]) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO kv (key, value, expires_at) VALUES (?, ?, NULL)
+            ON CONFLICT (key) DO UPDATE SET value = excluded.value, expires_at = NULL",
+        )
+        .bind(key)
+        .bind(value)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn set_ex(&self, key: &str, value: &[u8], ttl: Duration) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO kv (key, value, expires_at) VALUES (?, ?, ?)
+            ON CONFLICT (key) DO UPDATE SET value = excluded.value, expires_at = excluded.expires_at",
+        )
+        .bind(key)
+        .bind(value)
+        .bind(now() + ttl.as_secs().max(1) as i64)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        sqlx::query("DELETE FROM kv WHERE key = ?")
+            .bind(key)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn keys(&self, prefix: &str) -> Result<Vec<String>> {
+        let rows = sqlx::query(
+            "SELECT key FROM kv
+            WHERE substr(key, 1, length(?1)) = ?1 AND (expires_at IS NULL OR expires_at > ?2)",
+        )
+        .bind(prefix)
+        .bind(now())
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.into_iter().map(|r| r.get("key")).collect())
+    }
+
+    async fn ttl(&self, key: &str) -> Result<Option<Duration>> {
+        let row = sqlx::query("SELECT expires_at FROM kv WHERE key = ? AND expires_at > ?")
+            .bind(key)
+            .bind(now())
+            .fetch_optional(&self.pool)
+            .await?;
+
+        Ok(row.map(|r| Duration::from_secs((r.get::<i64, _>("expires_at") - now()) as u64)))
+    }
+
+    async fn incr(&self, key: &str, ttl: Duration) -> Result<u64> {
+        let now = now();
+        let mut tx = self.pool.begin().await?;
+
+        // Counters are stored as text so they read back the same way they do from Redis.
+        let current = sqlx::query(
+            "SELECT value FROM kv WHERE key = ? AND (expires_at IS NULL OR expires_at > ?)",
+        )
+        .bind(key)
+        .bind(now)
+        .fetch_optional(&mut *tx)
+        .await?
+        .and_then(|r| String::from_utf8(r.get("value")).ok())
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or_default();
+
+        let value = current + 1;
+        sqlx::query(
+            "INSERT INTO kv (key, value, expires_at) VALUES (?, ?, ?)
+            ON CONFLICT (key) DO UPDATE SET value = excluded.value, expires_at = excluded.expires_at",
+        )
+        .bind(key)
+        .bind(value.to_string().into_bytes())
+        .bind(now + ttl.as_secs().max(1) as i64)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        Ok(value)
+    }
+}
@@ -0,0 +1,446 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    sync::{Arc, Mutex},
+};
+
+use actix_multipart::form::tempfile::TempFile;
+use serde::Serialize;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+use crate::{
+    acl::Viewer,
+    archive::Archive,
+    error::{Error, Result},
+    import::{ImportMode, Importer},
+    paths::{canonical_path, page_path_error, slug_path},
+    state::{unix_time, AuditAction, AuditEvent, PageWrite, State},
+};
+
+/// How many uploads are remembered, so their reports can be looked at once they're done.
+const KEPT_JOBS: usize = 100;
+
+/// An upload waiting to be imported, with everything needed from the request it came in with.
+pub struct Upload {
+    pub file: TempFile,
+    pub mode: ImportMode,
+    /// Where to put a single page, or the folder to put it in, instead of at its file name.
+    pub path: String,
+    /// Whether the archive is everything the wiki should have, so pages missing from it go.
+    pub sync: bool,
+    /// Whether spaces in file names become `-` in the paths they're imported to.
+    pub dashes: bool,
+    /// Who uploaded it, which decides where it can put pages and whose name is on them.
+    pub viewer: Viewer,
+    /// How the uploader is named in the audit log.
+    pub actor: String,
+    pub ip: String,
+}
+
+/// What an upload did with each of its files.
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct UploadReport {
+    pub created: Vec<String>,
+    pub updated: Vec<String>,
+    /// Pages that already had exactly the uploaded markdown, so were left alone.
+    pub skipped: Vec<String>,
+    pub attachments: Vec<String>,
+    /// Pages that weren't in a synced archive and were moved to the trash.
+    pub trashed: Vec<String>,
+    pub failed: Vec<FailedFile>,
+    /// Why the upload stopped before the end of the archive, if it did.
+    pub stopped: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FailedFile {
+    pub file: String,
+    pub reason: String,
+}
+
+impl UploadReport {
+    fn fail(&mut self, file: &str, reason: impl ToString) {
+        self.failed.push(FailedFile {
+            file: file.to_owned(),
+            reason: reason.to_string(),
+        });
+    }
+
+    fn record(&mut self, path: String, write: PageWrite) {
+        match write {
+            PageWrite::Created => self.created.push(path),
+            PageWrite::Updated => self.updated.push(path),
+            PageWrite::Unchanged => self.skipped.push(path),
+        }
+    }
+
+    /// The paths the report lists, under headings saying what happened to them.
+    pub fn sections(&self) -> [(&str, &[String]); 5] {
+        [
+            ("Created", &self.created),
+            ("Updated", &self.updated),
+            ("Skipped, unchanged", &self.skipped),
+            ("Attached", &self.attachments),
+            ("Moved to the trash", &self.trashed),
+        ]
+    }
+
+    /// A line summing up the report, like `Upload finished: 2 pages created, 1 files failed.`.
+    pub fn summary(&self) -> String {
+        let counts = [
+            (self.created.len(), "pages created"),
+            (self.updated.len(), "pages updated"),
+            (self.skipped.len(), "unchanged pages skipped"),
+            (self.attachments.len(), "files attached"),
+            (self.trashed.len(), "missing pages trashed"),
+            (self.failed.len(), "files failed"),
+        ];
+        let parts: Vec<String> = counts
+            .iter()
+            .filter(|(n, _)| *n > 0)
+            .map(|(n, what)| format!("{} {}", n, what))
+            .collect();
+
+        let mut summary = match parts.is_empty() {
+            true => "Upload finished, but there was nothing in it.".to_owned(),
+            false => format!("Upload finished: {}.", parts.join(", ")),
+        };
+        if let Some(stopped) = &self.stopped {
+            summary.push_str(&format!(" It stopped early. {}", stopped));
+        }
+
+        summary
+    }
+}
+
+/// How an upload is getting on.
+#[derive(Debug, Clone, Serialize)]
+pub struct Job {
+    pub id: u64,
+    /// The name of the uploaded file.
+    pub file: String,
+    /// Only whoever uploaded the file can see how it's going.
+    #[serde(skip)]
+    pub actor: String,
+    /// How many files there are to import, once the archive's been opened.
+    pub total: usize,
+    pub processed: usize,
+    pub done: bool,
+    /// Why nothing could be imported, like the file not being an archive.
+    pub error: Option<String>,
+    /// What happened to each file, once the upload is done.
+    pub report: Option<UploadReport>,
+}
+
+impl Job {
+    /// How far through its files the upload is, out of 100.
+    pub fn percent(&self) -> usize {
+        (self.processed * 100).checked_div(self.total).unwrap_or(0)
+    }
+}
+
+/// Uploads waiting to be imported one at a time in the background, and how they got on.
+pub struct Jobs {
+    jobs: Mutex<BTreeMap<u64, Job>>,
+    queue: UnboundedSender<(u64, Upload)>,
+}
+
+impl Jobs {
+    pub fn new() -> (Arc<Self>, UnboundedReceiver<(u64, Upload)>) {
+        let (queue, rx) = mpsc::unbounded_channel();
+        let jobs = Self {
+            jobs: Mutex::new(BTreeMap::new()),
+            queue,
+        };
+
+        (Arc::new(jobs), rx)
+    }
+
+    /// Queues an upload to be imported, returning the id of its job.
+    pub fn submit(&self, upload: Upload) -> u64 {
+        let mut jobs = self.jobs.lock().unwrap();
+        let id = jobs.last_key_value().map_or(1, |(id, _)| id + 1);
+        while jobs.len() >= KEPT_JOBS {
+            let Some(&oldest) = jobs.iter().find(|(_, job)| job.done).map(|(id, _)| id) else {
+                break;
+            };
+            jobs.remove(&oldest);
+        }
+
+        jobs.insert(
+            id,
+            Job {
+                id,
+                file: upload.file.file_name.clone().unwrap_or_default(),
+                actor: upload.actor.clone(),
+                total: 0,
+                processed: 0,
+                done: false,
+                error: None,
+                report: None,
+            },
+        );
+        // The receiver lives as long as the server, so this can't fail.
+        let _ = self.queue.send((id, upload));
+
+        id
+    }
+
+    /// The job with `id`, if it was started by `actor`.
+    pub fn get(&self, id: u64, actor: &str) -> Option<Job> {
+        let jobs = self.jobs.lock().unwrap();
+        jobs.get(&id).filter(|job| job.actor == actor).cloned()
+    }
+
+    fn update(&self, id: u64, f: impl FnOnce(&mut Job)) {
+        if let Some(job) = self.jobs.lock().unwrap().get_mut(&id) {
+            f(job);
+        }
+    }
+}
+
+/// Imports queued uploads one after another until the server shuts down.
+pub async fn import_uploads(
+    state: State,
+    jobs: Arc<Jobs>,
+    mut queue: UnboundedReceiver<(u64, Upload)>,
+) {
+    while let Some((id, upload)) = queue.recv().await {
+        let result = import(&state, &jobs, id, &upload).await;
+        if let Err(e) = &result {
+            eprintln!("Error importing upload {}: {}", id, e);
+        }
+        jobs.update(id, |job| {
+            job.done = true;
+            match result {
+                Ok(report) => job.report = Some(report),
+                Err(e) => job.error = Some(e.to_string()),
+            }
+        });
+    }
+}
+
+/// Imports an uploaded archive or single page, reporting what happened to each file.
+async fn import(state: &State, jobs: &Jobs, id: u64, upload: &Upload) -> Result<UploadReport> {
+    let user = upload.viewer.user.as_deref();
+    let file_name = upload.file.file_name.as_deref().unwrap_or_default();
+    let mut report = UploadReport::default();
+
+    // A single page can be uploaded by itself rather than in an archive.
+    if file_name.to_lowercase().ends_with(".md") {
+        jobs.update(id, |job| job.total = 1);
+        let path = if upload.path.to_lowercase().ends_with(".md") {
+            canonical_path(&upload.path)
+        } else {
+            let file_name = slug_path(file_name, upload.dashes);
+            canonical_path(&format!("{}/{}", upload.path, file_name))
+        };
+        if let Some(error) = page_path_error(&path) {
+            report.fail(file_name, error);
+        } else if !state.can_see(&path, &upload.viewer) {
+            report.fail(file_name, format!("You can't add pages under {}", path));
+        } else if upload.file.size > state.max_page_bytes() {
+            let reason = format!(
+                "pages are limited to {} bytes of markdown",
+                state.max_page_bytes()
+            );
+            report.fail(file_name, reason);
+        } else {
+            let md = std::fs::read_to_string(upload.file.file.path()).map_err(bad_upload)?;
+            let importer = Importer::new(upload.mode, &[file_name.to_owned()], upload.dashes);
+            match state.set_page_as(&path, importer.convert(md), user).await {
+                Ok(write) => {
+                    if write != PageWrite::Unchanged {
+                        audit(state, upload, path.as_str()).await?;
+                    }
+                    report.record(path, write);
+                }
+                Err(e @ (Error::QuotaExceeded(_) | Error::TooLarge(_))) => {
+                    report.fail(file_name, e)
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        jobs.update(id, |job| job.processed = 1);
+
+        return Ok(report);
+    }
+
+    let mut archive = Archive::open(upload.file.file.path()).map_err(bad_upload)?;
+
+    let files = archive.file_names().map_err(bad_upload)?;
+    let importer = Importer::new(upload.mode, &files, upload.dashes);
+    let total = files
+        .iter()
+        .filter(|f| !f.ends_with('/') && !importer.skips(f))
+        .count();
+    jobs.update(id, |job| job.total = total);
+    let mut uploaded = HashSet::new();
+
+    let entries = archive
+        .entries(
+            state.max_page_bytes() as u64,
+            state.max_attachment_bytes() as u64,
+        )
+        .map_err(bad_upload)?;
+    for entry in entries {
+        let entry = entry.map_err(bad_upload)?;
+        if importer.skips(&entry.name) {
+            continue;
+        }
+        jobs.update(id, |job| job.processed += 1);
+        let path = importer.page_path(&entry.name);
+        let page = entry.name.ends_with(".md");
+        let canonical = canonical_path(&path);
+        // A page that's in the archive isn't missing from it, even if it can't be imported.
+        if page {
+            uploaded.insert(canonical.clone());
+        }
+        let Some(data) = entry.data else {
+            let reason = match entry.name.ends_with(".md") {
+                true => format!("over the {} byte page limit", state.max_page_bytes()),
+                false => format!(
+                    "over the {} byte attachment limit",
+                    state.max_attachment_bytes()
+                ),
+            };
+            report.fail(&entry.name, reason);
+            continue;
+        };
+
+        // Archives can't put anything where the uploader couldn't put a single page.
+        if let Some(error) = page.then(|| page_path_error(&canonical)).flatten() {
+            report.fail(&entry.name, error);
+            continue;
+        }
+        if !state.can_see(&canonical, &upload.viewer) {
+            report.fail(
+                &entry.name,
+                format!("You can't add files under {}", canonical),
+            );
+            continue;
+        }
+
+        let stored = if page {
+            let md = match String::from_utf8(data) {
+                Ok(md) => md,
+                Err(_) => {
+                    report.fail(&entry.name, "not valid UTF-8");
+                    continue;
+                }
+            };
+            state
+                .set_page_as(&path, importer.convert(md), user)
+                .await
+                .map(|write| report.record(canonical.clone(), write))
+        } else {
+            // Anything that isn't a page is kept so pages can embed or link to it.
+            state
+                .set_attachment(&path, &data)
+                .await
+                .map(|()| report.attachments.push(canonical.clone()))
+        };
+
+        match stored {
+            Ok(()) => {}
+            Err(e @ Error::TooLarge(_)) => report.fail(&entry.name, e),
+            Err(e @ Error::QuotaExceeded(_)) => {
+                report.fail(&entry.name, &e);
+                report.stopped = Some(e.to_string());
+                break;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    // Pages are only trashed once everything in the archive made it in.
+    if upload.sync && report.stopped.is_none() {
+        for path in state.list_pages().await? {
+            if !uploaded.contains(&path)
+                && state.can_see(&path, &upload.viewer)
+                && state.trash_page(&path).await?
+            {
+                report.trashed.push(path);
+            }
+        }
+    }
+
+    audit(state, upload, report.summary()).await?;
+
+    Ok(report)
+}
+
+async fn audit(state: &State, upload: &Upload, detail: impl Into<String>) -> Result<()> {
+    state
+        .record(&AuditEvent {
+            timestamp: unix_time(),
+            action: AuditAction::Upload,
+            actor: upload.actor.clone(),
+            ip: upload.ip.clone(),
+            detail: detail.into(),
+        })
+        .await
+}
+
+fn bad_upload(e: impl ToString) -> Error {
+    Error::BadUpload(e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use zip::{write::FileOptions, ZipWriter};
+
+    use super::*;
+
+    /// An upload of a zip holding `files`, synced so pages missing from it are trashed.
+    fn sync_upload(files: &[(&str, Vec<u8>)]) -> Upload {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        let mut zip = ZipWriter::new(file.as_file_mut());
+        for (name, data) in files {
+            zip.start_file(*name, FileOptions::default()).unwrap();
+            zip.write_all(data).unwrap();
+        }
+        zip.finish().unwrap();
+        drop(zip);
+
+        Upload {
+            file: TempFile {
+                size: file.as_file().metadata().unwrap().len() as usize,
+                file,
+                content_type: None,
+                file_name: Some("wiki.zip".to_owned()),
+            },
+            mode: ImportMode::Raw,
+            path: String::new(),
+            sync: true,
+            dashes: false,
+            viewer: Viewer {
+                role: Some(crate::state::Role::Editor),
+                user: None,
+            },
+            actor: "tests".to_owned(),
+            ip: String::new(),
+        }
+    }
+
+    #[actix_web::test]
+    async fn synced_pages_that_fail_to_import_are_not_trashed() {
+        let state = State::in_memory();
+        state
+            .set_page("handbook.md", "# Handbook".to_owned())
+            .await
+            .unwrap();
+        state.set_page("old.md", "# Old".to_owned()).await.unwrap();
+
+        let too_big = vec![b'a'; state.max_page_bytes() + 1];
+        let upload = sync_upload(&[("handbook.md", too_big), ("index.md", b"# Home".to_vec())]);
+        let (jobs, _queue) = Jobs::new();
+        let report = import(&state, &jobs, 0, &upload).await.unwrap();
+
+        assert_eq!(report.failed.len(), 1);
+        assert_eq!(report.trashed, ["old.md"]);
+        let handbook = state.page_markdown("handbook.md").await.unwrap();
+        assert_eq!(handbook.as_deref(), Some("# Handbook"));
+    }
+}
@@ -0,0 +1,76 @@
+use std::path::{Path, PathBuf};
+
+use notify::{RecursiveMode, Watcher};
+
+use crate::state::State;
+
+/// Imports every markdown file under `dir`, then keeps pages in sync as files are changed,
+/// added or removed until the server shuts down.
+pub async fn sync_dir(state: State, dir: PathBuf) {
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    let mut watcher = notify::recommended_watcher(move |event| {
+        let _ = tx.send(event);
+    })
+    .expect("File watcher should be creatable");
+    watcher
+        .watch(&dir, RecursiveMode::Recursive)
+        .expect("knowbase_PAGES_DIR should be a readable directory");
+
+    for file in markdown_files(&dir) {
+        sync_file(&state, &dir, &file).await;
+    }
+
+    while let Some(event) = rx.recv().await {
+        match event {
+            Ok(event) => {
+                for file in event.paths {
+                    sync_file(&state, &dir, &file).await;
+                }
+            }
+            Err(e) => eprintln!("Error watching {}: {}", dir.display(), e),
+        }
+    }
+}
+
+/// Renders a file into its page, or removes the page if the file is gone.
+pub async fn sync_file(state: &State, dir: &Path, file: &Path) {
+    if file.extension().is_none_or(|e| e != "md") {
+        return;
+    }
+
+    let Ok(path) = file.strip_prefix(dir) else {
+        return;
+    };
+    let path = path.to_string_lossy();
+
+    let result = match std::fs::read_to_string(file) {
+        Ok(md) => state.set_page(&path, md).await,
+        Err(_) if !file.exists() => state.delete_page(&path).await,
+        Err(e) => {
+            eprintln!("Error reading {}: {}", file.display(), e);
+            return;
+        }
+    };
+
+    if let Err(e) = result {
+        eprintln!("Error syncing {}: {}", file.display(), e);
+    }
+}
+
+pub fn markdown_files(dir: &Path) -> Vec<PathBuf> {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return Vec::new();
+    };
+
+    let mut files = Vec::new();
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            files.append(&mut markdown_files(&path));
+        } else if path.extension().is_some_and(|e| e == "md") {
+            files.push(path);
+        }
+    }
+
+    files
+}